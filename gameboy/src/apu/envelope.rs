@@ -0,0 +1,84 @@
+use std::io;
+use state::{StateReader, StateWriter};
+
+// Volume envelope shared by the square and noise channels (NRx2)
+pub struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Envelope {
+            initial_volume: 0,
+            increase: false,
+            period: 0,
+
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    pub fn read_reg(&self) -> u8 {
+        let increase = if self.increase { 1 << 3 } else { 0 };
+        (self.initial_volume << 4) | increase | self.period
+    }
+
+    pub fn write_reg(&mut self, val: u8) {
+        self.initial_volume = val >> 4;
+        self.increase = val & (1 << 3) != 0;
+        self.period = val & 0x7;
+    }
+
+    // The DAC is powered whenever the upper 5 bits of NRx2 are non-zero
+    pub fn dac_enabled(&self) -> bool {
+        self.initial_volume != 0 || self.increase
+    }
+
+    pub fn volume(&self) -> u8 {
+        self.volume
+    }
+
+    pub fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+    }
+
+    // Clocked at 64Hz by the frame sequencer
+    pub fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period;
+
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.read_reg());
+        state.write_u8(self.volume);
+        state.write_u8(self.timer);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        let reg = state.read_u8()?;
+        self.write_reg(reg);
+        self.volume = state.read_u8()? & 0xf;
+        self.timer = state.read_u8()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+use std::io;
+use state::{StateReader, StateWriter};
+
+// Length counter shared by all four channels, when it expires the channel is
+// disabled
+#[derive(Clone, Copy)]
+pub struct LengthCounter {
+    max: u16,
+    counter: u16,
+    pub enabled: bool,
+}
+
+impl LengthCounter {
+    pub fn new(max: u16) -> Self {
+        LengthCounter {
+            max: max,
+            counter: 0,
+            enabled: false,
+        }
+    }
+
+    pub fn load(&mut self, val: u8) {
+        self.counter = self.max - (val as u16 & (self.max - 1));
+    }
+
+    // Handles the length enable bit of a write to NRx4, returns true if the
+    // channel should be disabled.
+    //
+    // Enabling the counter while the next frame sequencer step won't clock it
+    // gives it an extra clock.
+    pub fn set_enabled(&mut self, enabled: bool, next_step_clocks_length: bool) -> bool {
+        let was_enabled = self.enabled;
+        self.enabled = enabled;
+
+        if !was_enabled && enabled && !next_step_clocks_length && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+
+    pub fn trigger(&mut self, next_step_clocks_length: bool) {
+        if self.counter == 0 {
+            self.counter = if self.enabled && !next_step_clocks_length {
+                self.max - 1
+            } else {
+                self.max
+            };
+        }
+    }
+
+    // Clocked at 256Hz by the frame sequencer, returns true if the channel
+    // should be disabled
+    pub fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.counter);
+        state.write_bool(self.enabled);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.counter = state.read_u16()?;
+        self.enabled = state.read_bool()?;
+        Ok(())
+    }
+}
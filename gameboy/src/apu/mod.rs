@@ -1,117 +1,346 @@
+use std::io;
+use device::Device;
+use state::{StateReader, StateWriter};
+
+mod envelope;
+mod length;
+mod square;
+mod wave;
+mod noise;
+
+use self::square::Square;
+use self::wave::Wave;
+use self::noise::Noise;
+
+const CLOCK_RATE: u32 = 4194304;
+// The frame sequencer is clocked at 512Hz by the falling edge of this bit of
+// the timer's divider
+const FRAME_SEQUENCER_DIV_BIT: u16 = 1 << 12;
+// Number of stereo samples to collect before handing them to the device
+const SAMPLE_BATCH_LENGTH: usize = 1024;
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+const READ_MASKS: [u8; 32] = [
+    0x80, 0x3f, 0x00, 0xff, 0xbf, // NR10 - NR14
+    0xff, 0x3f, 0x00, 0xff, 0xbf, // NR20 - NR24
+    0x7f, 0xff, 0x9f, 0xff, 0xbf, // NR30 - NR34
+    0xff, 0xff, 0x00, 0x00, 0xbf, // NR40 - NR44
+    0x00, 0x00, 0x70, // NR50 - NR52
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, // Unused
+];
+
 pub struct Apu {
-    chan1_sweep: u8, // 0xff10 - Channel 1 Sweep Register
-    chan1_pattern: u8, // 0xff11 - Channel 1 Sound length/Wave duty register
-    chan1_volume: u8,
-    chan1_freq_lo: u8,
-    chan1_freq_hi: u8,
-
-    chan2_pattern: u8,
-    chan2_volume: u8,
-    chan2_freq_lo: u8,
-    chan2_freq_hi: u8,
-
-    chan3_active: u8,
-    chan3_length: u8,
-    chan3_out_level: u8,
-    chan3_freq_lo: u8,
-    chan3_freq_hi: u8,
-    chan3_data: [u8; 16],
-
-    chan4_length: u8,
-    chan4_volume: u8,
-    chan4_counter: u8,
-    chan4_control: u8,
-
-    out_chan_control: u8,
-    output_terminal: u8,
-    sound_active: u8,
+    square1: Square, // 0xff10 - 0xff14
+    square2: Square, // 0xff16 - 0xff19
+    wave: Wave, // 0xff1a - 0xff1e, 0xff30 - 0xff3f
+    noise: Noise, // 0xff20 - 0xff23
+
+    out_chan_control: u8, // 0xff24 - NR50 Channel control / ON-OFF / Volume
+    output_terminal: u8, // 0xff25 - NR51 Selection of sound output terminal
+    power: bool, // 0xff26 - NR52 bit 7
+
+    frame_sequencer_div_bit: bool,
+    frame_sequencer_step: u8,
+
+    sample_rate: u32,
+    sample_counter: u32,
+    // Sum of the mixer output since the last sample, used to average the
+    // native rate output down to the device's sample rate
+    left_sum: f32,
+    right_sum: f32,
+    sum_count: u32,
+    capacitor_left: f32,
+    capacitor_right: f32,
+    samples: Vec<f32>, // Interleaved left and right samples
 }
 
 impl Apu {
     pub fn new() -> Self {
         Apu {
-            chan1_sweep: 0,
-            chan1_pattern: 0,
-            chan1_volume: 0,
-            chan1_freq_lo: 0,
-            chan1_freq_hi: 0,
-
-            chan2_pattern: 0,
-            chan2_volume: 0,
-            chan2_freq_lo: 0,
-            chan2_freq_hi: 0,
-
-            chan3_active: 0,
-            chan3_length: 0,
-            chan3_out_level: 0,
-            chan3_freq_lo: 0,
-            chan3_freq_hi: 0,
-            chan3_data: [0; 16],
-
-            chan4_length: 0,
-            chan4_volume: 0,
-            chan4_counter: 0,
-            chan4_control: 0,
+            square1: Square::new(true),
+            square2: Square::new(false),
+            wave: Wave::new(),
+            noise: Noise::new(),
 
             out_chan_control: 0,
             output_terminal: 0,
-            sound_active: 0,
+            power: false,
+
+            frame_sequencer_div_bit: false,
+            frame_sequencer_step: 0,
+
+            sample_rate: 0,
+            sample_counter: 0,
+            left_sum: 0.0,
+            right_sum: 0.0,
+            sum_count: 0,
+            capacitor_left: 0.0,
+            capacitor_right: 0.0,
+            samples: Vec::with_capacity(SAMPLE_BATCH_LENGTH * 2),
         }
     }
 
     pub fn read_reg(&self, addr: u16) -> u8 {
-        match addr {
-            0xff10 => self.chan1_sweep,
-            0xff11 => self.chan1_pattern,
-            0xff12 => self.chan1_volume,
-            0xff13 => self.chan1_freq_lo,
-            0xff14 => self.chan1_freq_hi,
-            0xff16 => self.chan2_pattern,
-            0xff17 => self.chan2_volume,
-            0xff18 => self.chan2_freq_lo,
-            0xff19 => self.chan2_freq_hi,
-            0xff1a => self.chan3_active,
-            0xff1b => self.chan3_length,
-            0xff1c => self.chan3_out_level,
-            0xff1d => self.chan3_freq_lo,
-            0xff1e => self.chan3_freq_hi,
-            0xff20 => self.chan4_length,
-            0xff21 => self.chan4_volume,
-            0xff22 => self.chan4_counter,
-            0xff23 => self.chan4_control,
+        let val = match addr {
+            0xff10...0xff14 => self.square1.read_reg(addr - 0xff10),
+            0xff16...0xff19 => self.square2.read_reg(addr - 0xff15),
+            0xff1a...0xff1e => self.wave.read_reg(addr - 0xff1a),
+            0xff20...0xff23 => self.noise.read_reg(addr - 0xff1f),
             0xff24 => self.out_chan_control,
             0xff25 => self.output_terminal,
-            0xff26 => self.sound_active,
-            0xff30...0xff3f => self.chan3_data[(addr - 0xff30) as usize],
-            _ => 0xff,
-        }
+            0xff26 => self.status(),
+            0xff30...0xff3f => return self.wave.read_ram((addr - 0xff30) as usize),
+            _ => 0x00,
+        };
+
+        // Write-only and unused bits read back as 1s
+        val | READ_MASKS[(addr - 0xff10) as usize]
     }
 
     pub fn write_reg(&mut self, addr: u16, val: u8) {
+        if addr >= 0xff30 {
+            // Wave RAM is accessible whether or not the APU is powered
+            self.wave.write_ram((addr - 0xff30) as usize, val);
+            return;
+        }
+
+        if addr == 0xff26 {
+            self.write_power(val);
+            return;
+        }
+
+        let next_step_clocks_length = self.frame_sequencer_step % 2 == 0;
+        if !self.power {
+            // While powered off the registers can't be written, except for the
+            // length counters on the DMG
+            match addr {
+                0xff11 => self.square1.length.load(val),
+                0xff16 => self.square2.length.load(val),
+                0xff1b => self.wave.length.load(val),
+                0xff20 => self.noise.length.load(val),
+                _ => {}
+            }
+            return;
+        }
+
         match addr {
-            0xff10 => self.chan1_sweep = val,
-            0xff11 => self.chan1_pattern = val,
-            0xff12 => self.chan1_volume = val,
-            0xff13 => self.chan1_freq_lo = val,
-            0xff14 => self.chan1_freq_hi = val,
-            0xff16 => self.chan2_pattern = val,
-            0xff17 => self.chan2_volume = val,
-            0xff18 => self.chan2_freq_lo = val,
-            0xff19 => self.chan2_freq_hi = val,
-            0xff1a => self.chan3_active = val,
-            0xff1b => self.chan3_length = val,
-            0xff1c => self.chan3_out_level = val,
-            0xff1d => self.chan3_freq_lo = val,
-            0xff1e => self.chan3_freq_hi = val,
-            0xff20 => self.chan4_length = val,
-            0xff21 => self.chan4_volume = val,
-            0xff22 => self.chan4_counter = val,
-            0xff23 => self.chan4_control = val,
+            0xff10...0xff14 => self.square1.write_reg(addr - 0xff10, val, next_step_clocks_length),
+            0xff16...0xff19 => self.square2.write_reg(addr - 0xff15, val, next_step_clocks_length),
+            0xff1a...0xff1e => self.wave.write_reg(addr - 0xff1a, val, next_step_clocks_length),
+            0xff20...0xff23 => self.noise.write_reg(addr - 0xff1f, val, next_step_clocks_length),
             0xff24 => self.out_chan_control = val,
             0xff25 => self.output_terminal = val,
-            0xff26 => self.sound_active = val,
-            0xff30...0xff3f => self.chan3_data[(addr - 0xff30) as usize] = val,
-            _ => {},
+            _ => {}
+        }
+    }
+
+    // The divider is the timer's internal counter before it's stepped
+    pub fn step(&mut self, cycles: u16, divider: u16, device: &mut Device) {
+        let sample_rate = device.audio_sample_rate();
+        if sample_rate != self.sample_rate {
+            self.sample_rate = sample_rate;
+            self.sample_counter = 0;
+            self.left_sum = 0.0;
+            self.right_sum = 0.0;
+            self.sum_count = 0;
+        }
+
+        for i in 0..cycles {
+            let div_bit = divider.wrapping_add(i + 1) & FRAME_SEQUENCER_DIV_BIT != 0;
+            let falling_edge = self.frame_sequencer_div_bit && !div_bit;
+            self.frame_sequencer_div_bit = div_bit;
+
+            if self.power {
+                if falling_edge {
+                    self.clock_frame_sequencer();
+                }
+
+                self.square1.step();
+                self.square2.step();
+                self.wave.step();
+                self.noise.step();
+            }
+
+            // Nothing is mixed for a device that doesn't want audio
+            if self.sample_rate == 0 {
+                continue;
+            }
+
+            let (left, right) = self.mix();
+            self.left_sum += left;
+            self.right_sum += right;
+            self.sum_count += 1;
+
+            self.sample_counter += self.sample_rate;
+            if self.sample_counter >= CLOCK_RATE {
+                self.sample_counter -= CLOCK_RATE;
+                self.output_sample();
+            }
+        }
+
+        if self.samples.len() >= SAMPLE_BATCH_LENGTH * 2 {
+            device.queue_audio_samples(&self.samples);
+            self.samples.clear();
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.square1.save_state(state);
+        self.square2.save_state(state);
+        self.wave.save_state(state);
+        self.noise.save_state(state);
+
+        state.write_u8(self.out_chan_control);
+        state.write_u8(self.output_terminal);
+        state.write_bool(self.power);
+
+        state.write_bool(self.frame_sequencer_div_bit);
+        state.write_u8(self.frame_sequencer_step);
+
+        state.write_u32(self.sample_counter);
+        state.write_f32(self.left_sum);
+        state.write_f32(self.right_sum);
+        state.write_u32(self.sum_count);
+        state.write_f32(self.capacitor_left);
+        state.write_f32(self.capacitor_right);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.square1.load_state(state)?;
+        self.square2.load_state(state)?;
+        self.wave.load_state(state)?;
+        self.noise.load_state(state)?;
+
+        self.out_chan_control = state.read_u8()?;
+        self.output_terminal = state.read_u8()?;
+        self.power = state.read_bool()?;
+
+        self.frame_sequencer_div_bit = state.read_bool()?;
+        self.frame_sequencer_step = state.read_u8()? & 0x7;
+
+        self.sample_counter = state.read_u32()? % CLOCK_RATE;
+        self.left_sum = state.read_f32()?;
+        self.right_sum = state.read_f32()?;
+        self.sum_count = state.read_u32()?;
+        self.capacitor_left = state.read_f32()?;
+        self.capacitor_right = state.read_f32()?;
+        // Samples still waiting to be queued belong to the abandoned timeline
+        self.samples.clear();
+        Ok(())
+    }
+
+    fn write_power(&mut self, val: u8) {
+        let power = val & (1 << 7) != 0;
+
+        if self.power && !power {
+            self.square1.power_off();
+            self.square2.power_off();
+            self.wave.power_off();
+            self.noise.power_off();
+            self.out_chan_control = 0;
+            self.output_terminal = 0;
+        } else if !self.power && power {
+            self.frame_sequencer_step = 0;
+        }
+
+        self.power = power;
+    }
+
+    fn status(&self) -> u8 {
+        let mut ret = if self.power { 1 << 7 } else { 0 };
+
+        if self.square1.enabled {
+            ret |= 1;
+        }
+        if self.square2.enabled {
+            ret |= 1 << 1;
+        }
+        if self.wave.enabled {
+            ret |= 1 << 2;
+        }
+        if self.noise.enabled {
+            ret |= 1 << 3;
+        }
+        ret
+    }
+
+    fn clock_frame_sequencer(&mut self) {
+        // Step   Length Ctr  Vol Env     Sweep
+        // 0      Clock       -           -
+        // 1      -           -           -
+        // 2      Clock       -           Clock
+        // 3      -           -           -
+        // 4      Clock       -           -
+        // 5      -           -           -
+        // 6      Clock       -           Clock
+        // 7      -           Clock       -
+        if self.frame_sequencer_step % 2 == 0 {
+            self.square1.clock_length();
+            self.square2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+        if self.frame_sequencer_step == 2 || self.frame_sequencer_step == 6 {
+            self.square1.clock_sweep();
+        }
+        if self.frame_sequencer_step == 7 {
+            self.square1.clock_envelope();
+            self.square2.clock_envelope();
+            self.noise.clock_envelope();
+        }
+
+        self.frame_sequencer_step = (self.frame_sequencer_step + 1) % 8;
+    }
+
+    fn output_sample(&mut self) {
+        let left = self.left_sum / self.sum_count as f32;
+        let right = self.right_sum / self.sum_count as f32;
+        self.left_sum = 0.0;
+        self.right_sum = 0.0;
+        self.sum_count = 0;
+
+        // Real hardware passes the output through a capacitor which removes
+        // the DC offset left by the DACs
+        let charge_factor = 0.999958f32.powf(CLOCK_RATE as f32 / self.sample_rate as f32);
+        let out_left = left - self.capacitor_left;
+        self.capacitor_left = left - out_left * charge_factor;
+        let out_right = right - self.capacitor_right;
+        self.capacitor_right = right - out_right * charge_factor;
+
+        self.samples.push(out_left);
+        self.samples.push(out_right);
+    }
+
+    fn mix(&self) -> (f32, f32) {
+        if !self.power {
+            return (0.0, 0.0);
         }
+
+        let channels = [(self.square1.output(), self.square1.dac_enabled()),
+                        (self.square2.output(), self.square2.dac_enabled()),
+                        (self.wave.output(), self.wave.dac_enabled()),
+                        (self.noise.output(), self.noise.dac_enabled())];
+
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (i, &(digital, dac_enabled)) in channels.iter().enumerate() {
+            if !dac_enabled {
+                continue;
+            }
+
+            // Each DAC maps 0x0 - 0xf linearly to 1.0 - -1.0
+            let analog = 1.0 - (digital as f32 / 7.5);
+            if self.output_terminal & (1 << i) != 0 {
+                right += analog;
+            }
+            if self.output_terminal & (1 << (i + 4)) != 0 {
+                left += analog;
+            }
+        }
+
+        let left_volume = ((self.out_chan_control >> 4) & 0x7) + 1;
+        let right_volume = (self.out_chan_control & 0x7) + 1;
+
+        (left / 4.0 * left_volume as f32 / 8.0, right / 4.0 * right_volume as f32 / 8.0)
     }
 }
@@ -0,0 +1,155 @@
+use std::io;
+use apu::envelope::Envelope;
+use apu::length::LengthCounter;
+use state::{StateReader, StateWriter};
+
+const DIVISORS: [u16; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// Channel 4 outputs the low bit of a 15-bit linear feedback shift register
+pub struct Noise {
+    pub length: LengthCounter,
+    pub envelope: Envelope,
+    clock_shift: u8, // NR43 - bits 4-7
+    width_mode: bool, // NR43 - bit 3, 7-bit LFSR when set
+    divisor_code: u8, // NR43 - bits 0-2
+
+    pub enabled: bool,
+    timer: u32,
+    lfsr: u16,
+}
+
+impl Noise {
+    pub fn new() -> Self {
+        Noise {
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            clock_shift: 0,
+            width_mode: false,
+            divisor_code: 0,
+
+            enabled: false,
+            timer: 0,
+            lfsr: 0x7fff,
+        }
+    }
+
+    pub fn read_reg(&self, reg: u16) -> u8 {
+        match reg {
+            2 => self.envelope.read_reg(),
+            3 => {
+                let width = if self.width_mode { 1 << 3 } else { 0 };
+                (self.clock_shift << 4) | width | self.divisor_code
+            }
+            4 if self.length.enabled => 1 << 6,
+            _ => 0,
+        }
+    }
+
+    pub fn write_reg(&mut self, reg: u16, val: u8, next_step_clocks_length: bool) {
+        match reg {
+            1 => self.length.load(val),
+            2 => {
+                self.envelope.write_reg(val);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                self.clock_shift = val >> 4;
+                self.width_mode = val & (1 << 3) != 0;
+                self.divisor_code = val & 0x7;
+            }
+            4 => {
+                let trigger = val & (1 << 7) != 0;
+                let length_enabled = val & (1 << 6) != 0;
+                if self.length.set_enabled(length_enabled, next_step_clocks_length) && !trigger {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.trigger(next_step_clocks_length);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn step(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period();
+
+            let xor = (self.lfsr & 1) ^ ((self.lfsr >> 1) & 1);
+            self.lfsr = (self.lfsr >> 1) | (xor << 14);
+            if self.width_mode {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (xor << 6);
+            }
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.enabled && self.lfsr & 1 == 0 {
+            self.envelope.volume()
+        } else {
+            0
+        }
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    // Powering the APU off leaves the length counter intact
+    pub fn power_off(&mut self) {
+        let length = self.length;
+
+        *self = Noise::new();
+        self.length = length;
+        self.length.enabled = false;
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+        state.write_u8(self.read_reg(3));
+
+        state.write_bool(self.enabled);
+        state.write_u32(self.timer);
+        state.write_u16(self.lfsr);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        let polynomial = state.read_u8()?;
+        self.write_reg(3, polynomial, false);
+
+        self.enabled = state.read_bool()?;
+        self.timer = state.read_u32()?;
+        self.lfsr = state.read_u16()?;
+        Ok(())
+    }
+
+    fn trigger(&mut self, next_step_clocks_length: bool) {
+        self.enabled = self.dac_enabled();
+        self.length.trigger(next_step_clocks_length);
+        self.envelope.trigger();
+        self.timer = self.period();
+        self.lfsr = 0x7fff;
+    }
+
+    fn period(&self) -> u32 {
+        (DIVISORS[self.divisor_code as usize] as u32) << self.clock_shift
+    }
+}
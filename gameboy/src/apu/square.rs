@@ -0,0 +1,253 @@
+use std::io;
+use apu::envelope::Envelope;
+use apu::length::LengthCounter;
+use state::{StateReader, StateWriter};
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+const DUTY_PATTERNS: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+    [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+    [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+];
+
+// Square wave channels 1 and 2, only channel 1 has a frequency sweep unit
+pub struct Square {
+    has_sweep: bool,
+
+    sweep_period: u8, // NR10 - bits 4-6
+    sweep_negate: bool, // NR10 - bit 3
+    sweep_shift: u8, // NR10 - bits 0-2
+    duty: u8, // NRx1 - bits 6-7
+    pub length: LengthCounter,
+    pub envelope: Envelope,
+    frequency: u16, // NRx3 and NRx4 bits 0-2
+
+    pub enabled: bool,
+    timer: u16,
+    duty_step: usize,
+
+    sweep_enabled: bool,
+    sweep_timer: u8,
+    shadow_frequency: u16,
+    // Set once a sweep calculation has used negate mode since the last
+    // trigger, clearing the negate bit afterwards disables the channel
+    sweep_negated: bool,
+}
+
+impl Square {
+    pub fn new(has_sweep: bool) -> Self {
+        Square {
+            has_sweep: has_sweep,
+
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            duty: 0,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            frequency: 0,
+
+            enabled: false,
+            timer: 0,
+            duty_step: 0,
+
+            sweep_enabled: false,
+            sweep_timer: 0,
+            shadow_frequency: 0,
+            sweep_negated: false,
+        }
+    }
+
+    pub fn read_reg(&self, reg: u16) -> u8 {
+        match reg {
+            0 if self.has_sweep => {
+                let negate = if self.sweep_negate { 1 << 3 } else { 0 };
+                (self.sweep_period << 4) | negate | self.sweep_shift
+            }
+            1 => self.duty << 6,
+            2 => self.envelope.read_reg(),
+            4 if self.length.enabled => 1 << 6,
+            _ => 0,
+        }
+    }
+
+    pub fn write_reg(&mut self, reg: u16, val: u8, next_step_clocks_length: bool) {
+        match reg {
+            0 if self.has_sweep => {
+                self.sweep_period = (val >> 4) & 0x7;
+                self.sweep_negate = val & (1 << 3) != 0;
+                self.sweep_shift = val & 0x7;
+
+                if !self.sweep_negate && self.sweep_negated {
+                    self.enabled = false;
+                }
+            }
+            1 => {
+                self.duty = val >> 6;
+                self.length.load(val);
+            }
+            2 => {
+                self.envelope.write_reg(val);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x700) | val as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xff) | ((val as u16 & 0x7) << 8);
+
+                let trigger = val & (1 << 7) != 0;
+                let length_enabled = val & (1 << 6) != 0;
+                if self.length.set_enabled(length_enabled, next_step_clocks_length) && !trigger {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.trigger(next_step_clocks_length);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn step(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period();
+            self.duty_step = (self.duty_step + 1) % 8;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.enabled && DUTY_PATTERNS[self.duty as usize][self.duty_step] != 0 {
+            self.envelope.volume()
+        } else {
+            0
+        }
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    // Powering the APU off leaves the length counter intact
+    pub fn power_off(&mut self) {
+        let length = self.length;
+
+        *self = Square::new(self.has_sweep);
+        self.length = length;
+        self.length.enabled = false;
+    }
+
+    // Clocked at 128Hz by the frame sequencer
+    pub fn clock_sweep(&mut self) {
+        if self.sweep_timer > 0 {
+            self.sweep_timer -= 1;
+        }
+        if self.sweep_timer != 0 {
+            return;
+        }
+
+        self.sweep_timer = if self.sweep_period == 0 { 8 } else { self.sweep_period };
+        if self.sweep_enabled && self.sweep_period != 0 {
+            let new_frequency = self.sweep_frequency();
+            if new_frequency <= 2047 && self.sweep_shift != 0 {
+                self.frequency = new_frequency;
+                self.shadow_frequency = new_frequency;
+
+                // The overflow check is run again with the new frequency, but
+                // the result isn't written back
+                self.sweep_frequency();
+            }
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.sweep_period);
+        state.write_bool(self.sweep_negate);
+        state.write_u8(self.sweep_shift);
+        state.write_u8(self.duty);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+        state.write_u16(self.frequency);
+
+        state.write_bool(self.enabled);
+        state.write_u16(self.timer);
+        state.write_u8(self.duty_step as u8);
+
+        state.write_bool(self.sweep_enabled);
+        state.write_u8(self.sweep_timer);
+        state.write_u16(self.shadow_frequency);
+        state.write_bool(self.sweep_negated);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.sweep_period = state.read_u8()? & 0x7;
+        self.sweep_negate = state.read_bool()?;
+        self.sweep_shift = state.read_u8()? & 0x7;
+        self.duty = state.read_u8()? & 0x3;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.frequency = state.read_u16()? & 0x7ff;
+
+        self.enabled = state.read_bool()?;
+        self.timer = state.read_u16()?;
+        self.duty_step = (state.read_u8()? & 0x7) as usize;
+
+        self.sweep_enabled = state.read_bool()?;
+        self.sweep_timer = state.read_u8()?;
+        self.shadow_frequency = state.read_u16()? & 0x7ff;
+        self.sweep_negated = state.read_bool()?;
+        Ok(())
+    }
+
+    fn trigger(&mut self, next_step_clocks_length: bool) {
+        self.enabled = self.dac_enabled();
+        self.length.trigger(next_step_clocks_length);
+        self.envelope.trigger();
+        self.timer = self.period();
+
+        if self.has_sweep {
+            self.sweep_negated = false;
+            self.shadow_frequency = self.frequency;
+            self.sweep_timer = if self.sweep_period == 0 { 8 } else { self.sweep_period };
+            self.sweep_enabled = self.sweep_period != 0 || self.sweep_shift != 0;
+            if self.sweep_shift != 0 {
+                self.sweep_frequency();
+            }
+        }
+    }
+
+    // Calculates the next swept frequency, disabling the channel if it
+    // overflows
+    fn sweep_frequency(&mut self) -> u16 {
+        let delta = self.shadow_frequency >> self.sweep_shift;
+        let new_frequency = if self.sweep_negate {
+            self.sweep_negated = true;
+            self.shadow_frequency - delta
+        } else {
+            self.shadow_frequency + delta
+        };
+
+        if new_frequency > 2047 {
+            self.enabled = false;
+        }
+        new_frequency
+    }
+
+    fn period(&self) -> u16 {
+        (2048 - self.frequency) * 4
+    }
+}
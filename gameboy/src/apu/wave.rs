@@ -0,0 +1,202 @@
+use std::io;
+use apu::length::LengthCounter;
+use state::{StateReader, StateWriter};
+
+// Channel 3 plays back the 32 4-bit samples stored in wave RAM
+pub struct Wave {
+    dac_enabled: bool, // NR30 - bit 7
+    pub length: LengthCounter,
+    volume_code: u8, // NR32 - bits 5-6
+    frequency: u16, // NR33 and NR34 bits 0-2
+    wave_ram: [u8; 16], // 0xff30 - 0xff3f
+
+    pub enabled: bool,
+    timer: u16,
+    position: usize,
+    sample_buffer: u8,
+    // Number of cycles since the channel last fetched from wave RAM, the CPU
+    // can only access wave RAM on the same cycle as the channel while it's
+    // playing
+    cycles_since_fetch: u16,
+}
+
+impl Wave {
+    pub fn new() -> Self {
+        Wave {
+            dac_enabled: false,
+            length: LengthCounter::new(256),
+            volume_code: 0,
+            frequency: 0,
+            wave_ram: [0; 16],
+
+            enabled: false,
+            timer: 0,
+            position: 0,
+            sample_buffer: 0,
+            cycles_since_fetch: 0,
+        }
+    }
+
+    pub fn read_reg(&self, reg: u16) -> u8 {
+        match reg {
+            0 if self.dac_enabled => 1 << 7,
+            2 => self.volume_code << 5,
+            4 if self.length.enabled => 1 << 6,
+            _ => 0,
+        }
+    }
+
+    pub fn write_reg(&mut self, reg: u16, val: u8, next_step_clocks_length: bool) {
+        match reg {
+            0 => {
+                self.dac_enabled = val & (1 << 7) != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(val),
+            2 => self.volume_code = (val >> 5) & 0x3,
+            3 => self.frequency = (self.frequency & 0x700) | val as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xff) | ((val as u16 & 0x7) << 8);
+
+                let trigger = val & (1 << 7) != 0;
+                let length_enabled = val & (1 << 6) != 0;
+                if self.length.set_enabled(length_enabled, next_step_clocks_length) && !trigger {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.trigger(next_step_clocks_length);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // While the channel is playing the CPU sees the byte the channel is
+    // currently reading, and only if it accesses it on the same cycle
+    pub fn read_ram(&self, index: usize) -> u8 {
+        if !self.enabled {
+            self.wave_ram[index]
+        } else if self.cycles_since_fetch == 0 {
+            self.wave_ram[self.position / 2]
+        } else {
+            0xff
+        }
+    }
+
+    pub fn write_ram(&mut self, index: usize, val: u8) {
+        if !self.enabled {
+            self.wave_ram[index] = val;
+        } else if self.cycles_since_fetch == 0 {
+            self.wave_ram[self.position / 2] = val;
+        }
+    }
+
+    pub fn step(&mut self) {
+        self.cycles_since_fetch = self.cycles_since_fetch.saturating_add(1);
+
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period();
+            self.position = (self.position + 1) % 32;
+
+            let byte = self.wave_ram[self.position / 2];
+            self.sample_buffer = if self.position % 2 == 0 { byte >> 4 } else { byte & 0xf };
+            self.cycles_since_fetch = 0;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+
+        match self.volume_code {
+            0 => 0,
+            1 => self.sample_buffer,
+            2 => self.sample_buffer >> 1,
+            3 => self.sample_buffer >> 2,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.dac_enabled
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    // Powering the APU off leaves wave RAM and the length counter intact
+    pub fn power_off(&mut self) {
+        let wave_ram = self.wave_ram;
+        let length = self.length;
+
+        *self = Wave::new();
+        self.wave_ram = wave_ram;
+        self.length = length;
+        self.length.enabled = false;
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.dac_enabled);
+        self.length.save_state(state);
+        state.write_u8(self.volume_code);
+        state.write_u16(self.frequency);
+        state.write_bytes(&self.wave_ram);
+
+        state.write_bool(self.enabled);
+        state.write_u16(self.timer);
+        state.write_u8(self.position as u8);
+        state.write_u8(self.sample_buffer);
+        state.write_u16(self.cycles_since_fetch);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.dac_enabled = state.read_bool()?;
+        self.length.load_state(state)?;
+        self.volume_code = state.read_u8()? & 0x3;
+        self.frequency = state.read_u16()? & 0x7ff;
+        state.read_bytes(&mut self.wave_ram)?;
+
+        self.enabled = state.read_bool()?;
+        self.timer = state.read_u16()?;
+        self.position = (state.read_u8()? % 32) as usize;
+        self.sample_buffer = state.read_u8()?;
+        self.cycles_since_fetch = state.read_u16()?;
+        Ok(())
+    }
+
+    fn trigger(&mut self, next_step_clocks_length: bool) {
+        // On the DMG, retriggering just as the channel is about to fetch a
+        // sample corrupts the start of wave RAM
+        if self.enabled && self.timer == 2 {
+            let index = ((self.position + 1) % 32) / 2;
+            if index < 4 {
+                self.wave_ram[0] = self.wave_ram[index];
+            } else {
+                let block = index & !0x3;
+                for i in 0..4 {
+                    self.wave_ram[i] = self.wave_ram[block + i];
+                }
+            }
+        }
+
+        self.enabled = self.dac_enabled;
+        self.length.trigger(next_step_clocks_length);
+        // There's a delay before the first sample is fetched, the sample
+        // buffer isn't refilled until then
+        self.timer = self.period() + 6;
+        self.position = 0;
+    }
+
+    fn period(&self) -> u16 {
+        (2048 - self.frequency) * 2
+    }
+}
@@ -3,40 +3,111 @@ extern crate clap;
 extern crate gameboy;
 extern crate minifb;
 
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
 use clap::{Arg, App};
 use minifb::{Key, Scale, WindowOptions, Window};
 use gameboy::vm::VM;
-use gameboy::cartridge::Cartridge;
+use gameboy::cartridge::{Cartridge, RtcClock};
 use gameboy::interconnect::Interconnect;
 use gameboy::device::{self, Device};
+use gameboy::model::Model;
+use gameboy::link::TcpLink;
+use gameboy::printer::Printer;
+use gameboy::serial::SerialSink;
+use gameboy::wav::WavWriter;
 
 struct ConsoleDevice {
     buffer: Box<[u32]>,
-    window: Window,
+    // There's no window when running headless
+    window: Option<Window>,
 
     width: usize,
     height: usize,
 
     buffer_set: bool,
+
+    sample_rate: u32,
+    audio_recorder: Option<WavWriter<BufWriter<File>>>,
+
+    // Reports events with no other output, like the rumble motor, on stderr
+    verbose: bool,
+
+    // Set when a link cable is connected, so a dropped connection is reported
+    link_error: Option<Rc<RefCell<Option<io::Error>>>>,
+    // Set when a printer is connected, to report the pages it saves
+    printed_pages: Option<Rc<RefCell<Vec<io::Result<PathBuf>>>>>,
 }
 
 impl ConsoleDevice {
-    fn new(window: Window, width: usize, height: usize) -> Self {
+    fn new(window: Option<Window>,
+           width: usize,
+           height: usize,
+           sample_rate: u32,
+           verbose: bool)
+           -> Self {
         ConsoleDevice {
             buffer: vec![0; width * height].into_boxed_slice(),
             window: window,
             width: width,
             height: height,
             buffer_set: false,
+
+            sample_rate: sample_rate,
+            audio_recorder: None,
+
+            verbose: verbose,
+
+            link_error: None,
+            printed_pages: None,
         }
+    }
+
+    fn record_audio(&mut self, file_name: &str) -> io::Result<()> {
+        let recorder = WavWriter::create(file_name, self.sample_rate)?;
+        self.audio_recorder = Some(recorder);
+        Ok(())
+    }
+
+    fn report_printed_pages(&mut self) {
+        if let Some(ref printed_pages) = self.printed_pages {
+            for result in printed_pages.borrow_mut().drain(..) {
+                match result {
+                    Ok(file_name) => eprintln!("Printed {}", file_name.display()),
+                    Err(e) => eprintln!("Failed to save print {}", e),
+                }
+            }
+        }
+    }
 
+    // The VM should be dropped first, so the printer's last page is reported
+    fn finish(&mut self) {
+        self.report_printed_pages();
+        if let Some(recorder) = self.audio_recorder.take() {
+            if let Err(e) = recorder.finish() {
+                eprintln!("Failed to finish the audio recording: {}", e);
+            }
+        }
     }
 }
 
 impl Device for ConsoleDevice {
     fn update(&mut self) {
+        if let Some(ref link_error) = self.link_error {
+            if let Some(e) = link_error.borrow_mut().take() {
+                eprintln!("Link cable disconnected: {}", e);
+            }
+        }
+        self.report_printed_pages();
+
         if self.buffer_set {
-            self.window.update_with_buffer(&*self.buffer);
+            if let Some(ref mut window) = self.window {
+                window.update_with_buffer(&*self.buffer);
+            }
             self.buffer_set = false;
         }
     }
@@ -51,6 +122,30 @@ impl Device for ConsoleDevice {
         self.buffer_set = true;
     }
 
+    fn audio_sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn queue_audio_samples(&mut self, samples: &[f32]) {
+        let result = match self.audio_recorder {
+            Some(ref mut recorder) => recorder.write_samples(samples),
+            None => Ok(()),
+        };
+        if let Err(e) = result {
+            eprintln!("Failed to write audio samples, recording stopped: {}", e);
+            self.audio_recorder = None;
+        }
+    }
+
+    fn set_rumble(&mut self, active: bool) {
+        // There's no motor to drive. Games pulse it quickly, so it's only
+        // reported when asked for, and kept out of the serial output on
+        // stdout.
+        if self.verbose {
+            eprintln!("Rumble {}", if active { "on" } else { "off" });
+        }
+    }
+
     fn key_down(&self, key: device::Key) -> bool {
         let key = match key {
             device::Key::Up => Key::Up,
@@ -61,13 +156,22 @@ impl Device for ConsoleDevice {
             device::Key::Enter => Key::Enter,
             device::Key::Z => Key::Z,
             device::Key::X => Key::X,
+            device::Key::R => Key::R,
+            device::Key::F1 => Key::F1,
+            device::Key::F2 => Key::F2,
+            device::Key::F3 => Key::F3,
+            device::Key::F4 => Key::F4,
+            device::Key::F5 => Key::F5,
+            device::Key::F6 => Key::F6,
+            device::Key::F7 => Key::F7,
+            device::Key::F8 => Key::F8,
         };
 
-        self.window.is_key_down(key)
+        self.window.as_ref().map_or(false, |w| w.is_key_down(key))
     }
 
     fn running(&self) -> bool {
-        self.window.is_open() && !self.window.is_key_down(Key::Escape)
+        self.window.as_ref().map_or(true, |w| w.is_open() && !w.is_key_down(Key::Escape))
     }
 }
 
@@ -90,33 +194,191 @@ fn main() {
                  .short("d")
                  .long("debug")
                  .takes_value(false))
+        .arg(Arg::with_name("verbose")
+                 .help("Reports the rumble motor switching on and off on stderr")
+                 .short("v")
+                 .long("verbose")
+                 .takes_value(false))
+        .arg(Arg::with_name("record-audio")
+                 .help("Records the audio output to a WAV file")
+                 .long("record-audio")
+                 .value_name("FILE")
+                 .takes_value(true))
+        .arg(Arg::with_name("sample-rate")
+                 .help("Sets the audio output sample rate in Hz")
+                 .long("sample-rate")
+                 .takes_value(true)
+                 .default_value("44100"))
+        .arg(Arg::with_name("headless")
+                 .help("Runs this many frames as fast as possible without opening a window")
+                 .long("headless")
+                 .value_name("FRAMES")
+                 .takes_value(true)
+                 .conflicts_with("debug"))
+        .arg(Arg::with_name("rtc-clock")
+                 .help("Sets whether the cartridge clock follows emulated or host time")
+                 .long("rtc-clock")
+                 .takes_value(true)
+                 .possible_values(&["emulated", "host"])
+                 .default_value("host"))
+        .arg(Arg::with_name("unlock-vram")
+                 .help("Lets the CPU access VRAM and OAM while the PPU is using them")
+                 .long("unlock-vram")
+                 .takes_value(false))
+        .arg(Arg::with_name("rewind-memory")
+                 .help("Sets the memory in MiB kept for rewinding, 0 disables rewind")
+                 .long("rewind-memory")
+                 .takes_value(true)
+                 .default_value("64"))
+        .arg(Arg::with_name("rewind-interval")
+                 .help("Sets the number of frames between rewind snapshots")
+                 .long("rewind-interval")
+                 .takes_value(true)
+                 .default_value("2"))
+        .arg(Arg::with_name("model")
+                 .help("Sets the hardware to emulate, auto uses the CGB for CGB cartridges")
+                 .long("model")
+                 .takes_value(true)
+                 .possible_values(&["auto", "dmg", "cgb"])
+                 .default_value("auto"))
+        .arg(Arg::with_name("colour-correction")
+                 .help("Adjusts CGB colours to look like they did on the CGB's screen")
+                 .long("colour-correction")
+                 .takes_value(false))
+        .arg(Arg::with_name("link-listen")
+                 .help("Waits for another emulator to connect a link cable on this port")
+                 .long("link-listen")
+                 .value_name("PORT")
+                 .takes_value(true)
+                 .conflicts_with("link-connect"))
+        .arg(Arg::with_name("link-connect")
+                 .help("Connects a link cable to another emulator listening at this address")
+                 .long("link-connect")
+                 .value_name("HOST:PORT")
+                 .takes_value(true))
+        .arg(Arg::with_name("printer")
+                 .help("Connects a Game Boy Printer that saves prints as PNGs in this directory")
+                 .long("printer")
+                 .value_name("DIR")
+                 .takes_value(true)
+                 .conflicts_with_all(&["link-listen", "link-connect"]))
+        .arg(Arg::with_name("serial-stdout")
+                 .help("Prints bytes sent over the serial port to stdout")
+                 .long("serial-stdout")
+                 .takes_value(false)
+                 .conflicts_with_all(&["link-listen", "link-connect", "printer"]))
         .get_matches();
 
     let input_file = matches.value_of("INPUT").unwrap();
     let mut cartridge = Cartridge::load(input_file).unwrap();
     let mut with_boot_rom = false;
     let start_in_debug = matches.is_present("debug");
+    let sample_rate = value_t_or_exit!(matches, "sample-rate", u32);
+    if sample_rate == 0 {
+        eprintln!("The sample rate must be greater than 0");
+        process::exit(1);
+    }
+    let rewind_memory = value_t_or_exit!(matches, "rewind-memory", usize);
+    let rewind_interval = value_t_or_exit!(matches, "rewind-interval", u32);
+
+    match matches.value_of("rtc-clock") {
+        Some("emulated") => cartridge.set_rtc_clock(RtcClock::Emulated),
+        _ => cartridge.set_rtc_clock(RtcClock::Host),
+    }
+    let save_file = Path::new(input_file).with_extension("sav");
+    if let Err(e) = cartridge.attach_save_file(&save_file) {
+        eprintln!("Failed to load {}: {}, starting with empty cartridge RAM",
+                  save_file.display(),
+                  e);
+    }
 
     if let Some(boot_file) = matches.value_of("boot-rom") {
         with_boot_rom = true;
         cartridge.load_boot_rom(boot_file).unwrap();
     }
-    let interconnect = Interconnect::new(cartridge);
+    let model = match matches.value_of("model") {
+        Some("dmg") => Model::Dmg,
+        Some("cgb") => Model::Cgb,
+        _ => Model::detect(&cartridge),
+    };
+    if matches.is_present("verbose") {
+        eprintln!("Model: {}", model);
+    }
+
+    let mut interconnect = Interconnect::with_model(cartridge, model);
+    interconnect.set_colour_correction(matches.is_present("colour-correction"));
+    interconnect.set_access_locking(!matches.is_present("unlock-vram"));
+
+    let link = if matches.is_present("link-listen") {
+        let port = value_t_or_exit!(matches, "link-listen", u16);
+        eprintln!("Waiting for a link cable connection on port {}", port);
+        Some(TcpLink::listen(port))
+    } else if let Some(addr) = matches.value_of("link-connect") {
+        Some(TcpLink::connect(addr))
+    } else {
+        None
+    };
+    let mut link_error = None;
+    let mut printed_pages = None;
+    if let Some(link) = link {
+        let link = link.unwrap_or_else(|e| {
+            eprintln!("Failed to connect the link cable: {}", e);
+            process::exit(1);
+        });
+        link_error = Some(link.error());
+        interconnect.set_serial_peer(Box::new(link));
+    } else if let Some(output_dir) = matches.value_of("printer") {
+        let printer = Printer::new(output_dir);
+        printed_pages = Some(printer.saved_pages());
+        interconnect.set_serial_peer(Box::new(printer));
+    } else if matches.is_present("serial-stdout") {
+        interconnect.set_serial_peer(Box::new(SerialSink::with_echo()));
+    }
+
     let width = interconnect.get_width();
     let height = interconnect.get_height();
 
     let mut vm = VM::new(interconnect, with_boot_rom, start_in_debug);
+    vm.set_state_path(Path::new(input_file));
+    if rewind_memory > 0 && rewind_interval > 0 {
+        vm.enable_rewind(rewind_memory * 1024 * 1024, rewind_interval);
+    }
 
-    let window_options = WindowOptions {
-        borderless: false,
-        title: true,
-        resize: false,
-        scale: Scale::X2,
+    let headless_frames = if matches.is_present("headless") {
+        Some(value_t_or_exit!(matches, "headless", u32))
+    } else {
+        None
+    };
+    let window = if headless_frames.is_some() {
+        None
+    } else {
+        let window_options = WindowOptions {
+            borderless: false,
+            title: true,
+            resize: false,
+            scale: Scale::X2,
+        };
+        Some(Window::new("GBrs", width, height, window_options).unwrap())
     };
 
-    let window = Window::new("GBrs", width, height, window_options).unwrap();
-
-    let mut device = ConsoleDevice::new(window, width, height);
+    let mut device = ConsoleDevice::new(window,
+                                        width,
+                                        height,
+                                        sample_rate,
+                                        matches.is_present("verbose"));
+    device.link_error = link_error;
+    device.printed_pages = printed_pages;
+    if let Some(audio_file) = matches.value_of("record-audio") {
+        if let Err(e) = device.record_audio(audio_file) {
+            eprintln!("Failed to record audio to {}: {}", audio_file, e);
+            process::exit(1);
+        }
+    }
 
-    vm.run(&mut device);
+    match headless_frames {
+        Some(frames) => vm.run_frames(&mut device, frames),
+        None => vm.run(&mut device),
+    }
+    drop(vm);
+    device.finish();
 }
@@ -0,0 +1,74 @@
+use interconnect::Interconnect;
+use device::Device;
+use gpu::OamCorruption;
+
+const M_CYCLE_CLOCKS: u16 = 4;
+
+// The CPU's side of the memory bus. Each access takes an M-cycle, the rest of
+// the system is run through the cycle before the access happens, so the
+// access sees the timer, PPU and DMA as they are at that point of the
+// instruction.
+pub struct Bus<'a> {
+    interconnect: &'a mut Interconnect,
+    device: &'a mut Device,
+    cycles: u16, // Clocks run since the bus was created
+}
+
+impl<'a> Bus<'a> {
+    pub fn new(interconnect: &'a mut Interconnect, device: &'a mut Device) -> Self {
+        Bus {
+            interconnect: interconnect,
+            device: device,
+            cycles: 0,
+        }
+    }
+
+    // Runs an M-cycle without a memory access
+    pub fn tick(&mut self) {
+        self.interconnect.step(M_CYCLE_CLOCKS, self.device);
+        self.cycles += M_CYCLE_CLOCKS;
+    }
+
+    // Runs an M-cycle where the address is put on the bus for the 16-bit
+    // incrementer without being read or written, e.g. for INC rr
+    pub fn tick_with_address(&mut self, addr: u16) {
+        self.tick();
+        self.interconnect.corrupt_oam(addr, OamCorruption::Write);
+    }
+
+    pub fn read_byte(&mut self, addr: u16) -> u8 {
+        self.tick();
+        self.interconnect.corrupt_oam(addr, OamCorruption::Read);
+        self.interconnect.read_byte(addr)
+    }
+
+    // A read where the incrementer also changes the address register, as in
+    // LDI A,(HL) and POP
+    pub fn read_byte_increment(&mut self, addr: u16) -> u8 {
+        self.tick();
+        self.interconnect.corrupt_oam(addr, OamCorruption::ReadIncrement);
+        self.interconnect.read_byte(addr)
+    }
+
+    pub fn write_byte(&mut self, addr: u16, val: u8) {
+        self.tick();
+        self.interconnect.corrupt_oam(addr, OamCorruption::Write);
+        self.interconnect.write_byte(addr, val);
+    }
+
+    // The low byte is written first
+    pub fn write_halfword(&mut self, addr: u16, val: u16) {
+        self.write_byte(addr, val as u8);
+        self.write_byte(addr.wrapping_add(1), (val >> 8) as u8);
+    }
+
+    pub fn cycles(&self) -> u16 {
+        self.cycles
+    }
+
+    // For the CPU's dealings with the system that don't go over the bus,
+    // e.g. checking for interrupts
+    pub fn interconnect(&mut self) -> &mut Interconnect {
+        self.interconnect
+    }
+}
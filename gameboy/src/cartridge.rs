@@ -1,14 +1,33 @@
-use std::io::{self, Read};
+use std::io::{self, Read, Write};
 use std::fmt;
-use std::fs::File;
-use std::path::Path;
-
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use time;
+use device::Device;
+use state::{self, StateReader, StateWriter};
+
+const CGB_FLAG_OFFSET: usize = 0x0143;
 const ROM_TYPE_OFFSET: usize = 0x0147;
 const RAM_SIZE_OFFSET: usize = 0x0149;
 
+const CLOCK_RATE: u32 = 4194304;
+
+// The MBC2 has 512 4-bit RAM cells built in
+const MBC2_RAM_LENGTH: usize = 512;
+
+// MBC3 save files have the clock appended, as ten 32-bit registers (current
+// then latched) followed by a 64-bit or, in older files, 32-bit timestamp
+const RTC_FOOTER_LENGTH: usize = 48;
+const RTC_FOOTER_LENGTH_32: usize = 44;
+
+#[derive(PartialEq, Eq)]
 enum Mbc {
     NONE,
     MBC1,
+    MBC2,
+    MBC3,
+    MBC5,
 }
 
 impl From<u8> for Mbc {
@@ -16,6 +35,9 @@ impl From<u8> for Mbc {
         match val {
             0x00 | 0x08 | 0x09 => Mbc::NONE,
             0x01 | 0x02 | 0x03 => Mbc::MBC1,
+            0x05 | 0x06 => Mbc::MBC2,
+            0x0f...0x13 => Mbc::MBC3,
+            0x19...0x1e => Mbc::MBC5,
             _ => panic!("Unknown cartridge type {:02x}", val),
         }
     }
@@ -28,10 +50,233 @@ impl fmt::Display for Mbc {
                match *self {
                    Mbc::NONE => "None",
                    Mbc::MBC1 => "MBC1",
+                   Mbc::MBC2 => "MBC2",
+                   Mbc::MBC3 => "MBC3",
+                   Mbc::MBC5 => "MBC5",
                })
     }
 }
 
+// Where the MBC3 real time clock gets its time from
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RtcClock {
+    // Advances with the emulated CPU clock, so it stops while the emulator is
+    // paused and is deterministic
+    Emulated,
+    // Follows the host's wall clock
+    Host,
+}
+
+// MBC3 real time clock, mapped into 0xa000 - 0xbfff when registers 0x08 -
+// 0x0c are selected
+struct Rtc {
+    clock: RtcClock,
+
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days: u16, // 9 bits
+    halted: bool,
+    day_carry: bool,
+
+    latched: [u8; 5],
+    latch_primed: bool, // Set when 0x00 is written to 0x6000 - 0x7fff
+
+    cycles: u32, // Emulated cycles since the last second ticked
+    host_time: i64, // Host seconds when the clock was last brought up to date
+}
+
+impl Rtc {
+    fn new() -> Self {
+        Rtc {
+            clock: RtcClock::Emulated,
+
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            days: 0,
+            halted: false,
+            day_carry: false,
+
+            latched: [0; 5],
+            latch_primed: false,
+
+            cycles: 0,
+            host_time: time::get_time().sec,
+        }
+    }
+
+    fn set_clock(&mut self, clock: RtcClock) {
+        self.update();
+        self.clock = clock;
+    }
+
+    fn step(&mut self, cycles: u16) {
+        if self.clock != RtcClock::Emulated || self.halted {
+            return;
+        }
+
+        self.cycles += cycles as u32;
+        while self.cycles >= CLOCK_RATE {
+            self.cycles -= CLOCK_RATE;
+            self.tick();
+        }
+    }
+
+    // Catches up with the host clock
+    fn update(&mut self) {
+        if self.clock != RtcClock::Host {
+            return;
+        }
+
+        let now = time::get_time().sec;
+        if !self.halted {
+            let elapsed = now - self.host_time;
+            self.advance(elapsed);
+        }
+        self.host_time = now;
+    }
+
+    fn advance(&mut self, seconds: i64) {
+        let mut remaining = seconds;
+        while remaining > 0 {
+            // Skip whole days at once, which matters when catching up with
+            // time spent switched off, as long as the registers are in range
+            if remaining >= 86400 && self.seconds < 60 && self.minutes < 60 && self.hours < 24 {
+                self.days = (self.days + 1) & 0x1ff;
+                if self.days == 0 {
+                    self.day_carry = true;
+                }
+                remaining -= 86400;
+            } else {
+                self.tick();
+                remaining -= 1;
+            }
+        }
+    }
+
+    fn tick(&mut self) {
+        // Each counter wraps at its bit width, an out of range value written
+        // by the game counts up to the wrap without carrying
+        self.seconds = (self.seconds + 1) & 0x3f;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+
+        self.minutes = (self.minutes + 1) & 0x3f;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+
+        self.hours = (self.hours + 1) & 0x1f;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+
+        self.days = (self.days + 1) & 0x1ff;
+        if self.days == 0 {
+            self.day_carry = true;
+        }
+    }
+
+    fn write_latch(&mut self, val: u8) {
+        if self.latch_primed && val == 0x01 {
+            self.update();
+            self.latched = self.registers();
+        }
+        self.latch_primed = val == 0x00;
+    }
+
+    fn registers(&self) -> [u8; 5] {
+        let mut control = (self.days >> 8) as u8;
+        if self.halted {
+            control |= 1 << 6;
+        }
+        if self.day_carry {
+            control |= 1 << 7;
+        }
+
+        [self.seconds, self.minutes, self.hours, self.days as u8, control]
+    }
+
+    fn read_reg(&self, reg: usize) -> u8 {
+        self.latched[reg - 0x08]
+    }
+
+    fn write_reg(&mut self, reg: usize, val: u8) {
+        self.update();
+        self.set_reg(reg, val);
+    }
+
+    fn write_footer<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.update();
+
+        for reg in self.registers().iter().chain(self.latched.iter()) {
+            writer.write_u32::<LittleEndian>(*reg as u32)?;
+        }
+        writer.write_u64::<LittleEndian>(time::get_time().sec as u64)
+    }
+
+    fn read_footer(&mut self, footer: &[u8]) {
+        for i in 0..5 {
+            self.set_reg(0x08 + i, LittleEndian::read_u32(&footer[i * 4..]) as u8);
+            self.latched[i] = LittleEndian::read_u32(&footer[(i + 5) * 4..]) as u8;
+        }
+
+        // The time passed while the emulator wasn't running is caught up with
+        // if the clock follows the host
+        self.host_time = if footer.len() >= RTC_FOOTER_LENGTH {
+            LittleEndian::read_u64(&footer[40..]) as i64
+        } else {
+            LittleEndian::read_u32(&footer[40..]) as i64
+        };
+    }
+
+    fn save_state(&mut self, state: &mut StateWriter) {
+        self.update();
+
+        state.write_bytes(&self.registers());
+        state.write_bytes(&self.latched);
+        state.write_bool(self.latch_primed);
+        state.write_u32(self.cycles);
+        state.write_u64(self.host_time as u64);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        let mut registers = [0; 5];
+        state.read_bytes(&mut registers)?;
+        for (i, reg) in registers.iter().enumerate() {
+            self.set_reg(0x08 + i, *reg);
+        }
+        state.read_bytes(&mut self.latched)?;
+        self.latch_primed = state.read_bool()?;
+        self.cycles = state.read_u32()? % CLOCK_RATE;
+        self.host_time = state.read_u64()? as i64;
+        Ok(())
+    }
+
+    fn set_reg(&mut self, reg: usize, val: u8) {
+        match reg {
+            0x08 => {
+                self.seconds = val & 0x3f;
+                self.cycles = 0;
+            }
+            0x09 => self.minutes = val & 0x3f,
+            0x0a => self.hours = val & 0x1f,
+            0x0b => self.days = (self.days & 0x100) | val as u16,
+            0x0c => {
+                self.days = (self.days & 0xff) | ((val as u16 & 0x01) << 8);
+                self.halted = val & (1 << 6) != 0;
+                self.day_carry = val & (1 << 7) != 0;
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
 pub struct Cartridge {
     rom: Box<[u8]>,
     ram: Box<[u8]>,
@@ -43,6 +288,15 @@ pub struct Cartridge {
     rom_bank_lower: usize,
     bank_upper: usize,
     ram_banking: bool,
+    rtc: Rtc,
+
+    has_rumble: bool,
+    rumble_active: bool, // Last motor state written by the game
+    rumble_reported: bool, // Last motor state passed to the device
+
+    save_file: Option<PathBuf>,
+    ram_dirty: bool, // Set when the RAM or clock has changed since it was saved
+    save_file_unreadable: bool, // Left alone until there's something to save
 
     rom_offsets: (usize, usize),
     ram_bank_offset: usize,
@@ -57,12 +311,12 @@ impl Cartridge {
 
         let cart = Cartridge::from_bytes(&buffer);
 
-        println!("Loaded {:0x} bytes of cart", buffer.len());
-        println!("{}", cart.name());
-        println!("Cart type: {}", cart.type_name());
-        println!("MBC: {}", cart.mbc);
-        println!("Rom Size: {}", cart.rom_size());
-        println!("Ram Size: {} KByte", cart.ram.len() / 1024);
+        eprintln!("Loaded {:0x} bytes of cart", buffer.len());
+        eprintln!("{}", cart.name());
+        eprintln!("Cart type: {}", cart.type_name());
+        eprintln!("MBC: {}", cart.mbc);
+        eprintln!("Rom Size: {}", cart.rom_size());
+        eprintln!("Ram Size: {} KByte", cart.ram.len() / 1024);
 
         Ok(cart)
     }
@@ -78,12 +332,84 @@ impl Cartridge {
         Ok(())
     }
 
+    // Battery backed RAM is loaded from the file if it exists, and written
+    // back to it by save_ram. Carts without a battery ignore the file. If the
+    // file can't be read the RAM is left empty and the file is only replaced
+    // once the game has changed the RAM.
+    pub fn attach_save_file<P: AsRef<Path>>(&mut self, file_name: P) -> io::Result<()> {
+        if !self.has_battery() {
+            return Ok(());
+        }
+        self.save_file = Some(file_name.as_ref().to_path_buf());
+
+        let result = self.read_save_file(file_name);
+        self.save_file_unreadable = result.is_err();
+        result
+    }
+
+    fn read_save_file<P: AsRef<Path>>(&mut self, file_name: P) -> io::Result<()> {
+        let mut file = match File::open(file_name) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer)?;
+
+        let ram_length = self.ram.len();
+        if buffer.len() < ram_length {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Save file is too short"));
+        }
+        self.ram.copy_from_slice(&buffer[..ram_length]);
+
+        let footer = &buffer[ram_length..];
+        if self.has_rtc() && footer.len() >= RTC_FOOTER_LENGTH_32 {
+            self.rtc.read_footer(footer);
+        }
+
+        Ok(())
+    }
+
+    pub fn save_ram(&mut self) -> io::Result<()> {
+        let file_name = match self.save_file {
+            Some(ref file_name) => file_name.clone(),
+            None => return Ok(()),
+        };
+        if self.save_file_unreadable && !self.ram_dirty {
+            return Ok(());
+        }
+
+        let mut buffer = self.ram.to_vec();
+        if self.has_rtc() {
+            self.rtc.write_footer(&mut buffer)?;
+        }
+
+        // Write to a temporary file first so a crash part way through doesn't
+        // destroy the existing save
+        let temp_file_name = file_name.with_extension("sav.tmp");
+        {
+            let mut file = File::create(&temp_file_name)?;
+            file.write_all(&buffer)?;
+            file.sync_all()?;
+        }
+        fs::rename(temp_file_name, file_name)?;
+
+        self.ram_dirty = false;
+        self.save_file_unreadable = false;
+        Ok(())
+    }
+
+    pub fn ram_dirty(&self) -> bool {
+        self.ram_dirty
+    }
+
     pub fn from_bytes(bytes: &[u8]) -> Cartridge {
         let bytes_copy = bytes.to_vec();
 
         let rom_type = bytes[ROM_TYPE_OFFSET];
         let mbc: Mbc = rom_type.into();
         let ram_size = match bytes[RAM_SIZE_OFFSET] {
+            _ if mbc == Mbc::MBC2 => MBC2_RAM_LENGTH,
             0x00 => 0,
             0x01 => 2 * 1024,
             0x02 => 8 * 1024,
@@ -104,6 +430,15 @@ impl Cartridge {
             rom_bank_lower: 1,
             bank_upper: 0,
             ram_banking: false,
+            rtc: Rtc::new(),
+
+            has_rumble: rom_type >= 0x1c && rom_type <= 0x1e,
+            rumble_active: false,
+            rumble_reported: false,
+
+            save_file: None,
+            ram_dirty: false,
+            save_file_unreadable: false,
 
             rom_offsets: (0x0000, 0x4000),
             ram_bank_offset: 0,
@@ -117,13 +452,25 @@ impl Cartridge {
 
         match addr {
             0...0x00ff if self.boot_rom_active => self.boot_rom[addr],
+            // The CGB boot ROM carries on past the cartridge header
+            0x0200...0x08ff if self.boot_rom_active && addr < self.boot_rom.len() => {
+                self.boot_rom[addr]
+            }
             0...0x3fff => self.rom[lower + addr],
             0x4000...0x7fff => self.rom[upper + (addr - 0x4000)],
             0xa000...0xbfff => {
-                if self.ram_enabled {
-                    self.ram[self.ram_bank_offset + (addr - 0xa000)]
-                } else {
+                if !self.ram_enabled {
+                    0xff
+                } else if self.rtc_selected() {
+                    self.rtc.read_reg(self.bank_upper)
+                } else if self.mbc == Mbc::MBC2 {
+                    // Only the lower nibble is stored, the upper nibble
+                    // floats high
+                    0xf0 | self.ram[addr & (MBC2_RAM_LENGTH - 1)]
+                } else if self.ram.is_empty() {
                     0xff
+                } else {
+                    self.ram[self.ram_bank_offset + (addr - 0xa000)]
                 }
             }
             _ => panic!("Unrecognized read address in cartridge {:04x}", addr),
@@ -131,6 +478,91 @@ impl Cartridge {
     }
 
     pub fn write(&mut self, addr: u16, val: u8) {
+        match self.mbc {
+            Mbc::NONE => self.write_none(addr, val),
+            Mbc::MBC1 => self.write_mbc1(addr, val),
+            Mbc::MBC2 => self.write_mbc2(addr, val),
+            Mbc::MBC3 => self.write_mbc3(addr, val),
+            Mbc::MBC5 => self.write_mbc5(addr, val),
+        }
+    }
+
+    pub fn step(&mut self, cycles: u16, device: &mut Device) {
+        if self.mbc == Mbc::MBC3 {
+            self.rtc.step(cycles);
+        }
+
+        if self.rumble_active != self.rumble_reported {
+            self.rumble_reported = self.rumble_active;
+            device.set_rumble(self.rumble_active);
+        }
+    }
+
+    pub fn set_rtc_clock(&mut self, clock: RtcClock) {
+        self.rtc.set_clock(clock);
+    }
+
+    // Bit 7 of the CGB flag is set by both CGB enhanced (0x80) and CGB only
+    // (0xc0) cartridges
+    pub fn supports_cgb(&self) -> bool {
+        self.rom[CGB_FLAG_OFFSET] & 0x80 != 0
+    }
+
+    pub fn disable_boot_rom(&mut self) {
+        self.boot_rom_active = false;
+    }
+
+    // The header checksums identify the ROM a state was saved with, the ROM
+    // itself isn't stored
+    pub fn save_state(&mut self, state: &mut StateWriter) {
+        state.write_bytes(&self.rom[0x0134..0x0150]);
+
+        state.write_bytes(&self.ram);
+        state.write_bool(self.boot_rom_active);
+        state.write_u16(self.rom_bank_lower as u16);
+        state.write_u16(self.bank_upper as u16);
+        state.write_bool(self.ram_banking);
+        state.write_bool(self.ram_enabled);
+        state.write_bool(self.rumble_active);
+        self.rtc.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        let mut header = [0; 0x1c];
+        state.read_bytes(&mut header)?;
+        if header[..] != self.rom[0x0134..0x0150] {
+            return Err(state::invalid_state("State was saved with a different ROM"));
+        }
+
+        state.read_bytes(&mut self.ram)?;
+        self.boot_rom_active = state.read_bool()? && !self.boot_rom.is_empty();
+        self.rom_bank_lower = state.read_u16()? as usize;
+        self.bank_upper = state.read_u16()? as usize;
+        self.ram_banking = state.read_bool()?;
+        self.ram_enabled = state.read_bool()?;
+        self.rumble_active = state.read_bool()?;
+        self.rtc.load_state(state)?;
+
+        self.update_rom_offset();
+        self.update_ram_offset();
+        self.ram_dirty = true;
+        Ok(())
+    }
+
+    fn write_none(&mut self, addr: u16, val: u8) {
+        let addr = addr as usize;
+        match addr {
+            0x0000...0x7fff => {} // No banking registers
+            0xa000...0xbfff => self.write_ram(addr, val),
+            _ => {
+                panic!("Unrecognized write address in cartridge {:04x}={:02x}",
+                       addr,
+                       val)
+            }
+        }
+    }
+
+    fn write_mbc1(&mut self, addr: u16, val: u8) {
         let addr = addr as usize;
         match addr {
             0x0000...0x1fff => self.ram_enabled = (val & 0x0a) != 0,
@@ -149,9 +581,32 @@ impl Cartridge {
                 self.update_rom_offset();
                 self.update_ram_offset();
             }
+            0xa000...0xbfff => self.write_ram(addr, val),
+            _ => {
+                panic!("Unrecognized write address in cartridge {:04x}={:02x}",
+                       addr,
+                       val)
+            }
+        }
+    }
+
+    fn write_mbc2(&mut self, addr: u16, val: u8) {
+        let addr = addr as usize;
+        match addr {
+            // Bit 8 of the address selects between RAM enable and ROM bank
+            0x0000...0x3fff if addr & 0x0100 == 0 => {
+                self.ram_enabled = (val & 0x0f) == 0x0a
+            }
+            0x0000...0x3fff => {
+                let val = (val as usize) & 0x0f;
+                self.rom_bank_lower = if val == 0x00 { 0x01 } else { val };
+                self.update_rom_offset();
+            }
+            0x4000...0x7fff => {}
             0xa000...0xbfff => {
                 if self.ram_enabled {
-                    self.ram[self.ram_bank_offset + (addr - 0xa000)] = val;
+                    self.ram[addr & (MBC2_RAM_LENGTH - 1)] = val & 0x0f;
+                    self.ram_dirty = true;
                 }
             }
             _ => {
@@ -162,14 +617,92 @@ impl Cartridge {
         }
     }
 
-    pub fn disable_boot_rom(&mut self) {
-        self.boot_rom_active = false;
+    fn write_mbc3(&mut self, addr: u16, val: u8) {
+        let addr = addr as usize;
+        match addr {
+            // Enables both the RAM and the RTC registers
+            0x0000...0x1fff => self.ram_enabled = (val & 0x0f) == 0x0a,
+            0x2000...0x3fff => {
+                let val = (val as usize) & 0x7f;
+                self.rom_bank_lower = if val == 0x00 { 0x01 } else { val };
+                self.update_rom_offset();
+            }
+            0x4000...0x5fff => {
+                // 0x00 - 0x03 select a RAM bank, 0x08 - 0x0c an RTC register
+                self.bank_upper = val as usize;
+                self.update_ram_offset();
+            }
+            0x6000...0x7fff => self.rtc.write_latch(val),
+            0xa000...0xbfff => {
+                if self.ram_enabled && self.rtc_selected() {
+                    self.rtc.write_reg(self.bank_upper, val);
+                    self.ram_dirty = true;
+                } else {
+                    self.write_ram(addr, val);
+                }
+            }
+            _ => {
+                panic!("Unrecognized write address in cartridge {:04x}={:02x}",
+                       addr,
+                       val)
+            }
+        }
+    }
+
+    fn write_mbc5(&mut self, addr: u16, val: u8) {
+        let addr = addr as usize;
+        match addr {
+            0x0000...0x1fff => self.ram_enabled = (val & 0x0f) == 0x0a,
+            0x2000...0x2fff => {
+                // Bank 0 can be mapped into the upper window on the MBC5
+                self.rom_bank_lower = (self.rom_bank_lower & 0x100) | val as usize;
+                self.update_rom_offset();
+            }
+            0x3000...0x3fff => {
+                self.rom_bank_lower = (self.rom_bank_lower & 0xff) | ((val as usize & 0x01) << 8);
+                self.update_rom_offset();
+            }
+            0x4000...0x5fff => {
+                // On rumble carts bit 3 drives the motor instead of selecting
+                // a RAM bank
+                if self.has_rumble {
+                    self.rumble_active = val & (1 << 3) != 0;
+                    self.bank_upper = (val as usize) & 0x07;
+                } else {
+                    self.bank_upper = (val as usize) & 0x0f;
+                }
+                self.update_ram_offset();
+            }
+            0x6000...0x7fff => {}
+            0xa000...0xbfff => self.write_ram(addr, val),
+            _ => {
+                panic!("Unrecognized write address in cartridge {:04x}={:02x}",
+                       addr,
+                       val)
+            }
+        }
+    }
+
+    fn write_ram(&mut self, addr: usize, val: u8) {
+        if self.ram_enabled && !self.ram.is_empty() {
+            self.ram[self.ram_bank_offset + (addr - 0xa000)] = val;
+            self.ram_dirty = true;
+        }
+    }
+
+    fn rtc_selected(&self) -> bool {
+        self.mbc == Mbc::MBC3 && self.bank_upper >= 0x08 && self.bank_upper <= 0x0c
     }
 
     fn update_rom_offset(&mut self) {
-        let bank_upper = self.bank_upper << 5;
-        let lower = if self.ram_banking { bank_upper } else { 0x00 };
-        let upper = bank_upper | self.rom_bank_lower;
+        let (lower, upper) = match self.mbc {
+            Mbc::MBC2 | Mbc::MBC3 | Mbc::MBC5 => (0x00, self.rom_bank_lower),
+            _ => {
+                let bank_upper = self.bank_upper << 5;
+                let lower = if self.ram_banking { bank_upper } else { 0x00 };
+                (lower, bank_upper | self.rom_bank_lower)
+            }
+        };
 
         let lower_bank = (lower * 0x4000) & (self.rom.len() - 1);
         let upper_bank = (upper * 0x4000) & (self.rom.len() - 1);
@@ -178,7 +711,13 @@ impl Cartridge {
     }
 
     fn update_ram_offset(&mut self) {
-        self.ram_bank_offset = if self.ram_banking {
+        let banked = match self.mbc {
+            Mbc::MBC3 => self.bank_upper <= 0x03,
+            Mbc::MBC5 => true,
+            _ => self.ram_banking,
+        };
+
+        self.ram_bank_offset = if banked && !self.ram.is_empty() {
             (self.bank_upper * 0x2000) & (self.ram.len() - 1)
         } else {
             0
@@ -203,6 +742,20 @@ impl Cartridge {
         self.rom[0x0147]
     }
 
+    fn has_battery(&self) -> bool {
+        match self.rom_type() {
+            0x03 | 0x06 | 0x09 | 0x0f | 0x10 | 0x13 | 0x1b | 0x1e => true,
+            _ => false,
+        }
+    }
+
+    fn has_rtc(&self) -> bool {
+        match self.rom_type() {
+            0x0f | 0x10 => true,
+            _ => false,
+        }
+    }
+
     fn type_name(&self) -> &'static str {
         match self.rom_type() {
             0x00 => "ROM ONLY",
@@ -10,6 +10,7 @@ pub enum Command {
     ShowRegs,
     ShowIORegs,
     Step(usize),
+    StepBack(usize),
     Continue,
     Goto(u16),
     ShowMem(Option<u16>),
@@ -48,6 +49,11 @@ fn command<I: Stream<Item = char>>(input: I) -> ParseResult<Command, I> {
                 .map(|(_, count)| Command::Step(count.unwrap_or(1)))
                 .boxed();
 
+    let step_back = (choice([try(string("stepback")), try(string("sb"))]),
+                     optional((spaces(), usize_()).map(|x| x.1)))
+            .map(|(_, count)| Command::StepBack(count.unwrap_or(1)))
+            .boxed();
+
     let continue_ =
         choice([try(string("continue")), try(string("c"))]).map(|_| Command::Continue).boxed();
 
@@ -104,6 +110,7 @@ fn command<I: Stream<Item = char>>(input: I) -> ParseResult<Command, I> {
     choice(vec![show_regs,
                 show_io_regs,
                 step,
+                step_back,
                 continue_,
                 goto,
                 show_mem,
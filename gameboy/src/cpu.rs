@@ -1,4 +1,8 @@
+use std::io;
+use bus::Bus;
+use device::Device;
 use interconnect::Interconnect;
+use state::{StateReader, StateWriter};
 
 #[derive(Clone, Copy)]
 pub struct Flags {
@@ -51,10 +55,15 @@ pub struct Cpu {
     pub sp: u16,
     pub pc: u16,
 
-    pub instructions_to_di: u8,
+    // EI only enables interrupts once the instruction after it has run
+    pub instructions_to_ei: u8,
     pub interrupts_enabled: bool,
 
-    pub halted: i8,
+    pub halted: bool,
+    // Set by HALT when it's skipped, the next opcode is read twice
+    pub halt_bug: bool,
+    // Set by an illegal opcode, only a reset gets the CPU going again
+    pub locked_up: bool,
 
     pub total_cycles: u32,
 }
@@ -62,7 +71,7 @@ pub struct Cpu {
 #[cfg_attr(rustfmt, rustfmt_skip)]
 static CYCLE_COUNTS: [u16; 256] = [
      4, 12,  8,  8,  4,  4,  8,  4, 20,  8,  8,  8,  4,  4,  8,  4,
-     0, 12,  8,  8,  4,  4,  8,  4, 12,  8,  8,  8,  4,  4,  8,  4,
+     4, 12,  8,  8,  4,  4,  8,  4, 12,  8,  8,  8,  4,  4,  8,  4,
      8, 12,  8,  8,  4,  4,  8,  4,  8,  8,  8,  8,  4,  4,  8,  4,
      8, 12,  8,  8, 12, 12, 12,  4,  8,  8,  8,  8,  4,  4,  8,  4,
      4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4,
@@ -121,51 +130,69 @@ impl Cpu {
             sp: 0xfffe,
             pc: 0x100,
 
-            instructions_to_di: 0,
+            instructions_to_ei: 0,
             interrupts_enabled: true,
 
-            halted: 0,
+            halted: false,
+            halt_bug: false,
+            locked_up: false,
 
             total_cycles: 0,
         }
     }
 
+    // Runs an instruction, the rest of the system is run along with it.
+    // Returns the number of clocks taken.
+    pub fn step(&mut self, interconnect: &mut Interconnect, device: &mut Device) -> u16 {
+        let mut bus = Bus::new(interconnect, device);
+        self.execute(&mut bus);
+
+        self.total_cycles += bus.cycles() as u32;
+        bus.cycles()
+    }
+
     #[cfg_attr(feature = "cargo-clippy", allow(match_same_arms, cyclomatic_complexity))]
-    pub fn step(&mut self, interconnect: &mut Interconnect) -> u16 {
-        let interrupt_flags = interconnect.read_byte(0xff0f);
-        let interrupt_enable = interconnect.read_byte(0xffff);
-        let interrupt_request = interrupt_flags & interrupt_enable;
-
-        if self.halted == 1 && interrupt_request == 0 {
-            // Step forward one NOP
-            return 4;
+    fn execute(&mut self, bus: &mut Bus) {
+        let interrupt_request = self.interrupt_request(bus);
+
+        if self.locked_up || bus.interconnect().stopped() {
+            bus.tick();
+            return;
         }
 
-        if self.halted == 1 && !self.interrupts_enabled {
-            self.halted = 0;
+        if self.halted {
+            if interrupt_request == 0 {
+                // Step forward one NOP
+                bus.tick();
+                return;
+            }
+            // A pending interrupt wakes the CPU even with interrupts disabled
+            self.halted = false;
         }
 
-        if self.interrupts_enabled && (interrupt_request != 0) {
-            self.handle_interrupt(interconnect, interrupt_flags, interrupt_enable);
+        if self.interrupts_enabled && interrupt_request != 0 {
+            self.dispatch_interrupt(bus);
+            return;
         }
 
-        let old_pc = self.pc;
-        let instr = self.read_pc_byte(interconnect);
+        let start_cycles = bus.cycles();
+        let instr = self.read_pc_byte(bus);
         let mut cycle_count = CYCLE_COUNTS[instr as usize];
 
         match instr {
             0x00 => {} // NOP - No Operation
             0x01 => {
                 // LD BC, nn
-                let lsb = self.read_pc_byte(interconnect);
-                let msb = self.read_pc_byte(interconnect);
+                let lsb = self.read_pc_byte(bus);
+                let msb = self.read_pc_byte(bus);
 
                 self.b = msb;
                 self.c = lsb;
             }
-            0x02 => interconnect.write_byte(self.bc(), self.a), // LD (BC), A
+            0x02 => bus.write_byte(self.bc(), self.a), // LD (BC), A
             0x03 => {
                 // INC BC
+                bus.tick_with_address(self.bc());
                 let bc = self.bc().wrapping_add(1);
                 self.set_bc(bc);
             }
@@ -177,7 +204,7 @@ impl Cpu {
                 let val = self.b;
                 self.b = self.dec(val);
             }
-            0x06 => self.b = self.read_pc_byte(interconnect), // LD B,n
+            0x06 => self.b = self.read_pc_byte(bus), // LD B,n
             0x07 => {
                 let val = self.a;
                 self.a = self.rlc(val);
@@ -185,9 +212,9 @@ impl Cpu {
             }
             0x08 => {
                 // LD (nn), SP
-                let addr = self.read_pc_halfword(interconnect);
+                let addr = self.read_pc_halfword(bus);
 
-                interconnect.write_halfword(addr, self.sp);
+                bus.write_halfword(addr, self.sp);
             }
             0x09 => {
                 // ADD HL, BC
@@ -198,10 +225,11 @@ impl Cpu {
             }
             0x0a => {
                 let addr = self.bc();
-                self.a = interconnect.read_byte(addr);
+                self.a = bus.read_byte(addr);
             }
             0x0b => {
                 // DEC BC
+                bus.tick_with_address(self.bc());
                 let bc = self.bc().wrapping_sub(1);
                 self.set_bc(bc);
             }
@@ -213,24 +241,40 @@ impl Cpu {
                 let val = self.c;
                 self.c = self.dec(val);
             }
-            0x0e => self.c = self.read_pc_byte(interconnect), // LD C,n
+            0x0e => self.c = self.read_pc_byte(bus), // LD C,n
             0x0f => {
                 // RRC A
                 let val = self.a;
                 self.a = self.rrc(val);
                 self.f.z = false;
             }
+            0x10 => {
+                // STOP - the second byte is skipped unless an interrupt is
+                // pending. With a button held the CPU halts instead, or
+                // carries on if an interrupt is pending.
+                let interrupt_pending = interrupt_request != 0;
+                if !interrupt_pending {
+                    self.pc = self.pc.wrapping_add(1);
+                }
+
+                if !bus.interconnect().button_held() {
+                    bus.interconnect().stop();
+                } else if !interrupt_pending {
+                    self.halted = true;
+                }
+            }
             0x11 => {
                 // LD DE, nn
-                let lsb = self.read_pc_byte(interconnect);
-                let msb = self.read_pc_byte(interconnect);
+                let lsb = self.read_pc_byte(bus);
+                let msb = self.read_pc_byte(bus);
 
                 self.d = msb;
                 self.e = lsb;
             }
-            0x12 => interconnect.write_byte(self.de(), self.a), // LD (DE), A
+            0x12 => bus.write_byte(self.de(), self.a), // LD (DE), A
             0x13 => {
                 // INC DE
+                bus.tick_with_address(self.de());
                 let de = self.de().wrapping_add(1);
                 self.set_de(de);
             }
@@ -242,7 +286,7 @@ impl Cpu {
                 let val = self.d;
                 self.d = self.dec(val);
             }
-            0x16 => self.d = self.read_pc_byte(interconnect), // LD D,n
+            0x16 => self.d = self.read_pc_byte(bus), // LD D,n
             0x17 => {
                 let val = self.a;
                 self.a = self.rl(val);
@@ -250,7 +294,7 @@ impl Cpu {
             }
             0x18 => {
                 // JR n - realtive jump by n
-                let n = self.read_pc_byte(interconnect);
+                let n = self.read_pc_byte(bus);
                 self.pc = self.pc.wrapping_add(n as i8 as u16);
             }
             0x19 => {
@@ -260,9 +304,10 @@ impl Cpu {
 
                 self.set_hl(val);
             }
-            0x1a => self.a = interconnect.read_byte(self.de()),
+            0x1a => self.a = bus.read_byte(self.de()),
             0x1b => {
                 // DEC DE
+                bus.tick_with_address(self.de());
                 let de = self.de().wrapping_sub(1);
                 self.set_de(de);
             }
@@ -274,7 +319,7 @@ impl Cpu {
                 let val = self.e;
                 self.e = self.dec(val);
             }
-            0x1e => self.e = self.read_pc_byte(interconnect), // LD E,n
+            0x1e => self.e = self.read_pc_byte(bus), // LD E,n
             0x1f => {
                 // RR A
                 let val = self.a;
@@ -283,7 +328,7 @@ impl Cpu {
             }
             0x20 => {
                 // JR NZ, n
-                let n = self.read_pc_byte(interconnect) as i8 as u16;
+                let n = self.read_pc_byte(bus) as i8 as u16;
 
                 if !self.f.z {
                     self.pc = self.pc.wrapping_add(n);
@@ -292,22 +337,23 @@ impl Cpu {
             }
             0x21 => {
                 // LD HL, nn
-                let lsb = self.read_pc_byte(interconnect);
-                let msb = self.read_pc_byte(interconnect);
+                let lsb = self.read_pc_byte(bus);
+                let msb = self.read_pc_byte(bus);
 
                 self.h = msb;
                 self.l = lsb;
             }
             0x22 => {
                 // LDI (HL), A
-                interconnect.write_byte(self.hl(), self.a);
+                bus.write_byte(self.hl(), self.a);
                 let val = self.hl().wrapping_add(1);
 
                 self.h = (val >> 8) as u8;
                 self.l = (val & 0xff) as u8;
             }
             0x23 => {
-                // INC DE
+                // INC HL
+                bus.tick_with_address(self.hl());
                 let hl = self.hl().wrapping_add(1);
                 self.set_hl(hl);
             }
@@ -319,7 +365,7 @@ impl Cpu {
                 let val = self.h;
                 self.h = self.dec(val);
             }
-            0x26 => self.h = self.read_pc_byte(interconnect), // LD H,n
+            0x26 => self.h = self.read_pc_byte(bus), // LD H,n
             0x27 => {
                 // DAA - Decimal adjust a
                 let mut val = self.a as u8;
@@ -351,7 +397,7 @@ impl Cpu {
             }
             0x28 => {
                 // JR Z, n
-                let n = self.read_pc_byte(interconnect) as i8 as u16;
+                let n = self.read_pc_byte(bus) as i8 as u16;
 
                 if self.f.z {
                     self.pc = self.pc.wrapping_add(n);
@@ -367,7 +413,7 @@ impl Cpu {
             }
             0x2a => {
                 // LDI A, (HL) - Load the value at address HL into A, increment HL
-                self.a = interconnect.read_byte(self.hl());
+                self.a = bus.read_byte_increment(self.hl());
                 let val = self.hl().wrapping_add(1);
 
                 self.h = (val >> 8) as u8;
@@ -375,6 +421,7 @@ impl Cpu {
             }
             0x2b => {
                 // DEC HL
+                bus.tick_with_address(self.hl());
                 let hl = self.hl().wrapping_sub(1);
                 self.set_hl(hl);
             }
@@ -386,7 +433,7 @@ impl Cpu {
                 let val = self.l;
                 self.l = self.dec(val);
             }
-            0x2e => self.l = self.read_pc_byte(interconnect), // LD L,n
+            0x2e => self.l = self.read_pc_byte(bus), // LD L,n
             0x2f => {
                 self.a = !self.a;
 
@@ -395,7 +442,7 @@ impl Cpu {
             }
             0x30 => {
                 // JMP NC, n
-                let n = self.read_pc_byte(interconnect) as i8 as u16;
+                let n = self.read_pc_byte(bus) as i8 as u16;
 
                 if !self.f.c {
                     self.pc = self.pc.wrapping_add(n);
@@ -404,8 +451,8 @@ impl Cpu {
             }
             0x31 => {
                 // LD SP, nn
-                let lsb = self.read_pc_byte(interconnect);
-                let msb = self.read_pc_byte(interconnect);
+                let lsb = self.read_pc_byte(bus);
+                let msb = self.read_pc_byte(bus);
 
                 let val = ((msb as u16) << 8) | lsb as u16;
 
@@ -413,7 +460,7 @@ impl Cpu {
             }
             0x32 => {
                 // LDD (HL), A
-                interconnect.write_byte(self.hl(), self.a);
+                bus.write_byte(self.hl(), self.a);
                 let val = self.hl().wrapping_sub(1);
 
                 self.h = (val >> 8) as u8;
@@ -421,21 +468,22 @@ impl Cpu {
             }
             0x33 => {
                 // INC SP
+                bus.tick_with_address(self.sp);
                 let sp = self.sp.wrapping_add(1);
                 self.sp = sp;
             }
             0x34 => {
-                let val = interconnect.read_byte(self.hl());
-                interconnect.write_byte(self.hl(), self.inc(val));
+                let val = bus.read_byte(self.hl());
+                bus.write_byte(self.hl(), self.inc(val));
             }
             0x35 => {
-                let val = interconnect.read_byte(self.hl());
-                interconnect.write_byte(self.hl(), self.dec(val));
+                let val = bus.read_byte(self.hl());
+                bus.write_byte(self.hl(), self.dec(val));
             }
             0x36 => {
-                let val = self.read_pc_byte(interconnect);
+                let val = self.read_pc_byte(bus);
 
-                interconnect.write_byte(self.hl(), val);
+                bus.write_byte(self.hl(), val);
             }
             0x37 => {
                 // SCF
@@ -445,7 +493,7 @@ impl Cpu {
             }
             0x38 => {
                 // JMP C, n
-                let n = self.read_pc_byte(interconnect) as i8 as u16;
+                let n = self.read_pc_byte(bus) as i8 as u16;
 
                 if self.f.c {
                     self.pc = self.pc.wrapping_add(n);
@@ -460,7 +508,7 @@ impl Cpu {
                 self.set_hl(val);
             }
             0x3a => {
-                self.a = interconnect.read_byte(self.hl());
+                self.a = bus.read_byte_increment(self.hl());
                 let val = self.hl().wrapping_sub(1);
 
                 self.h = (val >> 8) as u8;
@@ -468,6 +516,7 @@ impl Cpu {
             }
             0x3b => {
                 // DEC SP
+                bus.tick_with_address(self.sp);
                 let sp = self.sp.wrapping_sub(1);
                 self.sp = sp;
             }
@@ -481,7 +530,7 @@ impl Cpu {
             }
             0x3e => {
                 // LD A, # - Load immediate 8-bit into A
-                let val = self.read_pc_byte(interconnect);
+                let val = self.read_pc_byte(bus);
 
                 self.a = val;
             }
@@ -497,7 +546,7 @@ impl Cpu {
             0x43 => self.b = self.e, // LD B, E
             0x44 => self.b = self.h, // LD B, H
             0x45 => self.b = self.l, // LD B, L
-            0x46 => self.b = interconnect.read_byte(self.hl()), // LD B, (HL)
+            0x46 => self.b = bus.read_byte(self.hl()), // LD B, (HL)
             0x47 => self.b = self.a, // LD B, A
             0x48 => self.c = self.b, // LD C, B
             0x49 => {} // LD C, C
@@ -505,7 +554,7 @@ impl Cpu {
             0x4b => self.c = self.e, // LD C, E
             0x4c => self.c = self.h, // LD C, H
             0x4d => self.c = self.l, // LD C, L
-            0x4e => self.c = interconnect.read_byte(self.hl()), // LD C, (HL)
+            0x4e => self.c = bus.read_byte(self.hl()), // LD C, (HL)
             0x4f => self.c = self.a, // LD C, A
             0x50 => self.d = self.b, // LD D, B
             0x51 => self.d = self.c, // LD D, C
@@ -513,7 +562,7 @@ impl Cpu {
             0x53 => self.d = self.e, // LD D, E
             0x54 => self.d = self.h, // LD D, H
             0x55 => self.d = self.l, // LD D, L
-            0x56 => self.d = interconnect.read_byte(self.hl()), // LD D, (HL)
+            0x56 => self.d = bus.read_byte(self.hl()), // LD D, (HL)
             0x57 => self.d = self.a, // LD D, A
             0x58 => self.e = self.b, // LD E, B
             0x59 => self.e = self.c, // LD E, C
@@ -521,7 +570,7 @@ impl Cpu {
             0x5b => {} // LD E, E
             0x5c => self.e = self.h, // LD E, H
             0x5d => self.e = self.l, // LD E, L
-            0x5e => self.e = interconnect.read_byte(self.hl()), // LD E, (HL)
+            0x5e => self.e = bus.read_byte(self.hl()), // LD E, (HL)
             0x5f => self.e = self.a, // LD E, A
             0x60 => self.h = self.b, // LD H, B
             0x61 => self.h = self.c, // LD H, C
@@ -529,7 +578,7 @@ impl Cpu {
             0x63 => self.h = self.e, // LD H, E
             0x64 => {} // LD H, H
             0x65 => self.h = self.l, // LD H, L
-            0x66 => self.h = interconnect.read_byte(self.hl()), // LD H, (HL)
+            0x66 => self.h = bus.read_byte(self.hl()), // LD H, (HL)
             0x67 => self.h = self.a, // LD H, A
             0x68 => self.l = self.b, // LD L, B
             0x69 => self.l = self.c, // LD L, C
@@ -538,22 +587,30 @@ impl Cpu {
             0x6c => self.l = self.h, // LD L, H
             0x6f => self.l = self.a, // LD L, A
             0x6d => {} // LD L, L
-            0x6e => self.l = interconnect.read_byte(self.hl()), // LD L, (HL)
-            0x70 => interconnect.write_byte(self.hl(), self.b), // LD (HL), B
-            0x71 => interconnect.write_byte(self.hl(), self.c), // LD (HL), C
-            0x72 => interconnect.write_byte(self.hl(), self.d), // LD (HL), D
-            0x73 => interconnect.write_byte(self.hl(), self.e), // LD (HL), E
-            0x74 => interconnect.write_byte(self.hl(), self.h), // LD (HL), H
-            0x75 => interconnect.write_byte(self.hl(), self.l), // LD (HL), L
+            0x6e => self.l = bus.read_byte(self.hl()), // LD L, (HL)
+            0x70 => bus.write_byte(self.hl(), self.b), // LD (HL), B
+            0x71 => bus.write_byte(self.hl(), self.c), // LD (HL), C
+            0x72 => bus.write_byte(self.hl(), self.d), // LD (HL), D
+            0x73 => bus.write_byte(self.hl(), self.e), // LD (HL), E
+            0x74 => bus.write_byte(self.hl(), self.h), // LD (HL), H
+            0x75 => bus.write_byte(self.hl(), self.l), // LD (HL), L
             0x76 => {
-                // HALT
+                // HALT - with interrupts disabled and one already pending
+                // the CPU doesn't halt, and PC isn't incremented after the
+                // next opcode is fetched. If EI came just before, the
+                // interrupt is dispatched straight away instead and returns
+                // to the HALT.
                 if !self.interrupts_enabled && interrupt_request != 0 {
-                    self.halted = -1;
+                    if self.instructions_to_ei > 0 {
+                        self.pc = self.pc.wrapping_sub(1);
+                    } else {
+                        self.halt_bug = true;
+                    }
                 } else {
-                    self.halted = 1;
+                    self.halted = true;
                 }
             }
-            0x77 => interconnect.write_byte(self.hl(), self.a), // LD (HL), A
+            0x77 => bus.write_byte(self.hl(), self.a), // LD (HL), A
             0x78 => self.a = self.b, // LD A, B
             0x79 => self.a = self.c, // LD A, C
             0x7a => self.a = self.d, // LD A, D
@@ -563,7 +620,7 @@ impl Cpu {
             0x7e => {
                 // LD A, (HL)
                 let addr = self.hl();
-                self.a = interconnect.read_byte(addr);
+                self.a = bus.read_byte(addr);
             }
             0x7f => {} // LD A, A
             0x80 => {
@@ -598,7 +655,7 @@ impl Cpu {
             }
             0x86 => {
                 // ADD A, (HL)
-                let val = interconnect.read_byte(self.hl());
+                let val = bus.read_byte(self.hl());
                 self.a = self.addc(val, false);
             }
             0x87 => {
@@ -644,7 +701,7 @@ impl Cpu {
             }
             0x8e => {
                 // ADDC A, (HL)
-                let val = interconnect.read_byte(self.hl());
+                let val = bus.read_byte(self.hl());
                 let carry = self.f.c;
                 self.a = self.addc(val, carry);
             }
@@ -686,7 +743,7 @@ impl Cpu {
             }
             0x96 => {
                 // SUB A, (HL)
-                let val = interconnect.read_byte(self.hl());
+                let val = bus.read_byte(self.hl());
                 self.a = self.subc(val, false);
             }
             0x97 => {
@@ -732,7 +789,7 @@ impl Cpu {
             }
             0x9e => {
                 // SUBC A, (HL)
-                let val = interconnect.read_byte(self.hl());
+                let val = bus.read_byte(self.hl());
                 let carry = self.f.c;
                 self.a = self.subc(val, carry);
             }
@@ -767,7 +824,7 @@ impl Cpu {
                 self.a = self.and(val);
             }
             0xa6 => {
-                let val = interconnect.read_byte(self.hl());
+                let val = bus.read_byte(self.hl());
                 self.a = self.and(val);
             }
             0xa7 => {
@@ -799,7 +856,7 @@ impl Cpu {
                 self.a = self.xor(val);
             }
             0xae => {
-                let val = interconnect.read_byte(self.hl());
+                let val = bus.read_byte(self.hl());
                 self.a = self.xor(val);
             }
             0xaf => {
@@ -831,7 +888,7 @@ impl Cpu {
                 self.a = self.or(val);
             }
             0xb6 => {
-                let val = interconnect.read_byte(self.hl());
+                let val = bus.read_byte(self.hl());
                 self.a = self.or(val);
             }
             0xb7 => {
@@ -863,7 +920,7 @@ impl Cpu {
                 self.subc(val, false);
             }
             0xbe => {
-                let val = interconnect.read_byte(self.hl());
+                let val = bus.read_byte(self.hl());
                 self.subc(val, false);
             }
             0xbf => {
@@ -873,22 +930,20 @@ impl Cpu {
             0xc0 => {
                 // RET NZ - return if NZ
                 if !self.f.z {
-                    self.ret(interconnect);
+                    bus.tick(); // Checking the condition takes a cycle
+                    self.ret(bus);
                     cycle_count += 12;
                 }
             }
             0xc1 => {
                 // POP BC
-                let c = self.pop_byte(interconnect);
-                let b = self.pop_byte(interconnect);
-
-                self.b = b;
-                self.c = c;
+                let val = self.pop_halfword(bus);
+                self.set_bc(val);
             }
             0xc2 => {
                 // JP NZ, nn - Jump to address nn if NZ
-                let lsb = self.read_pc_byte(interconnect);
-                let msb = self.read_pc_byte(interconnect);
+                let lsb = self.read_pc_byte(bus);
+                let msb = self.read_pc_byte(bus);
 
                 if !self.f.z {
                     self.pc = ((msb as u16) << 8) | lsb as u16;
@@ -897,47 +952,48 @@ impl Cpu {
             }
             0xc3 => {
                 // JP nn - Jump to address nn
-                let lsb = self.read_pc_byte(interconnect);
-                let msb = self.read_pc_byte(interconnect);
+                let lsb = self.read_pc_byte(bus);
+                let msb = self.read_pc_byte(bus);
 
                 self.pc = ((msb as u16) << 8) | lsb as u16;
             }
             0xc4 => {
                 // CALL NZ, nn
-                let addr = self.read_pc_halfword(interconnect);
+                let addr = self.read_pc_halfword(bus);
 
                 if !self.f.z {
-                    self.call(interconnect, addr);
+                    self.call(bus, addr);
                     cycle_count += 12;
                 }
             }
             0xc5 => {
                 // PUSH BC
                 let halfword = self.bc();
-                self.push_halfword(interconnect, halfword);
+                self.push_halfword(bus, halfword);
             }
             0xc6 => {
-                let n = self.read_pc_byte(interconnect);
+                let n = self.read_pc_byte(bus);
                 self.a = self.addc(n, false);
             }
             0xc7 => {
-                self.call(interconnect, 0x0000);
+                self.call(bus, 0x0000);
             }
             0xc8 => {
                 // RET Z - return if Z flag is set
                 if self.f.z {
-                    self.ret(interconnect);
+                    bus.tick(); // Checking the condition takes a cycle
+                    self.ret(bus);
                     cycle_count += 12;
                 }
             }
             0xc9 => {
                 // RET - pop return address and jump there
-                self.ret(interconnect);
+                self.ret(bus);
             }
             0xca => {
                 // JP Z, nn - Jump to address nn if Z
-                let lsb = self.read_pc_byte(interconnect);
-                let msb = self.read_pc_byte(interconnect);
+                let lsb = self.read_pc_byte(bus);
+                let msb = self.read_pc_byte(bus);
 
                 if self.f.z {
                     self.pc = ((msb as u16) << 8) | lsb as u16;
@@ -946,7 +1002,7 @@ impl Cpu {
             }
             0xcb => {
                 // Extended instructions
-                let sub_instr = self.read_pc_byte(interconnect);
+                let sub_instr = self.read_pc_byte(bus);
                 match sub_instr {
                     0x00 => {
                         let val = self.b;
@@ -973,8 +1029,8 @@ impl Cpu {
                         self.l = self.rlc(val);
                     }
                     0x06 => {
-                        let val = interconnect.read_byte(self.hl());
-                        interconnect.write_byte(self.hl(), self.rlc(val));
+                        let val = bus.read_byte(self.hl());
+                        bus.write_byte(self.hl(), self.rlc(val));
                     }
                     0x07 => {
                         let val = self.a;
@@ -1005,8 +1061,8 @@ impl Cpu {
                         self.l = self.rrc(val);
                     }
                     0x0e => {
-                        let val = interconnect.read_byte(self.hl());
-                        interconnect.write_byte(self.hl(), self.rrc(val));
+                        let val = bus.read_byte(self.hl());
+                        bus.write_byte(self.hl(), self.rrc(val));
                     }
                     0x0f => {
                         let val = self.a;
@@ -1037,8 +1093,8 @@ impl Cpu {
                         self.l = self.rl(val);
                     }
                     0x16 => {
-                        let val = interconnect.read_byte(self.hl());
-                        interconnect.write_byte(self.hl(), self.rl(val));
+                        let val = bus.read_byte(self.hl());
+                        bus.write_byte(self.hl(), self.rl(val));
                     }
                     0x17 => {
                         let val = self.a;
@@ -1069,8 +1125,8 @@ impl Cpu {
                         self.l = self.rr(val);
                     }
                     0x1e => {
-                        let val = interconnect.read_byte(self.hl());
-                        interconnect.write_byte(self.hl(), self.rr(val));
+                        let val = bus.read_byte(self.hl());
+                        bus.write_byte(self.hl(), self.rr(val));
                     }
                     0x1f => {
                         let val = self.a;
@@ -1101,8 +1157,8 @@ impl Cpu {
                         self.l = self.sla(val);
                     }
                     0x26 => {
-                        let val = interconnect.read_byte(self.hl());
-                        interconnect.write_byte(self.hl(), self.sla(val));
+                        let val = bus.read_byte(self.hl());
+                        bus.write_byte(self.hl(), self.sla(val));
                     }
                     0x27 => {
                         let val = self.a;
@@ -1133,8 +1189,8 @@ impl Cpu {
                         self.l = self.sra(val);
                     }
                     0x2e => {
-                        let val = interconnect.read_byte(self.hl());
-                        interconnect.write_byte(self.hl(), self.sra(val));
+                        let val = bus.read_byte(self.hl());
+                        bus.write_byte(self.hl(), self.sra(val));
                     }
                     0x2f => {
                         let val = self.a;
@@ -1172,9 +1228,9 @@ impl Cpu {
                     }
                     0x36 => {
                         // SWAP (HL)
-                        let val = interconnect.read_byte(self.hl());
+                        let val = bus.read_byte(self.hl());
                         let res = self.swap(val);
-                        interconnect.write_byte(self.hl(), res);
+                        bus.write_byte(self.hl(), res);
                     }
                     0x37 => {
                         // SWAP A
@@ -1213,8 +1269,8 @@ impl Cpu {
                     }
                     0x3e => {
                         // SRL (HL)
-                        let val = interconnect.read_byte(self.hl());
-                        interconnect.write_byte(self.hl(), self.srl(val));
+                        let val = bus.read_byte(self.hl());
+                        bus.write_byte(self.hl(), self.srl(val));
                     }
                     0x3f => {
                         // SRL A
@@ -1253,7 +1309,7 @@ impl Cpu {
                     }
                     0x46 => {
                         // BIT (HL), 0
-                        let val = interconnect.read_byte(self.hl());
+                        let val = bus.read_byte(self.hl());
                         self.bit(val, 0);
                     }
                     0x47 => {
@@ -1293,7 +1349,7 @@ impl Cpu {
                     }
                     0x4e => {
                         // BIT (HL), 1
-                        let val = interconnect.read_byte(self.hl());
+                        let val = bus.read_byte(self.hl());
                         self.bit(val, 1);
                     }
                     0x4f => {
@@ -1333,7 +1389,7 @@ impl Cpu {
                     }
                     0x56 => {
                         // BIT (HL), 2
-                        let val = interconnect.read_byte(self.hl());
+                        let val = bus.read_byte(self.hl());
                         self.bit(val, 2);
                     }
                     0x57 => {
@@ -1373,7 +1429,7 @@ impl Cpu {
                     }
                     0x5e => {
                         // BIT (HL), 3
-                        let val = interconnect.read_byte(self.hl());
+                        let val = bus.read_byte(self.hl());
                         self.bit(val, 3);
                     }
                     0x5f => {
@@ -1413,7 +1469,7 @@ impl Cpu {
                     }
                     0x66 => {
                         // BIT (HL), 4
-                        let val = interconnect.read_byte(self.hl());
+                        let val = bus.read_byte(self.hl());
                         self.bit(val, 4);
                     }
                     0x67 => {
@@ -1453,7 +1509,7 @@ impl Cpu {
                     }
                     0x6e => {
                         // BIT (HL), 5
-                        let val = interconnect.read_byte(self.hl());
+                        let val = bus.read_byte(self.hl());
                         self.bit(val, 5);
                     }
                     0x6f => {
@@ -1493,7 +1549,7 @@ impl Cpu {
                     }
                     0x76 => {
                         // BIT (HL), 6
-                        let val = interconnect.read_byte(self.hl());
+                        let val = bus.read_byte(self.hl());
                         self.bit(val, 6);
                     }
                     0x77 => {
@@ -1533,7 +1589,7 @@ impl Cpu {
                     }
                     0x7e => {
                         // BIT (HL), 7
-                        let val = interconnect.read_byte(self.hl());
+                        let val = bus.read_byte(self.hl());
                         self.bit(val, 7);
                     }
                     0x7f => {
@@ -1573,8 +1629,8 @@ impl Cpu {
                     }
                     0x86 => {
                         // RES (HL), 0
-                        let val = interconnect.read_byte(self.hl());
-                        interconnect.write_byte(self.hl(), self.res(val, 0));
+                        let val = bus.read_byte(self.hl());
+                        bus.write_byte(self.hl(), self.res(val, 0));
                     }
                     0x87 => {
                         // RES A, 0
@@ -1613,8 +1669,8 @@ impl Cpu {
                     }
                     0x8e => {
                         // RES (HL), 1
-                        let val = interconnect.read_byte(self.hl());
-                        interconnect.write_byte(self.hl(), self.res(val, 1));
+                        let val = bus.read_byte(self.hl());
+                        bus.write_byte(self.hl(), self.res(val, 1));
                     }
                     0x8f => {
                         // RES A, 1
@@ -1653,8 +1709,8 @@ impl Cpu {
                     }
                     0x96 => {
                         // RES (HL), 2
-                        let val = interconnect.read_byte(self.hl());
-                        interconnect.write_byte(self.hl(), self.res(val, 2));
+                        let val = bus.read_byte(self.hl());
+                        bus.write_byte(self.hl(), self.res(val, 2));
                     }
                     0x97 => {
                         // RES A, 2
@@ -1693,8 +1749,8 @@ impl Cpu {
                     }
                     0x9e => {
                         // RES (HL), 3
-                        let val = interconnect.read_byte(self.hl());
-                        interconnect.write_byte(self.hl(), self.res(val, 3));
+                        let val = bus.read_byte(self.hl());
+                        bus.write_byte(self.hl(), self.res(val, 3));
                     }
                     0x9f => {
                         // RES A, 3
@@ -1733,8 +1789,8 @@ impl Cpu {
                     }
                     0xa6 => {
                         // RES (HL), 4
-                        let val = interconnect.read_byte(self.hl());
-                        interconnect.write_byte(self.hl(), self.res(val, 4));
+                        let val = bus.read_byte(self.hl());
+                        bus.write_byte(self.hl(), self.res(val, 4));
                     }
                     0xa7 => {
                         // RES A, 4
@@ -1773,8 +1829,8 @@ impl Cpu {
                     }
                     0xae => {
                         // RES (HL), 5
-                        let val = interconnect.read_byte(self.hl());
-                        interconnect.write_byte(self.hl(), self.res(val, 5));
+                        let val = bus.read_byte(self.hl());
+                        bus.write_byte(self.hl(), self.res(val, 5));
                     }
                     0xaf => {
                         // RES A, 5
@@ -1813,8 +1869,8 @@ impl Cpu {
                     }
                     0xb6 => {
                         // RES (HL), 6
-                        let val = interconnect.read_byte(self.hl());
-                        interconnect.write_byte(self.hl(), self.res(val, 6));
+                        let val = bus.read_byte(self.hl());
+                        bus.write_byte(self.hl(), self.res(val, 6));
                     }
                     0xb7 => {
                         // RES A, 6
@@ -1853,8 +1909,8 @@ impl Cpu {
                     }
                     0xbe => {
                         // RES (HL), 7
-                        let val = interconnect.read_byte(self.hl());
-                        interconnect.write_byte(self.hl(), self.res(val, 7));
+                        let val = bus.read_byte(self.hl());
+                        bus.write_byte(self.hl(), self.res(val, 7));
                     }
                     0xbf => {
                         // RES A, 7
@@ -1893,8 +1949,8 @@ impl Cpu {
                     }
                     0xc6 => {
                         // SET (HL), 0
-                        let val = interconnect.read_byte(self.hl());
-                        interconnect.write_byte(self.hl(), self.set(val, 0));
+                        let val = bus.read_byte(self.hl());
+                        bus.write_byte(self.hl(), self.set(val, 0));
                     }
                     0xc7 => {
                         // SET A, 0
@@ -1933,8 +1989,8 @@ impl Cpu {
                     }
                     0xce => {
                         // SET (HL), 1
-                        let val = interconnect.read_byte(self.hl());
-                        interconnect.write_byte(self.hl(), self.set(val, 1));
+                        let val = bus.read_byte(self.hl());
+                        bus.write_byte(self.hl(), self.set(val, 1));
                     }
                     0xcf => {
                         // SET A, 1
@@ -1973,8 +2029,8 @@ impl Cpu {
                     }
                     0xd6 => {
                         // SET (HL), 2
-                        let val = interconnect.read_byte(self.hl());
-                        interconnect.write_byte(self.hl(), self.set(val, 2));
+                        let val = bus.read_byte(self.hl());
+                        bus.write_byte(self.hl(), self.set(val, 2));
                     }
                     0xd7 => {
                         // SET A, 2
@@ -2013,8 +2069,8 @@ impl Cpu {
                     }
                     0xde => {
                         // SET (HL), 3
-                        let val = interconnect.read_byte(self.hl());
-                        interconnect.write_byte(self.hl(), self.set(val, 3));
+                        let val = bus.read_byte(self.hl());
+                        bus.write_byte(self.hl(), self.set(val, 3));
                     }
                     0xdf => {
                         // SET A, 3
@@ -2053,8 +2109,8 @@ impl Cpu {
                     }
                     0xe6 => {
                         // SET (HL), 4
-                        let val = interconnect.read_byte(self.hl());
-                        interconnect.write_byte(self.hl(), self.set(val, 4));
+                        let val = bus.read_byte(self.hl());
+                        bus.write_byte(self.hl(), self.set(val, 4));
                     }
                     0xe7 => {
                         // SET A, 4
@@ -2093,8 +2149,8 @@ impl Cpu {
                     }
                     0xee => {
                         // SET (HL), 5
-                        let val = interconnect.read_byte(self.hl());
-                        interconnect.write_byte(self.hl(), self.set(val, 5));
+                        let val = bus.read_byte(self.hl());
+                        bus.write_byte(self.hl(), self.set(val, 5));
                     }
                     0xef => {
                         // SET A, 5
@@ -2133,8 +2189,8 @@ impl Cpu {
                     }
                     0xf6 => {
                         // SET (HL), 6
-                        let val = interconnect.read_byte(self.hl());
-                        interconnect.write_byte(self.hl(), self.set(val, 6));
+                        let val = bus.read_byte(self.hl());
+                        bus.write_byte(self.hl(), self.set(val, 6));
                     }
                     0xf7 => {
                         // SET A, 6
@@ -2173,8 +2229,8 @@ impl Cpu {
                     }
                     0xfe => {
                         // SET (HL), 7
-                        let val = interconnect.read_byte(self.hl());
-                        interconnect.write_byte(self.hl(), self.set(val, 7));
+                        let val = bus.read_byte(self.hl());
+                        bus.write_byte(self.hl(), self.set(val, 7));
                     }
                     0xff => {
                         // SET A, 7
@@ -2187,49 +2243,47 @@ impl Cpu {
             }
             0xcc => {
                 // CALL Z, nn - Call function at nn if zero flag is set
-                let addr = self.read_pc_halfword(interconnect);
+                let addr = self.read_pc_halfword(bus);
 
                 if self.f.z {
-                    self.call(interconnect, addr);
+                    self.call(bus, addr);
                     cycle_count += 12;
                 }
             }
             0xcd => {
                 // CALL nn - Call function at nn
-                let addr = self.read_pc_halfword(interconnect);
+                let addr = self.read_pc_halfword(bus);
 
-                self.call(interconnect, addr);
+                self.call(bus, addr);
             }
             0xce => {
                 // ADDC A, n
-                let val = self.read_pc_byte(interconnect);
+                let val = self.read_pc_byte(bus);
                 let carry = self.f.c;
 
                 self.a = self.addc(val, carry);
             }
             0xcf => {
                 // RST 08
-                self.call(interconnect, 0x0008);
+                self.call(bus, 0x0008);
             }
             0xd0 => {
                 // RET NC
                 if !self.f.c {
-                    self.ret(interconnect);
+                    bus.tick(); // Checking the condition takes a cycle
+                    self.ret(bus);
                     cycle_count += 12;
                 }
             }
             0xd1 => {
                 // POP DE
-                let e = self.pop_byte(interconnect);
-                let d = self.pop_byte(interconnect);
-
-                self.d = d;
-                self.e = e;
+                let val = self.pop_halfword(bus);
+                self.set_de(val);
             }
             0xd2 => {
                 // JP NC, nn - Jump to address nn if NC
-                let lsb = self.read_pc_byte(interconnect);
-                let msb = self.read_pc_byte(interconnect);
+                let lsb = self.read_pc_byte(bus);
+                let msb = self.read_pc_byte(bus);
 
                 if !self.f.c {
                     self.pc = ((msb as u16) << 8) | lsb as u16;
@@ -2238,43 +2292,44 @@ impl Cpu {
             }
             0xd4 => {
                 // CALL NC, nn - Call function at nn if carry flag is not set
-                let addr = self.read_pc_halfword(interconnect);
+                let addr = self.read_pc_halfword(bus);
 
                 if !self.f.c {
-                    self.call(interconnect, addr);
+                    self.call(bus, addr);
                     cycle_count += 12;
                 }
             }
             0xd5 => {
                 // PUSH DE
                 let halfword = self.de();
-                self.push_halfword(interconnect, halfword);
+                self.push_halfword(bus, halfword);
             }
             0xd6 => {
                 // SUB A, n
-                let n = self.read_pc_byte(interconnect);
+                let n = self.read_pc_byte(bus);
                 self.a = self.subc(n, false);
             }
             0xd7 => {
                 // RST 10
-                self.call(interconnect, 0x0010);
+                self.call(bus, 0x0010);
             }
             0xd8 => {
                 // RET C - return if the C flag is set
                 if self.f.c {
-                    self.ret(interconnect);
+                    bus.tick(); // Checking the condition takes a cycle
+                    self.ret(bus);
                     cycle_count += 12;
                 }
             }
             0xd9 => {
                 // RETI - return and enable interrupts
-                self.ret(interconnect);
+                self.ret(bus);
                 self.interrupts_enabled = true;
             }
             0xda => {
                 // JP C, nn - Jump to address nn if C
-                let lsb = self.read_pc_byte(interconnect);
-                let msb = self.read_pc_byte(interconnect);
+                let lsb = self.read_pc_byte(bus);
+                let msb = self.read_pc_byte(bus);
 
                 if self.f.c {
                     self.pc = ((msb as u16) << 8) | lsb as u16;
@@ -2283,62 +2338,56 @@ impl Cpu {
             }
             0xdc => {
                 // CALL C, nn - Call function at nn if carry flag is set
-                let addr = self.read_pc_halfword(interconnect);
+                let addr = self.read_pc_halfword(bus);
 
                 if self.f.c {
-                    self.call(interconnect, addr);
+                    self.call(bus, addr);
                     cycle_count += 12;
                 }
             }
             0xde => {
                 // SUBC A, n
-                let n = self.read_pc_byte(interconnect);
+                let n = self.read_pc_byte(bus);
                 let carry = self.f.c;
                 self.a = self.subc(n, carry);
             }
             0xdf => {
                 // RST 18
-                self.call(interconnect, 0x0018);
+                self.call(bus, 0x0018);
             }
             0xe0 => {
                 // LDH (n), A - Store A in memory 0xff00+n
-                let n = self.read_pc_byte(interconnect);
+                let n = self.read_pc_byte(bus);
                 let addr = 0xff00 + (n as u16);
 
-                interconnect.write_byte(addr, self.a);
+                bus.write_byte(addr, self.a);
             }
             0xe1 => {
                 // POP HL
-                let l = self.pop_byte(interconnect);
-                let h = self.pop_byte(interconnect);
-
-                self.h = h;
-                self.l = l;
+                let val = self.pop_halfword(bus);
+                self.set_hl(val);
             }
             0xe2 => {
                 // LD (C), A
                 let addr = 0xff00 + (self.c as u16);
-                interconnect.write_byte(addr, self.a);
+                bus.write_byte(addr, self.a);
             }
             0xe5 => {
                 // PUSH HL
-                let h = self.h;
-                let l = self.l;
-
-                self.push_byte(interconnect, h);
-                self.push_byte(interconnect, l);
+                let halfword = self.hl();
+                self.push_halfword(bus, halfword);
             }
             0xe6 => {
-                let val = self.read_pc_byte(interconnect);
+                let val = self.read_pc_byte(bus);
                 self.a = self.and(val);
             }
             0xe7 => {
                 // RST 20
-                self.call(interconnect, 0x0020);
+                self.call(bus, 0x0020);
             }
             0xe8 => {
                 // ADD SP, n - Add 8 bit immediate to SP
-                let n = self.read_pc_byte(interconnect) as i8 as u16;
+                let n = self.read_pc_byte(bus) as i8 as u16;
                 let sp = self.sp;
 
                 let res = sp.wrapping_add(n);
@@ -2355,58 +2404,55 @@ impl Cpu {
             }
             0xea => {
                 // LD nn, A - Store A to immediate address
-                let addr = self.read_pc_halfword(interconnect);
-                interconnect.write_byte(addr, self.a);
+                let addr = self.read_pc_halfword(bus);
+                bus.write_byte(addr, self.a);
             }
             0xee => {
-                let val = self.read_pc_byte(interconnect);
+                let val = self.read_pc_byte(bus);
                 self.a = self.xor(val);
             }
             0xef => {
                 // RST 28
-                self.call(interconnect, 0x0028);
+                self.call(bus, 0x0028);
             }
             0xf0 => {
-                let n = self.read_pc_byte(interconnect);
+                let n = self.read_pc_byte(bus);
                 let addr = 0xff00 + (n as u16);
 
-                self.a = interconnect.read_byte(addr);
+                self.a = bus.read_byte(addr);
             }
             0xf1 => {
                 // POP AF
-                let f = self.pop_byte(interconnect);
-                let a = self.pop_byte(interconnect);
+                let val = self.pop_halfword(bus);
 
-                self.a = a;
-                self.f = f.into();
+                self.a = (val >> 8) as u8;
+                self.f = (val as u8).into();
             }
             0xf2 => {
                 let addr = 0xff00 + (self.c as u16);
-                self.a = interconnect.read_byte(addr);
+                self.a = bus.read_byte(addr);
             }
             0xf3 => {
-                // DI -Disable interrupts after the next instruction is executed
-                self.instructions_to_di = 1;
+                // DI - Disable interrupts, this also cancels a pending EI
+                self.interrupts_enabled = false;
+                self.instructions_to_ei = 0;
             }
             0xf5 => {
                 // PUSH AF
-                let a = self.a;
-                let f = self.f;
-
-                self.push_byte(interconnect, a);
-                self.push_byte(interconnect, f.into());
+                let halfword = self.af();
+                self.push_halfword(bus, halfword);
             }
             0xf6 => {
-                let val = self.read_pc_byte(interconnect);
+                let val = self.read_pc_byte(bus);
                 self.a = self.or(val);
             }
             0xf7 => {
                 // RST 30
-                self.call(interconnect, 0x0030);
+                self.call(bus, 0x0030);
             }
             0xf8 => {
                 // LD HL, SP+n
-                let n = self.read_pc_byte(interconnect) as i8 as u16;
+                let n = self.read_pc_byte(bus) as i8 as u16;
                 let addr = self.sp.wrapping_add(n);
 
                 self.f.z = false;
@@ -2417,107 +2463,136 @@ impl Cpu {
                 self.h = (addr >> 8) as u8;
                 self.l = (addr & 0xff) as u8;
             }
-            0xf9 => self.sp = self.hl(), // LD SP, HL
+            0xf9 => {
+                // LD SP, HL
+                bus.tick_with_address(self.hl());
+                self.sp = self.hl();
+            }
             0xfa => {
-                let addr = self.read_pc_halfword(interconnect);
-                self.a = interconnect.read_byte(addr);
+                let addr = self.read_pc_halfword(bus);
+                self.a = bus.read_byte(addr);
             }
             0xfb => {
-                // EI
-                self.interrupts_enabled = true;
+                // EI - Enable interrupts after the next instruction is
+                // executed. EI while one is already pending doesn't delay
+                // it further.
+                if !self.interrupts_enabled && self.instructions_to_ei == 0 {
+                    self.instructions_to_ei = 2;
+                }
             }
             0xfe => {
-                let val = self.read_pc_byte(interconnect);
+                let val = self.read_pc_byte(bus);
                 self.subc(val, false);
             }
             0xff => {
                 // RST 38
-                self.call(interconnect, 0x0038);
+                self.call(bus, 0x0038);
+            }
+            0xd3 | 0xdb | 0xdd | 0xe3 | 0xe4 | 0xeb | 0xec | 0xed | 0xf4 | 0xfc | 0xfd => {
+                // Illegal opcodes lock the CPU up
+                self.locked_up = true;
             }
-            _ => panic!("Unrecognized instruction {:02x} at {:04x}", instr, old_pc),
         }
 
-        if self.instructions_to_di > 0 {
-            self.instructions_to_di -= 1;
-            if self.instructions_to_di == 0 {
-                self.disable_interrupts();
+        if self.instructions_to_ei > 0 {
+            self.instructions_to_ei -= 1;
+            if self.instructions_to_ei == 0 {
+                self.interrupts_enabled = true;
             }
         }
 
-        self.total_cycles += cycle_count as u32;
-        cycle_count
+        // Run the cycles at the end of the instruction that don't access
+        // memory
+        while bus.cycles() - start_cycles < cycle_count {
+            bus.tick();
+        }
     }
 
-    fn read_pc_byte(&mut self, interconnect: &Interconnect) -> u8 {
-        let val = interconnect.read_byte(self.pc);
-        if self.halted == -1 {
-            self.halted = 0;
+    fn read_pc_byte(&mut self, bus: &mut Bus) -> u8 {
+        let val = bus.read_byte(self.pc);
+        if self.halt_bug {
+            self.halt_bug = false;
         } else {
             self.pc += 1;
         }
         val
     }
 
-    fn read_pc_halfword(&mut self, interconnect: &Interconnect) -> u16 {
-        let lsb = self.read_pc_byte(interconnect);
-        let msb = self.read_pc_byte(interconnect);
+    fn read_pc_halfword(&mut self, bus: &mut Bus) -> u16 {
+        let lsb = self.read_pc_byte(bus);
+        let msb = self.read_pc_byte(bus);
 
         ((msb as u16) << 8) | (lsb as u16)
     }
 
-    fn disable_interrupts(&mut self) {
-        self.interrupts_enabled = false;
+    // Interrupts that are both requested and enabled
+    fn interrupt_request(&self, bus: &mut Bus) -> u8 {
+        let interconnect = bus.interconnect();
+        interconnect.if_register & interconnect.ie_register & 0x1f
     }
 
-    fn handle_interrupt(&mut self, interconnect: &mut Interconnect, int_f: u8, int_e: u8) {
-        let interrupt_vector = int_f & int_e;
-        let interrupt = interrupt_vector.trailing_zeros();
-
-        let addr = match interrupt {
-            0 => 0x0040,
-            1 => 0x0048,
-            2 => 0x0050,
-            3 => 0x0058,
-            4 => 0x0060,
-            _ => unreachable!(),
-        };
+    // Dispatching an interrupt takes 5 M-cycles, two internal ones, two to
+    // push PC and one to jump. The interrupt is only picked between pushing
+    // the high and low bytes of PC, so if pushing the high byte overwrites IE
+    // and leaves nothing to dispatch, the CPU jumps to 0x0000 instead.
+    fn dispatch_interrupt(&mut self, bus: &mut Bus) {
+        self.interrupts_enabled = false;
+        self.instructions_to_ei = 0;
+
+        bus.tick();
+        bus.tick_with_address(self.sp);
+        let pc = self.pc;
+        self.push_byte(bus, (pc >> 8) as u8);
 
-        interconnect.write_byte(0xff0f, int_f & !(1 << interrupt));
+        let interrupt_request = self.interrupt_request(bus);
+        self.push_byte(bus, pc as u8);
 
-        self.call(interconnect, addr);
-        self.halted = 0;
+        self.pc = if interrupt_request == 0 {
+            0x0000
+        } else {
+            let interrupt = interrupt_request.trailing_zeros();
+            bus.interconnect().if_register &= !(1 << interrupt);
+            0x0040 + (interrupt as u16 * 8)
+        };
+        bus.tick();
     }
 
-    fn push_halfword(&mut self, interconnect: &mut Interconnect, addr: u16) {
-        self.sp -= 2;
-        interconnect.write_halfword(self.sp, addr);
+    // There's an internal cycle before the writes, the high byte is written
+    // first
+    fn push_halfword(&mut self, bus: &mut Bus, val: u16) {
+        bus.tick_with_address(self.sp);
+        self.push_byte(bus, (val >> 8) as u8);
+        self.push_byte(bus, val as u8);
     }
 
-    fn push_byte(&mut self, interconnect: &mut Interconnect, val: u8) {
-        self.sp -= 1;
-        interconnect.write_byte(self.sp, val);
+    fn push_byte(&mut self, bus: &mut Bus, val: u8) {
+        self.sp = self.sp.wrapping_sub(1);
+        bus.write_byte(self.sp, val);
     }
 
-    fn pop_halfword(&mut self, interconnect: &mut Interconnect) -> u16 {
-        let ret = interconnect.read_halfword(self.sp);
-        self.sp += 2;
-        ret
+    // SP is incremented while the first byte is read
+    fn pop_halfword(&mut self, bus: &mut Bus) -> u16 {
+        let lsb = bus.read_byte_increment(self.sp);
+        self.sp = self.sp.wrapping_add(1);
+        let msb = self.pop_byte(bus);
+
+        ((msb as u16) << 8) | (lsb as u16)
     }
 
-    fn pop_byte(&mut self, interconnect: &mut Interconnect) -> u8 {
-        let val = interconnect.read_byte(self.sp);
-        self.sp += 1;
+    fn pop_byte(&mut self, bus: &mut Bus) -> u8 {
+        let val = bus.read_byte(self.sp);
+        self.sp = self.sp.wrapping_add(1);
         val
     }
 
-    fn call(&mut self, interconnect: &mut Interconnect, addr: u16) {
+    fn call(&mut self, bus: &mut Bus, addr: u16) {
         let pc = self.pc;
-        self.push_halfword(interconnect, pc);
+        self.push_halfword(bus, pc);
         self.pc = addr;
     }
 
-    fn ret(&mut self, interconnect: &mut Interconnect) {
-        let addr = self.pop_halfword(interconnect);
+    fn ret(&mut self, bus: &mut Bus) {
+        let addr = self.pop_halfword(bus);
         self.pc = addr;
     }
 
@@ -2747,6 +2822,47 @@ impl Cpu {
         self.h = (val >> 8) as u8;
         self.l = (val & 0xff) as u8;
     }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.a);
+        state.write_u8(self.f.into());
+        state.write_u8(self.b);
+        state.write_u8(self.c);
+        state.write_u8(self.d);
+        state.write_u8(self.e);
+        state.write_u8(self.h);
+        state.write_u8(self.l);
+        state.write_u16(self.sp);
+        state.write_u16(self.pc);
+
+        state.write_u8(self.instructions_to_ei);
+        state.write_bool(self.interrupts_enabled);
+        state.write_bool(self.halted);
+        state.write_bool(self.halt_bug);
+        state.write_bool(self.locked_up);
+        state.write_u32(self.total_cycles);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.a = state.read_u8()?;
+        self.f = state.read_u8()?.into();
+        self.b = state.read_u8()?;
+        self.c = state.read_u8()?;
+        self.d = state.read_u8()?;
+        self.e = state.read_u8()?;
+        self.h = state.read_u8()?;
+        self.l = state.read_u8()?;
+        self.sp = state.read_u16()?;
+        self.pc = state.read_u16()?;
+
+        self.instructions_to_ei = state.read_u8()? % 3;
+        self.interrupts_enabled = state.read_bool()?;
+        self.halted = state.read_bool()?;
+        self.halt_bug = state.read_bool()?;
+        self.locked_up = state.read_bool()?;
+        self.total_cycles = state.read_u32()?;
+        Ok(())
+    }
 }
 
 
@@ -10,12 +10,33 @@ pub enum Key {
 
     Z,
     X,
+
+    // Held to play backwards
+    R,
+
+    // Quick save and load slots
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
 }
 
 pub trait Device {
     fn update(&mut self);
     fn set_frame_buffer(&mut self, buffer: &[u32]);
 
+    // The rate in Hz that the device wants audio samples delivered at
+    fn audio_sample_rate(&self) -> u32;
+    // Samples are interleaved left and right pairs in the range -1.0 to 1.0
+    fn queue_audio_samples(&mut self, samples: &[f32]);
+
+    // Called when the cartridge's rumble motor is switched on or off
+    fn set_rumble(&mut self, active: bool);
+
     fn key_down(&self, key: Key) -> bool;
 
     fn running(&self) -> bool;
@@ -0,0 +1,227 @@
+use std::io;
+use mem_map::*;
+use state::{StateReader, StateWriter};
+
+const TRANSFER_LENGTH: u16 = 0xa0;
+
+// OAM DMA copies 160 bytes to OAM, one per M-cycle, starting a cycle after
+// 0xff46 is written. While it runs the CPU can't use the bus the transfer is
+// reading from.
+pub struct Dma {
+    register: u8, // 0xff46 - DMA source address high byte
+
+    source: u16,
+    index: u16,
+    active: bool,
+    bus_value: u8, // Last byte read by the transfer
+
+    // A new transfer starts once the delay runs out, until then a transfer
+    // that's already running carries on
+    start_delay: u8,
+    start_source: u16,
+}
+
+impl Dma {
+    pub fn new() -> Self {
+        Dma {
+            register: 0xff,
+
+            source: 0,
+            index: 0,
+            active: false,
+            bus_value: 0xff,
+
+            start_delay: 0,
+            start_source: 0,
+        }
+    }
+
+    pub fn read_reg(&self) -> u8 {
+        self.register
+    }
+
+    pub fn write_reg(&mut self, val: u8) {
+        self.register = val;
+        self.start_delay = 1;
+        self.start_source = (val as u16) << 8;
+    }
+
+    pub fn active(&self) -> bool {
+        self.active
+    }
+
+    pub fn bus_value(&self) -> u8 {
+        self.bus_value
+    }
+
+    // Returns true if a CPU access to addr would use the same bus as the
+    // transfer, VRAM has a bus of its own
+    pub fn conflicts(&self, addr: u16) -> bool {
+        let vram = |a: u16| a >= VRAM_START && a <= VRAM_END;
+        vram(addr) == vram(self.source)
+    }
+
+    // Returns the address to read and the OAM offset to write for this cycle
+    // if a transfer is running
+    pub fn next_transfer(&self) -> Option<(u16, u16)> {
+        if !self.active {
+            return None;
+        }
+
+        // Sources above 0xdfff read from the echo of internal RAM
+        let mut addr = self.source + self.index;
+        if addr >= IRAM_ECHO_START {
+            addr -= 0x2000;
+        }
+        Some((addr, self.index))
+    }
+
+    pub fn transferred(&mut self, val: u8) {
+        self.bus_value = val;
+        self.index += 1;
+        if self.index == TRANSFER_LENGTH {
+            self.active = false;
+        }
+    }
+
+    // Called at the end of each M-cycle
+    pub fn step(&mut self) {
+        if self.start_delay > 0 {
+            self.start_delay -= 1;
+            if self.start_delay == 0 {
+                self.source = self.start_source;
+                self.index = 0;
+                self.active = true;
+            }
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.register);
+
+        state.write_u16(self.source);
+        state.write_u16(self.index);
+        state.write_bool(self.active);
+        state.write_u8(self.bus_value);
+
+        state.write_u8(self.start_delay);
+        state.write_u16(self.start_source);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.register = state.read_u8()?;
+
+        self.source = state.read_u16()?;
+        self.index = state.read_u16()? % TRANSFER_LENGTH;
+        self.active = state.read_bool()?;
+        self.bus_value = state.read_u8()?;
+
+        self.start_delay = state.read_u8()?;
+        self.start_source = state.read_u16()?;
+        Ok(())
+    }
+}
+
+pub const HDMA_BLOCK_LENGTH: u16 = 0x10;
+
+// CGB VRAM DMA copies blocks of 16 bytes into VRAM, either all at once
+// (general purpose) or one block at the start of each hblank
+pub struct Hdma {
+    source: u16, // 0xff51/0xff52 - HDMA1/HDMA2, the low 4 bits are ignored
+    destination: u16, // 0xff53/0xff54 - HDMA3/HDMA4, offset into VRAM
+    remaining: u8, // 0xff55 - HDMA5, blocks left to copy minus one
+
+    general: bool,
+    hblank: bool,
+}
+
+impl Hdma {
+    pub fn new() -> Self {
+        Hdma {
+            source: 0,
+            destination: 0,
+            remaining: 0x7f,
+
+            general: false,
+            hblank: false,
+        }
+    }
+
+    // Bit 7 of HDMA5 reads clear while an hblank transfer is running, once
+    // it's finished the register reads 0xff
+    pub fn read_reg(&self, addr: u16) -> u8 {
+        match addr {
+            0xff55 if self.hblank => self.remaining,
+            0xff55 => self.remaining | 0x80,
+            _ => 0xff,
+        }
+    }
+
+    pub fn write_reg(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xff51 => self.source = (self.source & 0x00ff) | ((val as u16) << 8),
+            0xff52 => self.source = (self.source & 0xff00) | (val & 0xf0) as u16,
+            0xff53 => self.destination = (self.destination & 0x00ff) | (((val & 0x1f) as u16) << 8),
+            0xff54 => self.destination = (self.destination & 0xff00) | (val & 0xf0) as u16,
+            0xff55 => {
+                // Writing with bit 7 clear during an hblank transfer stops it
+                if self.hblank && val & 0x80 == 0 {
+                    self.hblank = false;
+                    return;
+                }
+
+                self.remaining = val & 0x7f;
+                if val & 0x80 != 0 {
+                    self.hblank = true;
+                } else {
+                    self.general = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn general_pending(&self) -> bool {
+        self.general
+    }
+
+    pub fn hblank_active(&self) -> bool {
+        self.hblank
+    }
+
+    // Returns the source address and VRAM offset of the next block and moves
+    // on to the one after it
+    pub fn next_block(&mut self) -> (u16, u16) {
+        let block = (self.source, self.destination);
+        self.source = self.source.wrapping_add(HDMA_BLOCK_LENGTH);
+        self.destination = (self.destination + HDMA_BLOCK_LENGTH) & (VRAM_LENGTH - 1);
+
+        if self.remaining == 0 {
+            self.remaining = 0x7f;
+            self.general = false;
+            self.hblank = false;
+        } else {
+            self.remaining -= 1;
+        }
+        block
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.source);
+        state.write_u16(self.destination);
+        state.write_u8(self.remaining);
+
+        state.write_bool(self.general);
+        state.write_bool(self.hblank);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.source = state.read_u16()?;
+        self.destination = state.read_u16()? & (VRAM_LENGTH - 1);
+        self.remaining = state.read_u8()? & 0x7f;
+
+        self.general = state.read_bool()?;
+        self.hblank = state.read_bool()?;
+        Ok(())
+    }
+}
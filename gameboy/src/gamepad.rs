@@ -1,5 +1,7 @@
+use std::io;
 use device::{Device, Key};
 use interrupt::{Irq, Interrupt};
+use state::{StateReader, StateWriter};
 
 pub struct KeyPad {
     key_code: Key,
@@ -71,6 +73,16 @@ impl Gamepad {
         self.select.step(device, irq);
     }
 
+    // True while a button in a selected group is pressed, pulling one of the
+    // input lines low
+    pub fn input_low(&self) -> bool {
+        let button = self.a.pressed() || self.b.pressed() || self.select.pressed() ||
+                     self.start.pressed();
+        let direction = self.right.pressed() || self.left.pressed() || self.up.pressed() ||
+                        self.down.pressed();
+        (!self.p15 && button) || (!self.p14 && direction)
+    }
+
     pub fn read_reg(&self) -> u8 {
         let mut ret = 0xc0;
 
@@ -110,4 +122,25 @@ impl Gamepad {
         self.p15 = val & (1 << 5) != 0;
         self.p14 = val & (1 << 4) != 0;
     }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.p15);
+        state.write_bool(self.p14);
+
+        for key in &[&self.up, &self.down, &self.left, &self.right,
+                     &self.a, &self.b, &self.start, &self.select] {
+            state.write_bool(key.pressed);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.p15 = state.read_bool()?;
+        self.p14 = state.read_bool()?;
+
+        for key in &mut [&mut self.up, &mut self.down, &mut self.left, &mut self.right,
+                         &mut self.a, &mut self.b, &mut self.start, &mut self.select] {
+            key.pressed = state.read_bool()?;
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,180 @@
+use std::io;
+use std::collections::VecDeque;
+use state::{self, StateReader, StateWriter};
+
+// A pixel waiting in one of the FIFOs, the palette is applied when it's
+// shifted out to the LCD
+#[derive(Clone, Copy, Default)]
+pub struct Pixel {
+    pub colour: u8, // Colour number 0 - 3
+    pub palette: u8, // OBP0 or OBP1 on the DMG, palette 0 - 7 on the CGB
+    pub behind_bg: bool, // Sprite attribute bit 7, or the CGB BG map's
+    pub oam_index: u8, // Sprites earlier in OAM win on the CGB
+}
+
+impl Pixel {
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.colour);
+        state.write_u8(self.palette);
+        state.write_bool(self.behind_bg);
+        state.write_u8(self.oam_index);
+    }
+
+    pub fn load_state(state: &mut StateReader) -> io::Result<Pixel> {
+        Ok(Pixel {
+            colour: state.read_u8()? & 0x3,
+            palette: state.read_u8()? & 0x7,
+            behind_bg: state.read_bool()?,
+            oam_index: state.read_u8()?,
+        })
+    }
+}
+
+pub fn save_fifo(fifo: &VecDeque<Pixel>, state: &mut StateWriter) {
+    state.write_u8(fifo.len() as u8);
+    for pixel in fifo {
+        pixel.save_state(state);
+    }
+}
+
+pub fn load_fifo(fifo: &mut VecDeque<Pixel>, state: &mut StateReader) -> io::Result<()> {
+    let length = state.read_u8()?;
+    if length > 16 {
+        return Err(state::invalid_state("Pixel FIFO too long"));
+    }
+
+    fifo.clear();
+    for _ in 0..length {
+        fifo.push_back(Pixel::load_state(state)?);
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FetchStep {
+    Tile,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+impl From<u8> for FetchStep {
+    fn from(val: u8) -> Self {
+        match val & 0x3 {
+            0 => FetchStep::Tile,
+            1 => FetchStep::DataLow,
+            2 => FetchStep::DataHigh,
+            _ => FetchStep::Push,
+        }
+    }
+}
+
+impl Into<u8> for FetchStep {
+    fn into(self) -> u8 {
+        match self {
+            FetchStep::Tile => 0,
+            FetchStep::DataLow => 1,
+            FetchStep::DataHigh => 2,
+            FetchStep::Push => 3,
+        }
+    }
+}
+
+// The background fetcher reads a row of a tile over six dots, two for each
+// of the tile number and the two data bytes, then waits until the BG FIFO is
+// empty to push the eight pixels
+#[derive(Clone, Copy)]
+pub struct Fetcher {
+    pub step: FetchStep,
+    pub dot: bool, // Set on the second dot of a step, when it completes
+    pub delay: u8, // Dots left before the fetcher starts
+    pub tile_x: u8, // Tile column being fetched, relative to SCX or the window
+    pub window: bool,
+
+    pub tile_index: u8,
+    pub attributes: u8, // BG map attributes from VRAM bank 1 on the CGB
+    pub data_low: u8,
+    pub data_high: u8,
+}
+
+impl Fetcher {
+    pub fn new(delay: u8, window: bool) -> Self {
+        Fetcher {
+            step: FetchStep::Tile,
+            dot: false,
+            delay: delay,
+            tile_x: 0,
+            window: window,
+
+            tile_index: 0,
+            attributes: 0,
+            data_low: 0,
+            data_high: 0,
+        }
+    }
+
+    // Set from the last dot of fetching the tile's data
+    pub fn tile_ready(&self) -> bool {
+        self.step == FetchStep::Push || (self.step == FetchStep::DataHigh && self.dot)
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.step.into());
+        state.write_bool(self.dot);
+        state.write_u8(self.delay);
+        state.write_u8(self.tile_x);
+        state.write_bool(self.window);
+
+        state.write_u8(self.tile_index);
+        state.write_u8(self.attributes);
+        state.write_u8(self.data_low);
+        state.write_u8(self.data_high);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.step = state.read_u8()?.into();
+        self.dot = state.read_bool()?;
+        self.delay = state.read_u8()?;
+        self.tile_x = state.read_u8()?;
+        self.window = state.read_bool()?;
+
+        self.tile_index = state.read_u8()?;
+        self.attributes = state.read_u8()?;
+        self.data_low = state.read_u8()?;
+        self.data_high = state.read_u8()?;
+        Ok(())
+    }
+}
+
+// A sprite found by the OAM search for the current line
+#[derive(Clone, Copy)]
+pub struct Sprite {
+    pub y: u8,
+    pub x: u8,
+    pub tile: u8,
+    pub flags: u8,
+    pub oam_index: u8,
+    pub fetched: bool,
+}
+
+impl Sprite {
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.y);
+        state.write_u8(self.x);
+        state.write_u8(self.tile);
+        state.write_u8(self.flags);
+        state.write_u8(self.oam_index);
+        state.write_bool(self.fetched);
+    }
+
+    pub fn load_state(state: &mut StateReader) -> io::Result<Sprite> {
+        Ok(Sprite {
+            y: state.read_u8()?,
+            x: state.read_u8()?,
+            tile: state.read_u8()?,
+            flags: state.read_u8()?,
+            oam_index: state.read_u8()?,
+            fetched: state.read_bool()?,
+        })
+    }
+}
@@ -0,0 +1,1106 @@
+mod fifo;
+mod palette_ram;
+
+use std::io;
+use std::collections::VecDeque;
+use mem_map::*;
+use device::Device;
+use interrupt::{Irq, Interrupt};
+use state::{self, StateReader, StateWriter};
+use self::fifo::{FetchStep, Fetcher, Pixel, Sprite};
+use self::palette_ram::PaletteRam;
+
+const COLOUR_MAP: [u32; 4] = [0xff7e8429, 0xff527a4b, 0xff315d4b, 0xff29473e];
+// The CGB's screen is white while the LCD is off
+const CGB_BLANK_COLOUR: u32 = 0xffffffff;
+const WIDTH: usize = 160;
+const HEIGHT: usize = 144;
+// The OAM search stops after finding this many sprites on a line
+const SPRITES_PER_LINE: usize = 10;
+
+// The OAM search reads a row of two sprites each M-cycle
+const OAM_ROW_LENGTH: usize = 8;
+const OAM_ROWS: usize = OAM_LENGTH as usize / OAM_ROW_LENGTH;
+
+// Dots spent in each part of a line, the length of mode 3 varies
+const OAM_SEARCH_DOTS: u16 = 80;
+const LINE_DOTS: u16 = 456;
+// The first tile fetched on each line is thrown away
+const FIRST_FETCH_DELAY: u8 = 6;
+// Dots taken to fetch a sprite's tile data, once the BG fetcher has finished
+// the tile it's working on
+const SPRITE_FETCH_DOTS: u8 = 6;
+
+// How the CPU's access clashes with the OAM search on the DMG
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OamCorruption {
+    Write,
+    Read,
+    // A read while the incrementer changes the address register
+    ReadIncrement,
+}
+
+pub struct Gpu {
+    cgb_mode: bool,
+    colour_correction: bool,
+
+    vram: Box<[u8]>, // VRAM - mapped to 0x8000 - 0x9FFF, two banks on the CGB
+    oam: Box<[u8]>, // Obj/Sprite Attribute Table - mapped to 0xfe00 - 0xfea0
+    frame_buffer: Box<[u32]>,
+
+    lcd_control: LcdControlReg, // 0xff40 - LCDC
+    lcdc_status: LcdcStatusReg, // 0xff41 - STAT
+    scy: u8, // 0xff42 - SCY scroll background Y position
+    scx: u8, // 0xff43 - SCX scroll background X position
+    ly: u8, // 0xff44 - LY scanline being sent to LCD driver, reset on write
+    lyc: u8, // 0xff45 - LYC if equal to LY STAT.coincident is set
+    bg_palette_data: PaletteDataReg, // 0xff47 - BG & Window palette data
+    obj0_palette_data: PaletteDataReg, // 0xff48 OBJ0 palette data
+    obj1_palette_data: PaletteDataReg, // 0xff49 OBJ1 palette data
+    wy: u8, // 0xff4a - window Y position
+    wx: u8, // 0xff4b - window X position, offset from screen coords by 7
+    vram_bank: u8, // 0xff4f - VBK
+    bg_palette_ram: PaletteRam, // 0xff68/0xff69 - BCPS/BCPD
+    obj_palette_ram: PaletteRam, // 0xff6a/0xff6b - OCPS/OCPD
+
+    // The window keeps its own line counter, which only advances on lines
+    // where the window was drawn
+    window_line: u8,
+    window_y_triggered: bool, // Set once LY has matched WY this frame
+
+    // Pixel transfer state, used during mode 3
+    bg_fifo: VecDeque<Pixel>,
+    obj_fifo: VecDeque<Pixel>,
+    fetcher: Fetcher,
+    lx: u8, // Next pixel to be sent to the LCD
+    discard: u8, // Pixels still to be dropped for fine scrolling
+    window_active: bool, // Set once the window has started on this line
+    sprites: Vec<Sprite>,
+    sprite_fetch: Option<usize>, // Index into sprites being fetched
+    sprite_fetch_dots: u8,
+
+    // The STAT interrupt is requested on the rising edge of the OR of its
+    // enabled sources
+    stat_line: bool,
+    // On the DMG writing STAT briefly enables every source
+    stat_write_pending: bool,
+    // The first line after the LCD is switched on has no OAM search
+    first_line: bool,
+    // Set when the LCD has been switched off and the device hasn't been shown
+    // the blank screen yet
+    blank_pending: bool,
+    // Set when mode 3 ends on a visible line, for the HBlank HDMA
+    hblank_started: bool,
+
+    // When clear the CPU can access VRAM and OAM in every mode, which makes
+    // code that relies on accesses being dropped easy to spot. Writes that
+    // were dropped are counted for the debugger.
+    access_locking: bool,
+    dropped_vram_writes: u32,
+    dropped_oam_writes: u32,
+
+    cycles: u16, // Dot within the current line
+}
+
+impl Gpu {
+    pub fn new(cgb_mode: bool) -> Self {
+        let vram_banks = if cgb_mode { 2 } else { 1 };
+        Gpu {
+            cgb_mode: cgb_mode,
+            colour_correction: false,
+
+            vram: vec![0; VRAM_LENGTH as usize * vram_banks].into_boxed_slice(),
+            oam: vec![0; OAM_LENGTH as usize].into_boxed_slice(),
+            frame_buffer: vec![blank_colour(cgb_mode); WIDTH * HEIGHT].into_boxed_slice(),
+
+            lcd_control: LcdControlReg::default(),
+            lcdc_status: LcdcStatusReg::default(),
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            wy: 0,
+            wx: 0,
+            vram_bank: 0,
+            bg_palette_ram: PaletteRam::new(),
+            obj_palette_ram: PaletteRam::new(),
+            bg_palette_data: PaletteDataReg::default(),
+            obj0_palette_data: PaletteDataReg::default(),
+            obj1_palette_data: PaletteDataReg::default(),
+
+            window_line: 0,
+            window_y_triggered: false,
+
+            bg_fifo: VecDeque::with_capacity(16),
+            obj_fifo: VecDeque::with_capacity(16),
+            fetcher: Fetcher::new(FIRST_FETCH_DELAY, false),
+            lx: 0,
+            discard: 0,
+            window_active: false,
+            sprites: Vec::with_capacity(SPRITES_PER_LINE),
+            sprite_fetch: None,
+            sprite_fetch_dots: 0,
+
+            stat_line: false,
+            stat_write_pending: false,
+            first_line: false,
+            blank_pending: false,
+            hblank_started: false,
+
+            access_locking: true,
+            dropped_vram_writes: 0,
+            dropped_oam_writes: 0,
+
+            cycles: 0,
+        }
+    }
+
+    // The CPU can't access VRAM while the PPU is reading it in mode 3, reads
+    // return 0xff and writes are dropped
+    pub fn read_vram(&self, addr: u16) -> u8 {
+        if self.vram_locked() {
+            return 0xff;
+        }
+        self.vram[self.vram_offset(addr)]
+    }
+
+    pub fn write_vram(&mut self, addr: u16, val: u8) {
+        if self.vram_locked() {
+            self.dropped_vram_writes = self.dropped_vram_writes.wrapping_add(1);
+            return;
+        }
+        let offset = self.vram_offset(addr);
+        self.vram[offset] = val;
+    }
+
+    // HDMA writes to the selected VRAM bank, it's only allowed to run when
+    // the PPU isn't reading VRAM
+    pub fn hdma_write_vram(&mut self, addr: u16, val: u8) {
+        let offset = self.vram_offset(addr);
+        self.vram[offset] = val;
+    }
+
+    fn vram_offset(&self, addr: u16) -> usize {
+        (self.vram_bank as usize * VRAM_LENGTH as usize) + addr as usize
+    }
+
+    // Likewise OAM is locked during the OAM search and mode 3
+    pub fn read_oam(&self, addr: u16) -> u8 {
+        if self.oam_locked() {
+            return 0xff;
+        }
+        self.oam[addr as usize]
+    }
+
+    pub fn write_oam(&mut self, addr: u16, val: u8) {
+        if self.oam_locked() {
+            self.dropped_oam_writes = self.dropped_oam_writes.wrapping_add(1);
+            return;
+        }
+        self.oam[addr as usize] = val;
+    }
+
+    // OAM DMA has its own path to OAM which isn't locked
+    pub fn dma_write_oam(&mut self, addr: u16, val: u8) {
+        self.oam[addr as usize] = val;
+    }
+
+    // Corrupts the row the OAM search is reading, if any. OAM is treated as
+    // rows of four little endian words, each corruption mixes the row being
+    // read with the rows before it. The first row is never corrupted.
+    pub fn corrupt_oam(&mut self, corruption: OamCorruption) {
+        let row = match self.oam_row_accessed() {
+            Some(row) if row > 0 => row,
+            _ => return,
+        };
+
+        match corruption {
+            OamCorruption::Write => self.corrupt_oam_row(row, |a, b, c| ((a ^ c) & (b ^ c)) ^ c),
+            OamCorruption::Read => {
+                // The result also ends up in the row before
+                self.corrupt_oam_row(row, |a, b, c| b | (a & c));
+                let val = self.oam_word(row, 0);
+                self.set_oam_word(row - 1, 0, val);
+            }
+            OamCorruption::ReadIncrement => {
+                // The row before is corrupted and copied over its
+                // neighbours, except near either end of OAM. A normal read
+                // corruption follows either way.
+                if row >= 4 && row < OAM_ROWS - 1 {
+                    let a = self.oam_word(row - 2, 0);
+                    let b = self.oam_word(row - 1, 0);
+                    let c = self.oam_word(row, 0);
+                    let d = self.oam_word(row - 1, 2);
+                    self.set_oam_word(row - 1, 0, (b & (a | c | d)) | (a & c & d));
+
+                    for i in 0..OAM_ROW_LENGTH {
+                        let val = self.oam[((row - 1) * OAM_ROW_LENGTH) + i];
+                        self.oam[(row * OAM_ROW_LENGTH) + i] = val;
+                        self.oam[((row - 2) * OAM_ROW_LENGTH) + i] = val;
+                    }
+                }
+                self.corrupt_oam(OamCorruption::Read);
+            }
+        }
+    }
+
+    // The first word of the row is replaced using itself and the first and
+    // third words of the row before, the rest is copied from the row before
+    fn corrupt_oam_row<F: Fn(u16, u16, u16) -> u16>(&mut self, row: usize, corrupt: F) {
+        let a = self.oam_word(row, 0);
+        let b = self.oam_word(row - 1, 0);
+        let c = self.oam_word(row - 1, 2);
+        self.set_oam_word(row, 0, corrupt(a, b, c));
+
+        for i in 2..OAM_ROW_LENGTH {
+            self.oam[(row * OAM_ROW_LENGTH) + i] = self.oam[((row - 1) * OAM_ROW_LENGTH) + i];
+        }
+    }
+
+    fn oam_word(&self, row: usize, word: usize) -> u16 {
+        let offset = (row * OAM_ROW_LENGTH) + (word * 2);
+        (self.oam[offset] as u16) | ((self.oam[offset + 1] as u16) << 8)
+    }
+
+    fn set_oam_word(&mut self, row: usize, word: usize, val: u16) {
+        let offset = (row * OAM_ROW_LENGTH) + (word * 2);
+        self.oam[offset] = val as u8;
+        self.oam[offset + 1] = (val >> 8) as u8;
+    }
+
+    // The row the OAM search is reading this M-cycle
+    fn oam_row_accessed(&self) -> Option<usize> {
+        if self.lcd_control.lcd_control_op && self.lcdc_status.mode == 2 {
+            Some(self.cycles as usize / 4)
+        } else {
+            None
+        }
+    }
+
+    pub fn set_access_locking(&mut self, enabled: bool) {
+        self.access_locking = enabled;
+    }
+
+    // Returns the number of VRAM and OAM writes dropped because of locking
+    pub fn dropped_writes(&self) -> (u32, u32) {
+        (self.dropped_vram_writes, self.dropped_oam_writes)
+    }
+
+    pub fn set_colour_correction(&mut self, enabled: bool) {
+        self.colour_correction = enabled;
+    }
+
+    // Returns true once for each hblank on the visible lines
+    pub fn take_hblank_started(&mut self) -> bool {
+        let started = self.hblank_started;
+        self.hblank_started = false;
+        started
+    }
+
+    pub fn read_reg(&self, addr: u16) -> u8 {
+        match addr {
+            0xff40 => self.lcd_control.into(),
+            0xff41 => {
+                let status: u8 = self.lcdc_status.into();
+                status | 0x80
+            }
+            0xff42 => self.scy,
+            0xff43 => self.scx,
+            0xff44 => self.ly,
+            0xff45 => self.lyc,
+            0xff47 => self.bg_palette_data.into(),
+            0xff48 => self.obj0_palette_data.into(),
+            0xff49 => self.obj1_palette_data.into(),
+            0xff4a => self.wy,
+            0xff4b => self.wx,
+            0xff4f if self.cgb_mode => 0xfe | self.vram_bank,
+            0xff68 if self.cgb_mode => self.bg_palette_ram.read_spec(),
+            0xff69 if self.cgb_mode && !self.vram_locked() => self.bg_palette_ram.read_data(),
+            0xff6a if self.cgb_mode => self.obj_palette_ram.read_spec(),
+            0xff6b if self.cgb_mode && !self.vram_locked() => self.obj_palette_ram.read_data(),
+            _ => 0xff, // reads from unused addresses return 0xff
+        }
+    }
+
+    pub fn write_reg(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xff40 => {
+                let was_on = self.lcd_control.lcd_control_op;
+                self.lcd_control = val.into();
+                match (was_on, self.lcd_control.lcd_control_op) {
+                    (true, false) => self.lcd_off(),
+                    (false, true) => self.lcd_on(),
+                    _ => {}
+                }
+            }
+            0xff41 => {
+                // The mode and coincidence flag are read only
+                let mut status: LcdcStatusReg = val.into();
+                status.mode = self.lcdc_status.mode;
+                status.coincidence_flag = self.lcdc_status.coincidence_flag;
+                self.lcdc_status = status;
+                self.stat_write_pending = !self.cgb_mode && self.lcd_control.lcd_control_op;
+            }
+            0xff42 => self.scy = val,
+            0xff43 => self.scx = val,
+            0xff44 => {} // LY is read only
+            0xff45 => self.lyc = val,
+            0xff47 => self.bg_palette_data = val.into(),
+            0xff48 => self.obj0_palette_data = val.into(),
+            0xff49 => self.obj1_palette_data = val.into(),
+            0xff4a => self.wy = val,
+            0xff4b => self.wx = val,
+            0xff4f if self.cgb_mode => self.vram_bank = val & 1,
+            // Palette RAM is locked along with VRAM during mode 3
+            0xff68 if self.cgb_mode => self.bg_palette_ram.write_spec(val),
+            0xff69 if self.cgb_mode => {
+                let locked = self.vram_locked();
+                self.bg_palette_ram.write_data(val, locked);
+            }
+            0xff6a if self.cgb_mode => self.obj_palette_ram.write_spec(val),
+            0xff6b if self.cgb_mode => {
+                let locked = self.vram_locked();
+                self.obj_palette_ram.write_data(val, locked);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn step(&mut self, cycles: u16, device: &mut Device, irq: &mut Irq) {
+        if self.stat_write_pending {
+            self.stat_write_pending = false;
+            // The spurious interrupt only happens if the line was low and
+            // would have been raised with every source enabled
+            let mode = self.lcdc_status.mode;
+            if !self.stat_line && (mode == 0 || mode == 1 || self.lcdc_status.coincidence_flag) {
+                irq.raise_interrupt(Interrupt::Stat);
+            }
+            self.update_stat_line(irq);
+        }
+
+        if self.blank_pending {
+            self.blank_pending = false;
+            device.set_frame_buffer(&self.frame_buffer);
+        }
+
+        if self.lcd_control.lcd_control_op {
+            for _ in 0..cycles {
+                self.inner_step(device, irq);
+            }
+        }
+    }
+
+    pub fn get_width(&self) -> usize {
+        WIDTH
+    }
+
+    pub fn get_height(&self) -> usize {
+        HEIGHT
+    }
+
+    // Hands the current frame to the device outside of vblank, e.g. after
+    // loading a state
+    pub fn present_frame(&self, device: &mut Device) {
+        device.set_frame_buffer(&self.frame_buffer);
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.vram);
+        state.write_bytes(&self.oam);
+        for pixel in self.frame_buffer.iter() {
+            state.write_u32(*pixel);
+        }
+
+        state.write_u8(self.lcd_control.into());
+        state.write_u8(self.lcdc_status.into());
+        state.write_u8(self.scy);
+        state.write_u8(self.scx);
+        state.write_u8(self.ly);
+        state.write_u8(self.lyc);
+        state.write_u8(self.bg_palette_data.into());
+        state.write_u8(self.obj0_palette_data.into());
+        state.write_u8(self.obj1_palette_data.into());
+        state.write_u8(self.wy);
+        state.write_u8(self.wx);
+        state.write_u8(self.vram_bank);
+        self.bg_palette_ram.save_state(state);
+        self.obj_palette_ram.save_state(state);
+
+        state.write_u8(self.window_line);
+        state.write_bool(self.window_y_triggered);
+
+        fifo::save_fifo(&self.bg_fifo, state);
+        fifo::save_fifo(&self.obj_fifo, state);
+        self.fetcher.save_state(state);
+        state.write_u8(self.lx);
+        state.write_u8(self.discard);
+        state.write_bool(self.window_active);
+        state.write_u8(self.sprites.len() as u8);
+        for sprite in &self.sprites {
+            sprite.save_state(state);
+        }
+        state.write_u8(self.sprite_fetch.map_or(0xff, |i| i as u8));
+        state.write_u8(self.sprite_fetch_dots);
+
+        state.write_bool(self.stat_line);
+        state.write_bool(self.stat_write_pending);
+        state.write_bool(self.first_line);
+        state.write_bool(self.blank_pending);
+        state.write_bool(self.hblank_started);
+
+        state.write_u16(self.cycles);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        state.read_bytes(&mut self.vram)?;
+        state.read_bytes(&mut self.oam)?;
+        for pixel in self.frame_buffer.iter_mut() {
+            *pixel = state.read_u32()?;
+        }
+
+        self.lcd_control = state.read_u8()?.into();
+        self.lcdc_status = state.read_u8()?.into();
+        self.scy = state.read_u8()?;
+        self.scx = state.read_u8()?;
+        self.ly = state.read_u8()?;
+        if self.ly > 153 {
+            return Err(state::invalid_state("LY is past the last line"));
+        }
+        self.lyc = state.read_u8()?;
+        self.bg_palette_data = state.read_u8()?.into();
+        self.obj0_palette_data = state.read_u8()?.into();
+        self.obj1_palette_data = state.read_u8()?.into();
+        self.wy = state.read_u8()?;
+        self.wx = state.read_u8()?;
+        self.vram_bank = state.read_u8()? & 1;
+        if !self.cgb_mode {
+            self.vram_bank = 0;
+        }
+        self.bg_palette_ram.load_state(state)?;
+        self.obj_palette_ram.load_state(state)?;
+
+        self.window_line = state.read_u8()?;
+        self.window_y_triggered = state.read_bool()?;
+
+        fifo::load_fifo(&mut self.bg_fifo, state)?;
+        fifo::load_fifo(&mut self.obj_fifo, state)?;
+        self.fetcher.load_state(state)?;
+        self.lx = state.read_u8()?;
+        if self.lx as usize > WIDTH {
+            return Err(state::invalid_state("Pixel position is past the end of the line"));
+        }
+        self.discard = state.read_u8()?;
+        self.window_active = state.read_bool()?;
+        let sprite_count = state.read_u8()? as usize;
+        if sprite_count > SPRITES_PER_LINE {
+            return Err(state::invalid_state("Too many sprites on line"));
+        }
+        self.sprites.clear();
+        for _ in 0..sprite_count {
+            self.sprites.push(Sprite::load_state(state)?);
+        }
+        self.sprite_fetch = match state.read_u8()? as usize {
+            i if i < sprite_count => Some(i),
+            _ => None,
+        };
+        self.sprite_fetch_dots = state.read_u8()?;
+
+        self.stat_line = state.read_bool()?;
+        self.stat_write_pending = state.read_bool()?;
+        self.first_line = state.read_bool()?;
+        self.blank_pending = state.read_bool()?;
+        self.hblank_started = state.read_bool()?;
+
+        self.cycles = state.read_u16()? % LINE_DOTS;
+        Ok(())
+    }
+
+    fn vram_locked(&self) -> bool {
+        self.access_locking && self.lcd_control.lcd_control_op && self.lcdc_status.mode == 3
+    }
+
+    fn oam_locked(&self) -> bool {
+        let mode = self.lcdc_status.mode;
+        self.access_locking && self.lcd_control.lcd_control_op && (mode == 2 || mode == 3)
+    }
+
+    fn inner_step(&mut self, device: &mut Device, irq: &mut Irq) {
+        match self.lcdc_status.mode {
+            2 if self.cycles == OAM_SEARCH_DOTS - 1 => {
+                self.search_oam();
+                self.start_transfer();
+                self.lcdc_status.mode = 3;
+            }
+            0 if self.first_line && self.cycles == OAM_SEARCH_DOTS - 1 => {
+                self.first_line = false;
+                self.search_oam();
+                self.start_transfer();
+                self.lcdc_status.mode = 3;
+            }
+            3 => {
+                self.transfer_dot();
+
+                if self.lx as usize == WIDTH {
+                    if self.window_active {
+                        self.window_line += 1;
+                    }
+                    self.lcdc_status.mode = 0;
+                    self.hblank_started = true;
+                }
+            }
+            // LY reads 0 for most of line 153, so LYC is matched against 153
+            // only briefly
+            1 if self.ly == 153 && self.cycles == 4 => self.ly = 0,
+            _ => {}
+        }
+
+        self.cycles += 1;
+        if self.cycles == LINE_DOTS {
+            self.cycles = 0;
+            self.end_line(device, irq);
+        }
+
+        self.lcdc_status.coincidence_flag = self.ly == self.lyc;
+        self.update_stat_line(irq);
+    }
+
+    fn end_line(&mut self, device: &mut Device, irq: &mut Irq) {
+        if self.lcdc_status.mode == 1 {
+            // LY has already wrapped around to 0 on line 153
+            if self.ly == 0 {
+                self.lcdc_status.mode = 2;
+                self.window_line = 0;
+                self.window_y_triggered = false;
+            } else {
+                self.ly += 1;
+            }
+            return;
+        }
+
+        self.ly += 1;
+
+        if self.ly < 144 {
+            self.lcdc_status.mode = 2;
+        } else {
+            self.lcdc_status.mode = 1;
+            irq.raise_interrupt(Interrupt::VBlank);
+            device.set_frame_buffer(&self.frame_buffer);
+        }
+    }
+
+    fn update_stat_line(&mut self, irq: &mut Irq) {
+        let status = self.lcdc_status;
+        let mode = status.mode;
+        // The OAM source also fires at the start of vblank
+        let oam = mode == 2 || (mode == 1 && self.ly == 144 && self.cycles == 0);
+
+        let stat_line = (status.hblank_interrupt_enable && mode == 0 && !self.first_line) ||
+                        (status.vblank_interrupt_enable && mode == 1) ||
+                        (status.oam_interrupt_enable && oam) ||
+                        (status.coincidence_interrupt_enable && status.coincidence_flag);
+
+        if stat_line && !self.stat_line {
+            irq.raise_interrupt(Interrupt::Stat);
+        }
+        self.stat_line = stat_line;
+    }
+
+    // Switching the LCD off resets LY and the mode, and the screen is left
+    // blank until it's switched back on
+    fn lcd_off(&mut self) {
+        self.ly = 0;
+        self.cycles = 0;
+        self.lcdc_status.mode = 0;
+        self.stat_line = false;
+        self.hblank_started = false;
+        self.window_line = 0;
+        self.window_y_triggered = false;
+
+        let colour = blank_colour(self.cgb_mode);
+        for pixel in self.frame_buffer.iter_mut() {
+            *pixel = colour;
+        }
+        self.blank_pending = true;
+    }
+
+    // The first line after switching on is 4 dots short
+    fn lcd_on(&mut self) {
+        self.ly = 0;
+        self.cycles = 4;
+        self.lcdc_status.mode = 0;
+        self.lcdc_status.coincidence_flag = self.ly == self.lyc;
+        self.first_line = true;
+    }
+
+    // Finds the first ten sprites in OAM that cover the current line
+    fn search_oam(&mut self) {
+        let sprite_height = if self.lcd_control.sprite_size { 16 } else { 8 };
+        let ly = self.ly as u16 + 16;
+
+        self.sprites.clear();
+        for i in 0..40 {
+            let y = self.oam[i * 4];
+            if ly >= y as u16 && ly < y as u16 + sprite_height {
+                self.sprites.push(Sprite {
+                    y: y,
+                    x: self.oam[(i * 4) + 1],
+                    tile: self.oam[(i * 4) + 2],
+                    flags: self.oam[(i * 4) + 3],
+                    oam_index: i as u8,
+                    fetched: false,
+                });
+                if self.sprites.len() == SPRITES_PER_LINE {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn start_transfer(&mut self) {
+        if self.ly == self.wy {
+            self.window_y_triggered = true;
+        }
+
+        self.bg_fifo.clear();
+        self.obj_fifo.clear();
+        self.fetcher = Fetcher::new(FIRST_FETCH_DELAY, false);
+        self.lx = 0;
+        self.discard = self.scx % 8;
+        self.window_active = false;
+        self.sprite_fetch = None;
+        self.sprite_fetch_dots = 0;
+    }
+
+    // Runs one dot of mode 3, each dot the fetcher advances and a pixel is
+    // shifted out to the LCD unless the FIFO is empty or a sprite is being
+    // fetched
+    fn transfer_dot(&mut self) {
+        self.start_window();
+
+        if self.sprite_fetch.is_none() && self.lcd_control.sprite_display {
+            let lx = self.lx as u16;
+            self.sprite_fetch = self.sprites
+                .iter()
+                .position(|s| !s.fetched && s.x as u16 <= lx + 8);
+        }
+
+        if let Some(index) = self.sprite_fetch {
+            // The sprite fetch waits for the BG fetcher to finish its tile,
+            // no pixels are shifted out in the meantime
+            if !self.fetcher.tile_ready() || self.bg_fifo.is_empty() {
+                self.fetcher_dot();
+                if !self.fetcher.tile_ready() || self.bg_fifo.is_empty() {
+                    return;
+                }
+            }
+
+            self.sprite_fetch_dots += 1;
+            if self.sprite_fetch_dots == SPRITE_FETCH_DOTS {
+                self.fetch_sprite(index);
+                self.sprite_fetch = None;
+                self.sprite_fetch_dots = 0;
+            }
+            return;
+        }
+
+        self.fetcher_dot();
+        // With WX at 7 the window starts as soon as the first tile has been
+        // fetched, before its first pixel is shifted out
+        if self.start_window() {
+            return;
+        }
+
+        let bg = match self.bg_fifo.pop_front() {
+            Some(pixel) => pixel,
+            None => return,
+        };
+        if self.discard > 0 {
+            self.discard -= 1;
+            return;
+        }
+        let obj = self.obj_fifo.pop_front();
+
+        let colour = self.mix_pixel(bg, obj);
+        self.frame_buffer[(self.ly as usize * WIDTH) + self.lx as usize] = colour;
+        self.lx += 1;
+    }
+
+    // When the window starts the BG FIFO is cleared and the fetcher restarts
+    // on the window's tile map. WX is offset by 7, a WX below 7 shifts the
+    // window off the left edge of the screen. Returns true if it started.
+    fn start_window(&mut self) -> bool {
+        if self.window_active || !self.lcd_control.window_display || !self.window_y_triggered ||
+           self.bg_fifo.is_empty() || self.discard != 0 ||
+           (self.lx as u16 + 7) < self.wx as u16 || self.wx > 166 {
+            return false;
+        }
+
+        self.window_active = true;
+        self.bg_fifo.clear();
+        self.fetcher = Fetcher::new(0, true);
+        self.discard = 7u8.saturating_sub(self.wx);
+        true
+    }
+
+    fn mix_pixel(&self, bg: Pixel, obj: Option<Pixel>) -> u32 {
+        if self.cgb_mode {
+            return self.mix_cgb_pixel(bg, obj);
+        }
+
+        // With LCDC.0 clear neither the background nor the window is drawn
+        // on the DMG, sprites still are
+        let bg_colour = if self.lcd_control.bg_window_display { bg.colour } else { 0 };
+
+        if let Some(obj) = obj {
+            // Colour 0 is transparent, and sprites with bit 7 set are hidden
+            // behind BG colours 1 - 3
+            if obj.colour != 0 && self.lcd_control.sprite_display &&
+               !(obj.behind_bg && bg_colour != 0) {
+                let palette = if obj.palette == 0 {
+                    self.obj0_palette_data
+                } else {
+                    self.obj1_palette_data
+                };
+                return palette.colour(obj.colour);
+            }
+        }
+
+        if self.lcd_control.bg_window_display {
+            self.bg_palette_data.colour(bg_colour)
+        } else {
+            COLOUR_MAP[0]
+        }
+    }
+
+    // On the CGB LCDC.0 no longer hides the background, instead when it's
+    // clear sprites are always drawn on top. Otherwise either the BG map
+    // attributes or the sprite can put BG colours 1 - 3 in front.
+    fn mix_cgb_pixel(&self, bg: Pixel, obj: Option<Pixel>) -> u32 {
+        if let Some(obj) = obj {
+            let bg_in_front = self.lcd_control.bg_window_display && bg.colour != 0 &&
+                              (bg.behind_bg || obj.behind_bg);
+            if obj.colour != 0 && self.lcd_control.sprite_display && !bg_in_front {
+                let colour = self.obj_palette_ram.colour(obj.palette, obj.colour);
+                return palette_ram::rgb15_to_u32(colour, self.colour_correction);
+            }
+        }
+
+        let colour = self.bg_palette_ram.colour(bg.palette, bg.colour);
+        palette_ram::rgb15_to_u32(colour, self.colour_correction)
+    }
+
+    fn fetcher_dot(&mut self) {
+        if self.fetcher.delay > 0 {
+            self.fetcher.delay -= 1;
+            return;
+        }
+
+        if self.fetcher.step == FetchStep::Push {
+            if self.bg_fifo.is_empty() {
+                let attributes = self.fetcher.attributes;
+                let flip_horz = attributes & (1 << 5) != 0;
+                for i in 0..8 {
+                    let col = if flip_horz { 7 - i } else { i };
+                    let upper = (self.fetcher.data_high >> (7 - col)) & 1;
+                    let lower = (self.fetcher.data_low >> (7 - col)) & 1;
+                    self.bg_fifo.push_back(Pixel {
+                        colour: (upper << 1) | lower,
+                        palette: attributes & 0x7,
+                        behind_bg: attributes & (1 << 7) != 0,
+                        oam_index: 0,
+                    });
+                }
+                self.fetcher.tile_x = self.fetcher.tile_x.wrapping_add(1);
+                self.fetcher.step = FetchStep::Tile;
+            }
+            return;
+        }
+
+        // Each step takes two dots, the memory access happens on the second
+        if !self.fetcher.dot {
+            self.fetcher.dot = true;
+            return;
+        }
+        self.fetcher.dot = false;
+
+        match self.fetcher.step {
+            FetchStep::Tile => {
+                let (high_map, row, col) = if self.fetcher.window {
+                    (self.lcd_control.win_tile_map_display,
+                     self.window_line / 8,
+                     self.fetcher.tile_x & 31)
+                } else {
+                    (self.lcd_control.bg_tile_map_display,
+                     self.ly.wrapping_add(self.scy) / 8,
+                     ((self.scx / 8) + self.fetcher.tile_x) & 31)
+                };
+                let map_base = if high_map { 0x1c00 } else { 0x1800 };
+                let map_offset = map_base + (row as usize * 32) + col as usize;
+                self.fetcher.tile_index = self.vram[map_offset];
+                // The CGB keeps each tile's attributes at the same place in
+                // VRAM bank 1
+                if self.cgb_mode {
+                    self.fetcher.attributes = self.vram[VRAM_LENGTH as usize + map_offset];
+                }
+                self.fetcher.step = FetchStep::DataLow;
+            }
+            FetchStep::DataLow => {
+                let offset = self.bg_tile_row_offset();
+                self.fetcher.data_low = self.vram[offset];
+                self.fetcher.step = FetchStep::DataHigh;
+            }
+            FetchStep::DataHigh => {
+                let offset = self.bg_tile_row_offset();
+                self.fetcher.data_high = self.vram[offset + 1];
+                self.fetcher.step = FetchStep::Push;
+            }
+            FetchStep::Push => unreachable!(),
+        }
+    }
+
+    // Returns the offset in self.vram of the row of the tile being fetched
+    fn bg_tile_row_offset(&self) -> usize {
+        let mut row = if self.fetcher.window {
+            self.window_line % 8
+        } else {
+            self.ly.wrapping_add(self.scy) % 8
+        };
+
+        let attributes = self.fetcher.attributes;
+        if attributes & (1 << 6) != 0 {
+            row = 7 - row;
+        }
+        let bank_offset = if attributes & (1 << 3) != 0 { VRAM_LENGTH as usize } else { 0 };
+
+        let tile_index = self.fetcher.tile_index;
+        let tile_offset = if self.lcd_control.bg_win_tile_data {
+            tile_index as usize * 16
+        } else {
+            (((tile_index as i8) as isize) + 256) as usize * 16
+        };
+        bank_offset + tile_offset + (row as usize * 2)
+    }
+
+    // Merges the sprite's pixels into the OBJ FIFO, pixels of sprites
+    // fetched earlier keep priority unless they're transparent
+    fn fetch_sprite(&mut self, index: usize) {
+        self.sprites[index].fetched = true;
+        let sprite = self.sprites[index];
+
+        let sprite_height = if self.lcd_control.sprite_size { 16 } else { 8 };
+        let behind_bg = (sprite.flags & (1 << 7)) != 0;
+        let flip_vert = (sprite.flags & (1 << 6)) != 0;
+        let flip_horz = (sprite.flags & (1 << 5)) != 0;
+        // The CGB has eight sprite palettes and takes tiles from either bank
+        let (palette, bank_offset) = if self.cgb_mode {
+            let bank = if sprite.flags & (1 << 3) != 0 { VRAM_LENGTH as usize } else { 0 };
+            (sprite.flags & 0x7, bank)
+        } else {
+            ((sprite.flags >> 4) & 1, 0)
+        };
+
+        let mut sprite_row = (self.ly + 16).wrapping_sub(sprite.y) % sprite_height;
+        if flip_vert {
+            sprite_row = sprite_height - 1 - sprite_row;
+        }
+
+        // 8x16 sprites ignore bit 0 of the tile index, the row selects the
+        // top or bottom tile
+        let mut tile_index = sprite.tile as usize;
+        if sprite_height == 16 {
+            tile_index &= 0xfe;
+        }
+        let offset = bank_offset + (tile_index * 16) + (sprite_row as usize * 2);
+        let data_low = self.vram[offset];
+        let data_high = self.vram[offset + 1];
+
+        while self.obj_fifo.len() < 8 {
+            self.obj_fifo.push_back(Pixel::default());
+        }
+
+        // Sprites partly off the left edge lose their first pixels
+        let skip = 8u8.saturating_sub(sprite.x);
+        for i in skip..8 {
+            let col = if flip_horz { 7 - i } else { i };
+            let upper = (data_high >> (7 - col)) & 1;
+            let lower = (data_low >> (7 - col)) & 1;
+
+            let colour = (upper << 1) | lower;
+
+            // On the CGB a sprite earlier in OAM wins over one fetched
+            // before it
+            let cgb_mode = self.cgb_mode;
+            let slot = &mut self.obj_fifo[(i - skip) as usize];
+            if slot.colour == 0 || (cgb_mode && colour != 0 && sprite.oam_index < slot.oam_index) {
+                *slot = Pixel {
+                    colour: colour,
+                    palette: palette,
+                    behind_bg: behind_bg,
+                    oam_index: sprite.oam_index,
+                };
+            }
+        }
+    }
+}
+
+fn blank_colour(cgb_mode: bool) -> u32 {
+    if cgb_mode { CGB_BLANK_COLOUR } else { COLOUR_MAP[0] }
+}
+
+#[derive(Default, Copy, Clone)]
+pub struct LcdControlReg {
+    bg_window_display: bool,
+    sprite_display: bool,
+    sprite_size: bool,
+    bg_tile_map_display: bool,
+    bg_win_tile_data: bool,
+    window_display: bool,
+    win_tile_map_display: bool,
+    lcd_control_op: bool,
+}
+
+impl From<u8> for LcdControlReg {
+    fn from(val: u8) -> Self {
+        LcdControlReg {
+            bg_window_display: val & 1 != 0,
+            sprite_display: val & (1 << 1) != 0,
+            sprite_size: val & (1 << 2) != 0,
+            bg_tile_map_display: val & (1 << 3) != 0,
+            bg_win_tile_data: val & (1 << 4) != 0,
+            window_display: val & (1 << 5) != 0,
+            win_tile_map_display: val & (1 << 6) != 0,
+            lcd_control_op: val & (1 << 7) != 0,
+        }
+    }
+}
+
+impl Into<u8> for LcdControlReg {
+    fn into(self) -> u8 {
+        let mut ret: u8 = 0;
+        if self.bg_window_display {
+            ret |= 1;
+        }
+        if self.sprite_display {
+            ret |= 1 << 1;
+        }
+        if self.sprite_size {
+            ret |= 1 << 2;
+        }
+        if self.bg_tile_map_display {
+            ret |= 1 << 3;
+        }
+        if self.bg_win_tile_data {
+            ret |= 1 << 4;
+        }
+        if self.window_display {
+            ret |= 1 << 5;
+        }
+        if self.win_tile_map_display {
+            ret |= 1 << 6;
+        }
+        if self.lcd_control_op {
+            ret |= 1 << 7;
+        }
+        ret
+    }
+}
+
+
+#[derive(Copy, Clone)]
+pub struct LcdcStatusReg {
+    coincidence_interrupt_enable: bool,
+    oam_interrupt_enable: bool,
+    vblank_interrupt_enable: bool,
+    hblank_interrupt_enable: bool,
+    coincidence_flag: bool,
+    mode: u8,
+}
+
+impl Default for LcdcStatusReg {
+    fn default() -> Self {
+        LcdcStatusReg {
+            coincidence_interrupt_enable: false,
+            oam_interrupt_enable: false,
+            vblank_interrupt_enable: false,
+            hblank_interrupt_enable: false,
+            coincidence_flag: false,
+            mode: 2, // Start in OAM access mode
+        }
+    }
+}
+
+impl From<u8> for LcdcStatusReg {
+    fn from(val: u8) -> Self {
+        LcdcStatusReg {
+            coincidence_interrupt_enable: val & (1 << 6) != 0,
+            oam_interrupt_enable: val & (1 << 5) != 0,
+            vblank_interrupt_enable: val & (1 << 4) != 0,
+            hblank_interrupt_enable: val & (1 << 3) != 0,
+            coincidence_flag: val & (1 << 2) != 0,
+            mode: val & 0x3,
+        }
+    }
+}
+
+impl Into<u8> for LcdcStatusReg {
+    fn into(self) -> u8 {
+        let mut ret = self.mode;
+
+        if self.coincidence_interrupt_enable {
+            ret |= 1 << 6;
+        }
+        if self.oam_interrupt_enable {
+            ret |= 1 << 5;
+        }
+        if self.vblank_interrupt_enable {
+            ret |= 1 << 4;
+        }
+        if self.hblank_interrupt_enable {
+            ret |= 1 << 3;
+        }
+        if self.coincidence_flag {
+            ret |= 1 << 2;
+        }
+        ret
+    }
+}
+
+#[derive(Default, Copy, Clone)]
+pub struct PaletteDataReg {
+    col0_shade: usize,
+    col1_shade: usize,
+    col2_shade: usize,
+    col3_shade: usize,
+}
+
+impl From<u8> for PaletteDataReg {
+    fn from(val: u8) -> Self {
+        let val = val as usize;
+        PaletteDataReg {
+            col0_shade: val & 0x3,
+            col1_shade: (val >> 2) & 0x3,
+            col2_shade: (val >> 4) & 0x3,
+            col3_shade: (val >> 6) & 0x3,
+        }
+    }
+}
+
+impl PaletteDataReg {
+    fn colour(&self, tile_colour: u8) -> u32 {
+        let col_index = match tile_colour {
+            0 => self.col0_shade,
+            1 => self.col1_shade,
+            2 => self.col2_shade,
+            3 => self.col3_shade,
+            _ => unreachable!(),
+        };
+        COLOUR_MAP[col_index]
+    }
+}
+
+impl Into<u8> for PaletteDataReg {
+    fn into(self) -> u8 {
+        (self.col0_shade | (self.col1_shade << 2) | (self.col2_shade << 4) |
+         (self.col3_shade << 6)) as u8
+    }
+}
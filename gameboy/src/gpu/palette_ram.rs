@@ -0,0 +1,86 @@
+use std::io;
+use state::{StateReader, StateWriter};
+
+const PALETTE_RAM_LENGTH: usize = 64;
+
+// CGB colour palette memory, eight palettes of four 15 bit colours. It's
+// accessed a byte at a time through an index register (BCPS/OCPS) and a data
+// register (BCPD/OCPD), the index can step forward after each write.
+pub struct PaletteRam {
+    data: [u8; PALETTE_RAM_LENGTH],
+    index: u8,
+    auto_increment: bool,
+}
+
+impl PaletteRam {
+    pub fn new() -> Self {
+        PaletteRam {
+            data: [0; PALETTE_RAM_LENGTH],
+            index: 0,
+            auto_increment: false,
+        }
+    }
+
+    // Bit 6 is unused and reads set
+    pub fn read_spec(&self) -> u8 {
+        let increment = if self.auto_increment { 0x80 } else { 0 };
+        increment | 0x40 | self.index
+    }
+
+    pub fn write_spec(&mut self, val: u8) {
+        self.index = val & 0x3f;
+        self.auto_increment = val & 0x80 != 0;
+    }
+
+    pub fn read_data(&self) -> u8 {
+        self.data[self.index as usize]
+    }
+
+    // The index steps forward even when the write itself is dropped
+    pub fn write_data(&mut self, val: u8, locked: bool) {
+        if !locked {
+            self.data[self.index as usize] = val;
+        }
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3f;
+        }
+    }
+
+    // Returns the 15 bit colour, red in the low bits
+    pub fn colour(&self, palette: u8, colour: u8) -> u16 {
+        let offset = ((palette as usize * 4) + colour as usize) * 2;
+        (self.data[offset] as u16) | ((self.data[offset + 1] as u16) << 8)
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.data);
+        state.write_u8(self.index);
+        state.write_bool(self.auto_increment);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        state.read_bytes(&mut self.data)?;
+        self.index = state.read_u8()? & 0x3f;
+        self.auto_increment = state.read_bool()?;
+        Ok(())
+    }
+}
+
+// Expands a 15 bit CGB colour for the frame buffer. The CGB's LCD doesn't
+// show colours the way a modern screen does, the correction mixes the
+// channels to get closer to how games looked on the real thing.
+pub fn rgb15_to_u32(colour: u16, correct: bool) -> u32 {
+    let r = (colour & 0x1f) as u32;
+    let g = ((colour >> 5) & 0x1f) as u32;
+    let b = ((colour >> 10) & 0x1f) as u32;
+
+    let (r, g, b) = if correct {
+        (((r * 13) + (g * 2) + b) >> 1,
+         ((g * 3) + b) << 1,
+         ((r * 3) + (g * 2) + (b * 11)) >> 1)
+    } else {
+        ((r << 3) | (r >> 2), (g << 3) | (g >> 2), (b << 3) | (b >> 2))
+    };
+
+    0xff000000 | (r << 16) | (g << 8) | b
+}
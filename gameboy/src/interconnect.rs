@@ -1,84 +1,146 @@
 use std::collections::HashSet;
+use std::io;
 use mem_map::*;
 use cartridge::Cartridge;
 use memory::Memory;
-use gpu::Gpu;
+use gpu::{Gpu, OamCorruption};
 use device::Device;
 use apu::Apu;
 use timer::Timer;
 use gamepad::Gamepad;
 use interrupt::Irq;
+use dma::{Dma, Hdma, HDMA_BLOCK_LENGTH};
+use serial::{Serial, SerialPeer};
+use model::Model;
+use state::{self, StateReader, StateWriter};
+
+// Internal RAM is banked in 4KB blocks on the CGB, 0xd000 - 0xdfff maps one
+// of banks 1 - 7
+const WRAM_BANK_LENGTH: u16 = 0x1000;
+const CGB_WRAM_BANKS: u16 = 8;
+// Clocks the CPU is stalled for while the HDMA copies a block
+const HDMA_BLOCK_CLOCKS: u16 = 32;
 
 pub struct Interconnect {
+    model: Model,
+    // CGB hardware is only enabled for cartridges that support it, others
+    // run in the CGB's DMG compatibility mode
+    cgb_mode: bool,
+
     cartridge: Cartridge,
     gpu: Gpu,
     apu: Apu,
     timer: Timer,
     gamepad: Gamepad,
+    serial: Serial,
+    dma: Dma,
+    hdma: Hdma,
 
     internal_ram: Memory,
+    wram_bank: u8, // 0xff70 - SVBK
     high_ram: Memory,
     pub if_register: u8,
     pub ie_register: u8,
 
-    serial_transfer_data: u8,
-    serial_control: u8,
+    double_speed: bool, // 0xff4d - KEY1 bit 7
+    speed_switch_armed: bool, // 0xff4d - KEY1 bit 0
+    // Clocks the CPU has to wait for before it can carry on, e.g. while an
+    // HDMA transfer runs
+    stall_cycles: u16,
+    // Set by STOP, the system clock is stopped until a button is pressed
+    stopped: bool,
 
     trigger_watchpoint: bool,
     pub watchpoints: HashSet<u16>,
-
-    dma_source: u16,
-    dma_index: u16,
-    dma_active: bool,
 }
 
 impl Interconnect {
     pub fn new(cartridge: Cartridge) -> Interconnect {
+        let model = Model::detect(&cartridge);
+        Interconnect::with_model(cartridge, model)
+    }
+
+    pub fn with_model(cartridge: Cartridge, model: Model) -> Interconnect {
+        let cgb_mode = model == Model::Cgb && cartridge.supports_cgb();
+        let internal_ram_length = if cgb_mode {
+            WRAM_BANK_LENGTH * CGB_WRAM_BANKS
+        } else {
+            INTERNAL_RAM_LENGTH
+        };
+
         Interconnect {
+            model: model,
+            cgb_mode: cgb_mode,
+
             cartridge: cartridge,
-            gpu: Gpu::new(),
+            gpu: Gpu::new(cgb_mode),
             apu: Apu::new(),
             timer: Timer::default(),
             gamepad: Gamepad::new(),
+            serial: Serial::new(cgb_mode),
+            dma: Dma::new(),
+            hdma: Hdma::new(),
 
-            internal_ram: Memory::new(INTERNAL_RAM_LENGTH),
+            internal_ram: Memory::new(internal_ram_length),
+            wram_bank: 1,
             high_ram: Memory::new(HIGH_RAM_END),
 
             if_register: 0,
             ie_register: 0,
 
-            serial_transfer_data: 0,
-            serial_control: 0,
+            double_speed: false,
+            speed_switch_armed: false,
+            stall_cycles: 0,
+            stopped: false,
 
             watchpoints: HashSet::new(),
             trigger_watchpoint: false,
-
-            dma_source: 0,
-            dma_index: 0,
-            dma_active: false,
         }
     }
 
     pub fn read_byte(&self, addr: u16) -> u8 {
+        // During OAM DMA the CPU sees the byte being transferred on the bus
+        // the DMA is using, OAM itself reads as 0xff. HRAM and the IO
+        // registers are still accessible.
+        if self.dma.active() && addr < 0xff00 {
+            if addr >= OAM_START {
+                return 0xff;
+            }
+            if self.dma.conflicts(addr) {
+                return self.dma.bus_value();
+            }
+        }
+
+        self.read_bus(addr)
+    }
+
+    fn read_bus(&self, addr: u16) -> u8 {
         match addr {
             ROM_START...ROM_END => self.cartridge.read_byte(addr - ROM_START),
             VRAM_START...VRAM_END => self.gpu.read_vram(addr - VRAM_START),
             CRAM_START...CRAM_END => self.cartridge.read_byte(addr - ROM_START),
             INTERNAL_RAM_START...INTERNAL_RAM_END => {
-                self.internal_ram.read_byte(addr - INTERNAL_RAM_START)
+                self.internal_ram.read_byte(self.internal_ram_offset(addr - INTERNAL_RAM_START))
+            }
+            IRAM_ECHO_START...IRAM_ECHO_END => {
+                self.internal_ram.read_byte(self.internal_ram_offset(addr - IRAM_ECHO_START))
             }
-            IRAM_ECHO_START...IRAM_ECHO_END => self.internal_ram.read_byte(addr - IRAM_ECHO_START),
             OAM_START...OAM_END => self.gpu.read_oam(addr - OAM_START),
             0xff00 => self.gamepad.read_reg(),
-
-            // TODO - implement serial port (Link cable)
-            0xff01 => 0x00,
-            0xff02 => 0x7e,
+            0xff01...0xff02 => self.serial.read_reg(addr),
 
             0xff04...0xff07 => self.timer.read_reg(addr),
             0xff0f => self.if_register,
             0xff10...0xff3f => self.apu.read_reg(addr),
+            0xff46 => self.dma.read_reg(),
+            0xff4d if self.cgb_mode => {
+                let speed = if self.double_speed { 0x80 } else { 0 };
+                0x7e | speed | self.speed_switch_armed as u8
+            }
             0xff40...0xff4f => self.gpu.read_reg(addr),
+            0xff51...0xff55 if self.cgb_mode => self.hdma.read_reg(addr),
+            0xff68...0xff6b => self.gpu.read_reg(addr),
+            0xff70 if self.cgb_mode => 0xf8 | self.wram_bank,
             HIGH_RAM_START...HIGH_RAM_END => self.high_ram.read_byte(addr - HIGH_RAM_START),
             0xffff => self.ie_register,
 
@@ -97,34 +159,44 @@ impl Interconnect {
             VRAM_START...VRAM_END => self.gpu.write_vram(addr - VRAM_START, val),
             CRAM_START...CRAM_END => self.cartridge.write(addr - ROM_START, val),
             INTERNAL_RAM_START...INTERNAL_RAM_END => {
-                self.internal_ram.write_byte(addr - INTERNAL_RAM_START, val)
+                let offset = self.internal_ram_offset(addr - INTERNAL_RAM_START);
+                self.internal_ram.write_byte(offset, val)
             }
             IRAM_ECHO_START...IRAM_ECHO_END => {
-                self.internal_ram.write_byte(addr - IRAM_ECHO_START, val)
+                let offset = self.internal_ram_offset(addr - IRAM_ECHO_START);
+                self.internal_ram.write_byte(offset, val)
             }
             HIGH_RAM_START...HIGH_RAM_END => self.high_ram.write_byte(addr - HIGH_RAM_START, val),
-            OAM_START...OAM_END => self.gpu.write_oam(addr - OAM_START, val),
+            OAM_START...OAM_END if !self.dma.active() => {
+                self.gpu.write_oam(addr - OAM_START, val)
+            }
             0xff00 => self.gamepad.write_reg(val),
-
-            // TODO - implement serial port (Link cable)
-            0xff01 => self.serial_transfer_data = val,
-            0xff02 => self.serial_control = val,
+            0xff01...0xff02 => self.serial.write_reg(addr, val),
 
             0xff04...0xff07 => self.timer.write_reg(addr, val),
             0xff0f => self.if_register = val,
             0xff10...0xff3f => self.apu.write_reg(addr, val),
-            0xff46 => {
-                self.dma_source = (val as u16) << 8;
-                self.dma_index = 0;
-                self.dma_active = true;
-            }
-            0xff40...0xff4b => self.gpu.write_reg(addr, val),
+            0xff46 => self.dma.write_reg(val),
+            0xff4d if self.cgb_mode => self.speed_switch_armed = val & 1 != 0,
+            0xff40...0xff4f => self.gpu.write_reg(addr, val),
             0xff50 => self.cartridge.disable_boot_rom(),
+            0xff51...0xff55 if self.cgb_mode => self.hdma.write_reg(addr, val),
+            0xff68...0xff6b => self.gpu.write_reg(addr, val),
+            0xff70 if self.cgb_mode => self.wram_bank = if val & 0x7 == 0 { 1 } else { val & 0x7 },
             0xffff => self.ie_register = val,
             _ => {} // Writes to unused addresses have no effect
         }
     }
 
+    // Maps an offset into 0xc000 - 0xdfff to the switchable bank
+    fn internal_ram_offset(&self, offset: u16) -> u16 {
+        if self.cgb_mode && offset >= WRAM_BANK_LENGTH {
+            (self.wram_bank as u16 * WRAM_BANK_LENGTH) + offset - WRAM_BANK_LENGTH
+        } else {
+            offset
+        }
+    }
+
     pub fn read_halfword(&self, addr: u16) -> u16 {
         let lsb = self.read_byte(addr);
         let msb = self.read_byte(addr + 1);
@@ -140,31 +212,144 @@ impl Interconnect {
         self.write_byte(addr + 1, msb);
     }
 
-    pub fn step(&mut self, cycles: u16, device: &mut Device) -> bool {
-        if self.dma_active {
-            let index = self.dma_index;
-            let val = self.read_byte(self.dma_source + index);
-            self.write_byte(OAM_START + index, val);
-            self.dma_index += 1;
+    pub fn step(&mut self, cycles: u16, device: &mut Device) {
+        let mut irq = Irq::default();
+
+        if self.stopped {
+            // Only the buttons are watched, the cartridge's RTC has its own
+            // crystal so it keeps running
+            let normal_cycles = if self.double_speed { cycles / 2 } else { cycles };
+            self.cartridge.step(normal_cycles, device);
+            self.gamepad.step(cycles, device, &mut irq);
+            self.if_register |= irq.get_if();
 
-            if self.dma_index >= 160 {
-                self.dma_active = false;
+            if self.gamepad.input_low() {
+                self.stopped = false;
             }
+            return;
         }
 
-        let mut irq = Irq::default();
+        for _ in 0..cycles / 4 {
+            self.step_dma();
+        }
 
-        self.gpu.step(cycles, device, &mut irq);
+        // In double speed mode the CPU, timer and OAM DMA run twice as fast,
+        // everything else is still clocked at the normal rate. The APU sees
+        // the divider at half speed so its frame sequencer isn't sped up.
+        let (normal_cycles, divider) = if self.double_speed {
+            (cycles / 2, self.timer.divider >> 1)
+        } else {
+            (cycles, self.timer.divider)
+        };
+
+        self.cartridge.step(normal_cycles, device);
+        self.gpu.step(normal_cycles, device, &mut irq);
+        self.apu.step(normal_cycles, divider, device);
+        self.serial.step(cycles, self.timer.divider, &mut irq);
         self.timer.step(cycles, device, &mut irq);
         self.gamepad.step(cycles, device, &mut irq);
 
         self.if_register |= irq.get_if();
 
+        self.step_hdma();
+    }
+
+    // On the DMG the CPU putting an address in 0xfe00 - 0xfeff on the bus
+    // while the PPU is searching OAM corrupts the row the PPU is reading.
+    // The CGB doesn't have the bug.
+    pub fn corrupt_oam(&mut self, addr: u16, corruption: OamCorruption) {
+        if self.model == Model::Dmg && addr >= OAM_START && addr <= 0xfeff {
+            self.gpu.corrupt_oam(corruption);
+        }
+    }
+
+    // Returns true once after a watched address has been written
+    pub fn take_watchpoint_trigger(&mut self) -> bool {
         let trigger_watchpoint = self.trigger_watchpoint;
         self.trigger_watchpoint = false;
         trigger_watchpoint
     }
 
+    fn step_dma(&mut self) {
+        if let Some((addr, index)) = self.dma.next_transfer() {
+            let val = self.read_bus(addr);
+            self.gpu.dma_write_oam(index, val);
+            self.dma.transferred(val);
+        }
+        self.dma.step();
+    }
+
+    fn step_hdma(&mut self) {
+        let mut blocks = 0;
+        while self.hdma.general_pending() {
+            self.copy_hdma_block();
+            blocks += 1;
+        }
+        if self.gpu.take_hblank_started() && self.hdma.hblank_active() {
+            self.copy_hdma_block();
+            blocks += 1;
+        }
+
+        // The transfer takes the same time in either speed mode, so the CPU
+        // waits twice as many of its own clocks in double speed
+        let clocks = if self.double_speed { HDMA_BLOCK_CLOCKS * 2 } else { HDMA_BLOCK_CLOCKS };
+        self.stall_cycles += blocks * clocks;
+    }
+
+    fn copy_hdma_block(&mut self) {
+        let (source, destination) = self.hdma.next_block();
+        for i in 0..HDMA_BLOCK_LENGTH {
+            let val = self.read_bus(source.wrapping_add(i));
+            self.gpu.hdma_write_vram(destination + i, val);
+        }
+    }
+
+    // Returns the clocks the CPU has to sit out, they still need to be
+    // stepped through
+    pub fn take_stall_cycles(&mut self) -> u16 {
+        let cycles = self.stall_cycles;
+        self.stall_cycles = 0;
+        cycles
+    }
+
+    // Called for the STOP instruction when no button is held. The divider is
+    // reset, then the speed is switched if a switch has been armed through
+    // KEY1, otherwise the system clock stops until a button is pressed.
+    pub fn stop(&mut self) {
+        self.timer.write_reg(0xff04, 0);
+        if self.cgb_mode && self.speed_switch_armed {
+            self.speed_switch_armed = false;
+            self.double_speed = !self.double_speed;
+        } else {
+            self.stopped = true;
+        }
+    }
+
+    pub fn stopped(&self) -> bool {
+        self.stopped
+    }
+
+    // True while a selected button is pressed
+    pub fn button_held(&self) -> bool {
+        self.gamepad.input_low()
+    }
+
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    pub fn set_serial_peer(&mut self, peer: Box<SerialPeer>) {
+        self.serial.set_peer(peer);
+    }
+
+    pub fn set_colour_correction(&mut self, enabled: bool) {
+        self.gpu.set_colour_correction(enabled);
+    }
+
     pub fn get_width(&self) -> usize {
         self.gpu.get_width()
     }
@@ -173,7 +358,76 @@ impl Interconnect {
         self.gpu.get_height()
     }
 
+    pub fn present_frame(&self, device: &mut Device) {
+        self.gpu.present_frame(device);
+    }
+
+    pub fn set_access_locking(&mut self, enabled: bool) {
+        self.gpu.set_access_locking(enabled);
+    }
+
+    pub fn dropped_writes(&self) -> (u32, u32) {
+        self.gpu.dropped_writes()
+    }
+
     pub fn get_timer(&self) -> &Timer {
         &self.timer
     }
+
+    pub fn save_state(&mut self, state: &mut StateWriter) {
+        self.internal_ram.save_state(state);
+        self.high_ram.save_state(state);
+        state.write_u8(self.if_register);
+        state.write_u8(self.ie_register);
+
+        self.serial.save_state(state);
+
+        self.dma.save_state(state);
+        self.hdma.save_state(state);
+        state.write_u8(self.wram_bank);
+        state.write_bool(self.double_speed);
+        state.write_bool(self.speed_switch_armed);
+        state.write_u16(self.stall_cycles);
+        state.write_bool(self.stopped);
+
+        self.cartridge.save_state(state);
+        self.gpu.save_state(state);
+        self.apu.save_state(state);
+        self.timer.save_state(state);
+        self.gamepad.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.internal_ram.load_state(state)?;
+        self.high_ram.load_state(state)?;
+        self.if_register = state.read_u8()?;
+        self.ie_register = state.read_u8()?;
+
+        self.serial.load_state(state)?;
+
+        self.dma.load_state(state)?;
+        self.hdma.load_state(state)?;
+        self.wram_bank = state.read_u8()?;
+        if self.wram_bank == 0 || self.wram_bank > 7 {
+            return Err(state::invalid_state("Invalid WRAM bank"));
+        }
+        self.double_speed = state.read_bool()?;
+        self.speed_switch_armed = state.read_bool()?;
+        self.stall_cycles = state.read_u16()?;
+        self.stopped = state.read_bool()?;
+
+        self.cartridge.load_state(state)?;
+        self.gpu.load_state(state)?;
+        self.apu.load_state(state)?;
+        self.timer.load_state(state)?;
+        self.gamepad.load_state(state)
+    }
+
+    pub fn save_ram(&mut self) -> io::Result<()> {
+        self.cartridge.save_ram()
+    }
+
+    pub fn ram_dirty(&self) -> bool {
+        self.cartridge.ram_dirty()
+    }
 }
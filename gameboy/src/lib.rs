@@ -1,19 +1,31 @@
 extern crate strfmt;
 extern crate time;
 extern crate combine;
+extern crate byteorder;
+extern crate crc;
 
 pub mod vm;
 pub mod cartridge;
 pub mod cpu;
 pub mod interconnect;
 pub mod device;
+pub mod wav;
+pub mod model;
+pub mod serial;
+pub mod link;
+pub mod printer;
+pub mod png;
+pub mod rewind;
 
+mod bus;
 mod mem_map;
 mod memory;
 mod gpu;
 mod apu;
 mod timer;
+mod dma;
 mod opcodes;
 mod command;
 mod gamepad;
 mod interrupt;
+mod state;
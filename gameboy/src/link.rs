@@ -0,0 +1,229 @@
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::rc::Rc;
+use std::time::Duration;
+use serial::SerialPeer;
+
+const HANDSHAKE_MAGIC: &[u8; 4] = b"GBLK";
+const PROTOCOL_VERSION: u8 = 1;
+
+// Both sides swap a sync message every this many clocks, and wait for the
+// other's before running on
+const SYNC_CLOCKS: u32 = 1024;
+// If the other side's message takes longer than this it has probably stopped
+// running, in the debugger or while rewinding, so we run on and pick the
+// message up when it arrives
+const SYNC_TIMEOUT_MS: u64 = 100;
+const SYNC_MESSAGE_LENGTH: usize = 3;
+
+const SYNC_MASTER: u8 = 1 << 0;
+const SYNC_WAITING: u8 = 1 << 1;
+
+// A link cable to another emulator over TCP.
+//
+// The two sides run in lock step, swapping a message at each sync point with
+// the byte they've clocked out with their internal clock since the last one,
+// if any, and the byte they're waiting to have clocked by the other side, if
+// any. A side clocking a byte out gets the byte the other side was waiting
+// with at the last sync point, the other side picks the byte up at the next
+// one. Transfers finish up to a sync period late, but both sides always agree
+// on what was swapped.
+//
+// Only one message is in flight each way. While waiting for the other side's
+// message past the timeout the clock isn't counted towards the next sync
+// point, so a stalled side holds transfers up without freezing the other.
+pub struct TcpLink {
+    stream: Option<TcpStream>,
+    cycles: u32,
+    error: Rc<RefCell<Option<io::Error>>>,
+
+    // From the last message sent
+    sent_master: Option<u8>,
+    sent_waiting: bool,
+    offered: bool,
+    awaiting_reply: bool,
+    // Set once the reply has timed out, the stream is non-blocking until it
+    // arrives
+    stalled: bool,
+    // The part of the reply that's arrived
+    reply: [u8; SYNC_MESSAGE_LENGTH],
+    reply_length: usize,
+    // From the last message received
+    remote_waiting: Option<u8>,
+}
+
+impl TcpLink {
+    // Waits for the other side to connect
+    pub fn listen(port: u16) -> io::Result<TcpLink> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        TcpLink::accept(&listener)
+    }
+
+    pub fn accept(listener: &TcpListener) -> io::Result<TcpLink> {
+        let (stream, _) = listener.accept()?;
+        TcpLink::from_stream(stream)
+    }
+
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<TcpLink> {
+        let stream = TcpStream::connect(addr)?;
+        TcpLink::from_stream(stream)
+    }
+
+    fn from_stream(mut stream: TcpStream) -> io::Result<TcpLink> {
+        // Each message is tiny and the other side is waiting on it
+        stream.set_nodelay(true)?;
+
+        let mut handshake = [0; 5];
+        handshake[..4].copy_from_slice(HANDSHAKE_MAGIC);
+        handshake[4] = PROTOCOL_VERSION;
+        stream.write_all(&handshake)?;
+
+        let mut remote = [0; 5];
+        stream.read_exact(&mut remote)?;
+        if &remote[..4] != HANDSHAKE_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a Game Boy link"));
+        }
+        if remote[4] != PROTOCOL_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("Unsupported link protocol version {}",
+                                              remote[4])));
+        }
+        stream.set_read_timeout(Some(Duration::from_millis(SYNC_TIMEOUT_MS)))?;
+
+        Ok(TcpLink {
+            stream: Some(stream),
+            cycles: 0,
+            error: Rc::new(RefCell::new(None)),
+
+            sent_master: None,
+            sent_waiting: false,
+            offered: false,
+            awaiting_reply: false,
+            stalled: false,
+            reply: [0; SYNC_MESSAGE_LENGTH],
+            reply_length: 0,
+            remote_waiting: None,
+        })
+    }
+
+    // A handle to the error that disconnected the link, which is still usable
+    // once the link has been plugged in. After an error the link carries on
+    // as if the cable had been pulled out.
+    pub fn error(&self) -> Rc<RefCell<Option<io::Error>>> {
+        self.error.clone()
+    }
+
+    fn send(&mut self, master: Option<u8>, waiting: Option<u8>) -> io::Result<()> {
+        let stream = match self.stream {
+            Some(ref mut stream) => stream,
+            None => return Ok(()),
+        };
+
+        let mut flags = 0;
+        if master.is_some() {
+            flags |= SYNC_MASTER;
+        }
+        if waiting.is_some() {
+            flags |= SYNC_WAITING;
+        }
+        stream.write_all(&[flags, master.unwrap_or(0xff), waiting.unwrap_or(0xff)])?;
+        self.sent_waiting = waiting.is_some();
+        self.awaiting_reply = true;
+        Ok(())
+    }
+
+    // Reads what's arrived of the other side's message, returning the byte
+    // it clocked out since the last sync point and whether it was waiting at
+    // this one once the whole message is in
+    fn receive(&mut self) -> io::Result<Option<(Option<u8>, Option<u8>)>> {
+        let stream = match self.stream {
+            Some(ref mut stream) => stream,
+            None => {
+                self.awaiting_reply = false;
+                return Ok(Some((None, None)));
+            }
+        };
+
+        while self.reply_length < SYNC_MESSAGE_LENGTH {
+            match stream.read(&mut self.reply[self.reply_length..]) {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Link closed"));
+                }
+                Ok(length) => self.reply_length += length,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => {
+                    if !self.stalled {
+                        stream.set_nonblocking(true)?;
+                        self.stalled = true;
+                    }
+                    return Ok(None);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        if self.stalled {
+            stream.set_nonblocking(false)?;
+            self.stalled = false;
+        }
+
+        self.awaiting_reply = false;
+        self.reply_length = 0;
+        let message = self.reply;
+        let remote_master = if message[0] & SYNC_MASTER != 0 { Some(message[1]) } else { None };
+        let remote_waiting = if message[0] & SYNC_WAITING != 0 { Some(message[2]) } else { None };
+        Ok(Some((remote_master, remote_waiting)))
+    }
+
+    // Carry on as if the cable had been pulled out
+    fn disconnect(&mut self, error: io::Error) {
+        self.stream = None;
+        self.awaiting_reply = false;
+        *self.error.borrow_mut() = Some(error);
+    }
+}
+
+impl SerialPeer for TcpLink {
+    // The other side was waiting with this byte at the last sync point, if
+    // it wasn't the line is high
+    fn exchange(&mut self, data: u8) -> u8 {
+        self.sent_master = Some(data);
+        self.remote_waiting.take().unwrap_or(0xff)
+    }
+
+    fn external_clock(&mut self, cycles: u16, data: Option<u8>) -> Option<u8> {
+        if !self.awaiting_reply {
+            self.cycles += cycles as u32;
+            if self.cycles < SYNC_CLOCKS {
+                return None;
+            }
+            self.cycles -= SYNC_CLOCKS;
+
+            let master = self.sent_master.take();
+            if let Err(e) = self.send(master, data) {
+                self.disconnect(e);
+            }
+        }
+
+        let (remote_master, remote_waiting) = match self.receive() {
+            Ok(Some(message)) => message,
+            Ok(None) => return None,
+            Err(e) => {
+                self.disconnect(e);
+                (None, None)
+            }
+        };
+
+        // The other side only took our byte if we'd offered it at the last
+        // sync point
+        let offered = self.offered;
+        self.offered = self.sent_waiting;
+        self.remote_waiting = remote_waiting;
+
+        match remote_master {
+            Some(val) if offered && data.is_some() => Some(val),
+            _ => None,
+        }
+    }
+}
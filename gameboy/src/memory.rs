@@ -1,3 +1,6 @@
+use std::io;
+use state::{StateReader, StateWriter};
+
 pub struct Memory {
     mem: Box<[u8]>,
 }
@@ -14,4 +17,12 @@ impl Memory {
     pub fn write_byte(&mut self, addr: u16, val: u8) {
         self.mem[addr as usize] = val;
     }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.mem);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        state.read_bytes(&mut self.mem)
+    }
 }
@@ -0,0 +1,30 @@
+use std::fmt;
+use cartridge::Cartridge;
+
+// The hardware being emulated
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Model {
+    Dmg,
+    Cgb,
+}
+
+impl Model {
+    // Cartridges that support the CGB run in CGB mode, everything else runs
+    // on a DMG
+    pub fn detect(cartridge: &Cartridge) -> Model {
+        if cartridge.supports_cgb() {
+            Model::Cgb
+        } else {
+            Model::Dmg
+        }
+    }
+}
+
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Model::Dmg => write!(f, "DMG"),
+            Model::Cgb => write!(f, "CGB"),
+        }
+    }
+}
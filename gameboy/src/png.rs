@@ -0,0 +1,99 @@
+use std::io::{self, BufWriter, Write};
+use std::fs::File;
+use std::path::Path;
+use byteorder::{BigEndian, LittleEndian, WriteBytesExt};
+use crc::crc32::checksum_ieee;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+const BIT_DEPTH: u8 = 8;
+const COLOUR_TYPE_GREYSCALE: u8 = 0;
+// Longest block deflate can store without compressing it
+const STORED_BLOCK_LENGTH: usize = 0xffff;
+const ADLER_MODULUS: u32 = 65521;
+
+pub fn save_greyscale<P: AsRef<Path>>(file_name: P,
+                                      width: usize,
+                                      height: usize,
+                                      pixels: &[u8])
+                                      -> io::Result<()> {
+    let file = File::create(file_name)?;
+    let mut writer = BufWriter::new(file);
+    write_greyscale(&mut writer, width, height, pixels)?;
+    writer.flush()
+}
+
+// Writes an 8-bit greyscale PNG, one byte per pixel row by row. The image
+// data is stored uncompressed, which deflate allows for.
+pub fn write_greyscale<W: Write>(writer: &mut W,
+                                 width: usize,
+                                 height: usize,
+                                 pixels: &[u8])
+                                 -> io::Result<()> {
+    assert_eq!(width * height, pixels.len());
+
+    writer.write_all(&SIGNATURE)?;
+
+    let mut header = Vec::new();
+    header.write_u32::<BigEndian>(width as u32)?;
+    header.write_u32::<BigEndian>(height as u32)?;
+    header.write_u8(BIT_DEPTH)?;
+    header.write_u8(COLOUR_TYPE_GREYSCALE)?;
+    header.write_u8(0)?; // Deflate compression
+    header.write_u8(0)?; // Adaptive filtering
+    header.write_u8(0)?; // No interlacing
+    write_chunk(writer, b"IHDR", &header)?;
+
+    // Each row starts with its filter type, none here
+    let mut scanlines = Vec::with_capacity((width + 1) * height);
+    for row in pixels.chunks(width) {
+        scanlines.push(0);
+        scanlines.extend_from_slice(row);
+    }
+    write_chunk(writer, b"IDAT", &zlib_stored(&scanlines)?)?;
+
+    write_chunk(writer, b"IEND", &[])
+}
+
+fn write_chunk<W: Write>(writer: &mut W, chunk_type: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    writer.write_u32::<BigEndian>(data.len() as u32)?;
+
+    // The CRC covers the type as well as the data
+    let mut crc_data = Vec::with_capacity(data.len() + 4);
+    crc_data.extend_from_slice(chunk_type);
+    crc_data.extend_from_slice(data);
+    writer.write_all(&crc_data)?;
+    writer.write_u32::<BigEndian>(checksum_ieee(&crc_data))
+}
+
+// Wraps data in a zlib stream of stored deflate blocks
+fn zlib_stored(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut stream = Vec::with_capacity(data.len() + 16);
+    // Deflate with a 32K window, no preset dictionary, the check bits make
+    // the header a multiple of 31
+    stream.write_all(&[0x78, 0x01])?;
+
+    let mut blocks = data.chunks(STORED_BLOCK_LENGTH).peekable();
+    if blocks.peek().is_none() {
+        stream.write_all(&[0x01, 0x00, 0x00, 0xff, 0xff])?;
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        stream.write_u8(if last { 0x01 } else { 0x00 })?;
+        stream.write_u16::<LittleEndian>(block.len() as u16)?;
+        stream.write_u16::<LittleEndian>(!(block.len() as u16))?;
+        stream.write_all(block)?;
+    }
+
+    stream.write_u32::<BigEndian>(adler32(data))?;
+    Ok(stream)
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let mut a = 1;
+    let mut b = 0;
+    for byte in data {
+        a = (a + *byte as u32) % ADLER_MODULUS;
+        b = (b + a) % ADLER_MODULUS;
+    }
+    (b << 16) | a
+}
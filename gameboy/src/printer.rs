@@ -0,0 +1,312 @@
+use std::cell::RefCell;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use serial::SerialPeer;
+use png;
+
+const MAGIC: [u8; 2] = [0x88, 0x33];
+// Sent back in place of the first byte after the checksum
+const DEVICE_ID: u8 = 0x81;
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_STATUS: u8 = 0x0f;
+
+const STATUS_CHECKSUM_ERROR: u8 = 1 << 0;
+const STATUS_PRINTING: u8 = 1 << 1;
+const STATUS_IMAGE_FULL: u8 = 1 << 2;
+const STATUS_UNPROCESSED: u8 = 1 << 3;
+
+// The printer holds up to 9 DATA packets, each is two rows of 20 tiles
+const WIDTH: usize = 160;
+const TILES_PER_ROW: usize = WIDTH / 8;
+const TILE_LENGTH: usize = 16;
+const BUFFER_LENGTH: usize = 0x1680;
+// Each unit of margin feeds this many blank pixel rows of paper
+const MARGIN_ROWS: usize = 8;
+// Roughly how long the print head takes, the game polls the status until
+// it's done
+const PRINT_CLOCKS: u32 = 4194304;
+
+const SHADES: [u8; 4] = [0xff, 0xaa, 0x55, 0x00];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PacketState {
+    Magic(usize),
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    DeviceId,
+    Status,
+}
+
+// The Game Boy Printer.
+//
+// The game sends packets of a two byte magic number, a command, a
+// compression flag, a little endian data length, the data and a little
+// endian checksum of everything after the magic number. It then sends two
+// more bytes, the printer answers them with its ID and status. Printed
+// images are added to a page of paper, which is saved as a PNG once it's fed
+// out past the bottom margin.
+pub struct Printer {
+    output_dir: PathBuf,
+    pages_printed: u32,
+    saved_pages: Rc<RefCell<Vec<io::Result<PathBuf>>>>,
+
+    state: PacketState,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+
+    status: u8,
+    buffer: Vec<u8>, // Tile data waiting to be printed
+    page: Vec<u8>, // Shades of the printed rows on the current page
+    print_clocks: u32, // Clocks left until the current print finishes
+}
+
+impl Printer {
+    pub fn new<P: AsRef<Path>>(output_dir: P) -> Self {
+        Printer {
+            output_dir: output_dir.as_ref().to_path_buf(),
+            pages_printed: 0,
+            saved_pages: Rc::new(RefCell::new(Vec::new())),
+
+            state: PacketState::Magic(0),
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+
+            status: 0,
+            buffer: Vec::with_capacity(BUFFER_LENGTH),
+            page: Vec::new(),
+            print_clocks: 0,
+        }
+    }
+
+    // A handle to the result of saving each page, which is still usable once
+    // the printer has been plugged in. The frontend takes the results to
+    // report them.
+    pub fn saved_pages(&self) -> Rc<RefCell<Vec<io::Result<PathBuf>>>> {
+        self.saved_pages.clone()
+    }
+
+    // Returns the byte shifted back for the byte received
+    fn receive(&mut self, val: u8) -> u8 {
+        match self.state {
+            PacketState::Magic(i) => {
+                if val == MAGIC[i] {
+                    self.state = if i + 1 == MAGIC.len() {
+                        PacketState::Command
+                    } else {
+                        PacketState::Magic(i + 1)
+                    };
+                } else {
+                    // The second byte may be the start of a new packet
+                    self.state = PacketState::Magic(if val == MAGIC[0] { 1 } else { 0 });
+                }
+            }
+            PacketState::Command => {
+                self.command = val;
+                self.checksum = val as u16;
+                self.state = PacketState::Compression;
+            }
+            PacketState::Compression => {
+                self.compressed = val & 1 != 0;
+                self.checksum = self.checksum.wrapping_add(val as u16);
+                self.state = PacketState::LengthLow;
+            }
+            PacketState::LengthLow => {
+                self.length = val as u16;
+                self.checksum = self.checksum.wrapping_add(val as u16);
+                self.state = PacketState::LengthHigh;
+            }
+            PacketState::LengthHigh => {
+                self.length |= (val as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(val as u16);
+                self.data.clear();
+                self.state = if self.length == 0 {
+                    PacketState::ChecksumLow
+                } else {
+                    PacketState::Data
+                };
+            }
+            PacketState::Data => {
+                self.data.push(val);
+                self.checksum = self.checksum.wrapping_add(val as u16);
+                if self.data.len() == self.length as usize {
+                    self.state = PacketState::ChecksumLow;
+                }
+            }
+            PacketState::ChecksumLow => {
+                self.received_checksum = val as u16;
+                self.state = PacketState::ChecksumHigh;
+            }
+            PacketState::ChecksumHigh => {
+                self.received_checksum |= (val as u16) << 8;
+                self.run_command();
+                self.state = PacketState::DeviceId;
+            }
+            PacketState::DeviceId => {
+                self.state = PacketState::Status;
+                return DEVICE_ID;
+            }
+            PacketState::Status => {
+                self.state = PacketState::Magic(0);
+                return self.status;
+            }
+        }
+        0x00
+    }
+
+    fn run_command(&mut self) {
+        if self.checksum != self.received_checksum {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+
+        match self.command {
+            COMMAND_INIT => {
+                self.buffer.clear();
+                self.status = 0;
+            }
+            COMMAND_DATA => {
+                let data = if self.compressed {
+                    decompress(&self.data)
+                } else {
+                    self.data.clone()
+                };
+                let space = BUFFER_LENGTH - self.buffer.len();
+                self.buffer.extend(data.into_iter().take(space));
+
+                // An empty packet marks the end of the data
+                if !self.buffer.is_empty() {
+                    self.status |= STATUS_UNPROCESSED;
+                }
+                if self.buffer.len() == BUFFER_LENGTH {
+                    self.status |= STATUS_IMAGE_FULL;
+                }
+            }
+            COMMAND_PRINT if self.data.len() == 4 => {
+                let margins = self.data[1];
+                let palette = self.data[2];
+                self.print(margins >> 4, margins & 0xf, palette);
+                self.status &= !(STATUS_UNPROCESSED | STATUS_IMAGE_FULL);
+                self.status |= STATUS_PRINTING;
+                self.print_clocks = PRINT_CLOCKS;
+            }
+            COMMAND_STATUS => {} // Only asks for the status
+            _ => {}
+        }
+    }
+
+    // Adds the buffered image to the page, the page is saved once it's fed
+    // out by a bottom margin
+    fn print(&mut self, top_margin: u8, bottom_margin: u8, palette: u8) {
+        self.feed(top_margin);
+
+        let tile_rows = self.buffer.len() / (TILES_PER_ROW * TILE_LENGTH);
+        for y in 0..tile_rows * 8 {
+            for x in 0..WIDTH {
+                let tile = ((y / 8) * TILES_PER_ROW) + (x / 8);
+                let offset = (tile * TILE_LENGTH) + ((y % 8) * 2);
+                let lower = (self.buffer[offset] >> (7 - (x % 8))) & 1;
+                let upper = (self.buffer[offset + 1] >> (7 - (x % 8))) & 1;
+                let colour = (upper << 1) | lower;
+                let shade = (palette >> (colour * 2)) & 0x3;
+                self.page.push(SHADES[shade as usize]);
+            }
+        }
+        self.buffer.clear();
+
+        if bottom_margin > 0 {
+            self.feed(bottom_margin);
+            self.save_page();
+        }
+    }
+
+    fn feed(&mut self, margin: u8) {
+        let rows = margin as usize * MARGIN_ROWS;
+        let length = self.page.len() + (rows * WIDTH);
+        self.page.resize(length, SHADES[0]);
+    }
+
+    fn save_page(&mut self) {
+        if self.page.is_empty() {
+            return;
+        }
+
+        self.pages_printed += 1;
+        let file_name = self.output_dir.join(format!("print_{:04}.png", self.pages_printed));
+        let height = self.page.len() / WIDTH;
+        let result = match png::save_greyscale(&file_name, WIDTH, height, &self.page) {
+            Ok(()) => Ok(file_name),
+            Err(e) => Err(io::Error::new(e.kind(), format!("{}: {}", file_name.display(), e))),
+        };
+        self.saved_pages.borrow_mut().push(result);
+        self.page.clear();
+    }
+}
+
+// Whatever is left on the page comes out when the printer is switched off
+impl Drop for Printer {
+    fn drop(&mut self) {
+        self.save_page();
+    }
+}
+
+// Runs of a byte are stored as the run length minus 2 with bit 7 set followed
+// by the byte, other bytes are stored as the count minus 1 followed by the
+// bytes
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+        if control & 0x80 != 0 {
+            let length = (control & 0x7f) as usize + 2;
+            if let Some(val) = data.get(i) {
+                for _ in 0..length {
+                    output.push(*val);
+                }
+            }
+            i += 1;
+        } else {
+            let length = control as usize + 1;
+            let end = (i + length).min(data.len());
+            output.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+    output
+}
+
+impl SerialPeer for Printer {
+    fn exchange(&mut self, data: u8) -> u8 {
+        self.receive(data)
+    }
+
+    // The printer never drives the clock, it's only used to time printing
+    fn external_clock(&mut self, cycles: u16, _: Option<u8>) -> Option<u8> {
+        if self.print_clocks > 0 {
+            self.print_clocks = self.print_clocks.saturating_sub(cycles as u32);
+            if self.print_clocks == 0 {
+                self.status &= !STATUS_PRINTING;
+            }
+        }
+        None
+    }
+}
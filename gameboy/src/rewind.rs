@@ -0,0 +1,147 @@
+use std::collections::VecDeque;
+
+// Rewind history, a bounded list of save states from oldest to newest.
+//
+// Only the newest state is kept whole, each older state is stored as the
+// difference from the state after it. Consecutive states mostly match so the
+// difference is mostly zeros, which are run length encoded away.
+pub struct Rewind {
+    newest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+
+    memory_limit: usize,
+    memory_used: usize,
+}
+
+impl Rewind {
+    pub fn new(memory_limit: usize) -> Self {
+        Rewind {
+            newest: None,
+            deltas: VecDeque::new(),
+
+            memory_limit: memory_limit,
+            memory_used: 0,
+        }
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(previous) = self.newest.take() {
+            self.memory_used -= previous.len();
+            // A state with a different layout can't be diffed, so the older
+            // history can't be restored past it
+            if previous.len() == state.len() {
+                let delta = encode_delta(&previous, &state);
+                self.memory_used += delta.len();
+                self.deltas.push_back(delta);
+            } else {
+                self.clear();
+            }
+        }
+
+        self.memory_used += state.len();
+        self.newest = Some(state);
+
+        while self.memory_used > self.memory_limit && !self.deltas.is_empty() {
+            let oldest = self.deltas.pop_front().unwrap();
+            self.memory_used -= oldest.len();
+        }
+    }
+
+    // Removes and returns the newest state, the one before it becomes the
+    // newest
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let newest = self.newest.take()?;
+        self.memory_used -= newest.len();
+
+        if let Some(delta) = self.deltas.pop_back() {
+            self.memory_used -= delta.len();
+            let previous = decode_delta(&newest, &delta);
+            self.memory_used += previous.len();
+            self.newest = Some(previous);
+        }
+
+        Some(newest)
+    }
+
+    pub fn len(&self) -> usize {
+        self.deltas.len() + if self.newest.is_some() { 1 } else { 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    pub fn memory_used(&self) -> usize {
+        self.memory_used
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+        self.memory_used = 0;
+    }
+}
+
+// The delta is the two states XORed together, encoded as pairs of a run of
+// zero bytes followed by a run of literal bytes. Each run length is a LEB128
+// style variable length integer.
+fn encode_delta(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    let mut i = 0;
+    while i < old.len() {
+        let zeros_start = i;
+        while i < old.len() && old[i] == new[i] {
+            i += 1;
+        }
+        let literal_start = i;
+        while i < old.len() && old[i] != new[i] {
+            i += 1;
+        }
+
+        write_length(&mut delta, literal_start - zeros_start);
+        write_length(&mut delta, i - literal_start);
+        for j in literal_start..i {
+            delta.push(old[j] ^ new[j]);
+        }
+    }
+    delta
+}
+
+fn decode_delta(new: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut old = new.to_vec();
+    let mut offset = 0;
+    let mut i = 0;
+    while i < delta.len() {
+        offset += read_length(delta, &mut i);
+        let literal_length = read_length(delta, &mut i);
+        for _ in 0..literal_length {
+            old[offset] ^= delta[i];
+            offset += 1;
+            i += 1;
+        }
+    }
+    old
+}
+
+fn write_length(buffer: &mut Vec<u8>, length: usize) {
+    let mut length = length;
+    while length >= 0x80 {
+        buffer.push((length as u8) | 0x80);
+        length >>= 7;
+    }
+    buffer.push(length as u8);
+}
+
+fn read_length(buffer: &[u8], index: &mut usize) -> usize {
+    let mut length = 0;
+    let mut shift = 0;
+    loop {
+        let byte = buffer[*index];
+        *index += 1;
+        length |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return length;
+        }
+        shift += 7;
+    }
+}
@@ -0,0 +1,213 @@
+use std::io::{self, Write};
+use std::cell::RefCell;
+use std::rc::Rc;
+use interrupt::{Irq, Interrupt};
+use state::{StateReader, StateWriter};
+
+// The internal clock shifts a bit on each falling edge of these bits of the
+// timer's divider, 8192Hz normally or 262144Hz with the CGB's fast clock
+const CLOCK_DIV_BIT: u16 = 1 << 8;
+const FAST_CLOCK_DIV_BIT: u16 = 1 << 3;
+
+// Something plugged into the link port.
+//
+// Whichever side uses its internal clock drives the transfer, the other side
+// has its bits shifted by the external clock. Bytes are swapped whole, once
+// all eight bits have been clocked.
+pub trait SerialPeer {
+    // Called when this Game Boy has clocked out a whole byte, returns the
+    // byte shifted in from the peer
+    fn exchange(&mut self, data: u8) -> u8;
+
+    // Called every step with the clocks run, so the peer can drive the
+    // clock itself. data holds the byte waiting to be shifted out if a
+    // transfer using the external clock has been started. Returns the byte
+    // shifted in if the peer has clocked a whole byte.
+    fn external_clock(&mut self, cycles: u16, data: Option<u8>) -> Option<u8>;
+}
+
+// Nothing connected, the input line is pulled high and there's no external
+// clock
+pub struct Disconnected;
+
+impl SerialPeer for Disconnected {
+    fn exchange(&mut self, _: u8) -> u8 {
+        0xff
+    }
+
+    fn external_clock(&mut self, _: u16, _: Option<u8>) -> Option<u8> {
+        None
+    }
+}
+
+pub struct Serial {
+    peer: Box<SerialPeer>,
+    cgb_mode: bool,
+
+    data: u8, // 0xff01 - SB
+    control: u8, // 0xff02 - SC, transfer start, clock speed and clock select
+
+    bits: u8, // Bits clocked so far in the current transfer
+    clock_bit: bool, // Last value of the divider bit driving the clock
+}
+
+impl Serial {
+    pub fn new(cgb_mode: bool) -> Self {
+        Serial {
+            peer: Box::new(Disconnected),
+            cgb_mode: cgb_mode,
+
+            data: 0,
+            control: 0,
+
+            bits: 0,
+            clock_bit: false,
+        }
+    }
+
+    pub fn set_peer(&mut self, peer: Box<SerialPeer>) {
+        self.peer = peer;
+    }
+
+    pub fn read_reg(&self, addr: u16) -> u8 {
+        match addr {
+            0xff01 => self.data,
+            // The clock speed bit only exists on the CGB
+            0xff02 if self.cgb_mode => self.control | 0x7c,
+            0xff02 => self.control | 0x7e,
+            _ => 0xff,
+        }
+    }
+
+    pub fn write_reg(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xff01 => self.data = val,
+            0xff02 => {
+                self.control = val & if self.cgb_mode { 0x83 } else { 0x81 };
+                if self.transfer_started() {
+                    self.bits = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // The divider is the timer's internal counter before it's stepped
+    pub fn step(&mut self, cycles: u16, divider: u16, irq: &mut Irq) {
+        let clock_div_bit = if self.control & 0x2 != 0 {
+            FAST_CLOCK_DIV_BIT
+        } else {
+            CLOCK_DIV_BIT
+        };
+
+        for i in 0..cycles {
+            let clock_bit = divider.wrapping_add(i + 1) & clock_div_bit != 0;
+            let falling_edge = self.clock_bit && !clock_bit;
+            self.clock_bit = clock_bit;
+
+            if falling_edge && self.transfer_started() && self.internal_clock() {
+                self.bits += 1;
+                if self.bits == 8 {
+                    self.data = self.peer.exchange(self.data);
+                    self.finish_transfer(irq);
+                }
+            }
+        }
+
+        let waiting = if self.transfer_started() && !self.internal_clock() {
+            Some(self.data)
+        } else {
+            None
+        };
+        if let Some(val) = self.peer.external_clock(cycles, waiting) {
+            if waiting.is_some() {
+                self.data = val;
+                self.finish_transfer(irq);
+            }
+        }
+    }
+
+    fn transfer_started(&self) -> bool {
+        self.control & 0x80 != 0
+    }
+
+    fn internal_clock(&self) -> bool {
+        self.control & 0x1 != 0
+    }
+
+    fn finish_transfer(&mut self, irq: &mut Irq) {
+        self.control &= !0x80;
+        self.bits = 0;
+        irq.raise_interrupt(Interrupt::SerialIO);
+    }
+
+    // The peer isn't part of the state, it's whatever is plugged in now
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.data);
+        state.write_u8(self.control);
+
+        state.write_u8(self.bits);
+        state.write_bool(self.clock_bit);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.data = state.read_u8()?;
+        self.control = state.read_u8()? & 0x83;
+
+        self.bits = state.read_u8()? % 8;
+        self.clock_bit = state.read_bool()?;
+        Ok(())
+    }
+}
+
+// Collects the bytes sent with the internal clock, test ROMs print their
+// results this way. Answers as if nothing were connected.
+pub struct SerialSink {
+    output: Rc<RefCell<Vec<u8>>>,
+    echo: bool,
+}
+
+impl SerialSink {
+    pub fn new() -> Self {
+        SerialSink {
+            output: Rc::new(RefCell::new(Vec::new())),
+            echo: false,
+        }
+    }
+
+    // Also prints each byte to stdout as it arrives
+    pub fn with_echo() -> Self {
+        SerialSink {
+            output: Rc::new(RefCell::new(Vec::new())),
+            echo: true,
+        }
+    }
+
+    // A handle to the bytes received so far, which is still usable once the
+    // sink has been plugged in
+    pub fn output(&self) -> Rc<RefCell<Vec<u8>>> {
+        self.output.clone()
+    }
+}
+
+impl Default for SerialSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SerialPeer for SerialSink {
+    fn exchange(&mut self, data: u8) -> u8 {
+        self.output.borrow_mut().push(data);
+        if self.echo {
+            let mut stdout = io::stdout();
+            let _ = stdout.write_all(&[data]);
+            let _ = stdout.flush();
+        }
+        0xff
+    }
+
+    fn external_clock(&mut self, _: u16, _: Option<u8>) -> Option<u8> {
+        None
+    }
+}
@@ -0,0 +1,122 @@
+use std::io;
+use byteorder::{ByteOrder, LittleEndian};
+
+// Serializes machine state into a flat little endian buffer, each component
+// writes its fields in a fixed order and reads them back in the same order
+pub struct StateWriter {
+    buffer: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter { buffer: Vec::new() }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buffer
+    }
+
+    pub fn write_u8(&mut self, val: u8) {
+        self.buffer.push(val);
+    }
+
+    pub fn write_bool(&mut self, val: bool) {
+        self.write_u8(if val { 1 } else { 0 });
+    }
+
+    pub fn write_u16(&mut self, val: u16) {
+        let mut bytes = [0; 2];
+        LittleEndian::write_u16(&mut bytes, val);
+        self.buffer.extend_from_slice(&bytes);
+    }
+
+    pub fn write_u32(&mut self, val: u32) {
+        let mut bytes = [0; 4];
+        LittleEndian::write_u32(&mut bytes, val);
+        self.buffer.extend_from_slice(&bytes);
+    }
+
+    pub fn write_u64(&mut self, val: u64) {
+        let mut bytes = [0; 8];
+        LittleEndian::write_u64(&mut bytes, val);
+        self.buffer.extend_from_slice(&bytes);
+    }
+
+    pub fn write_f32(&mut self, val: f32) {
+        let mut bytes = [0; 4];
+        LittleEndian::write_f32(&mut bytes, val);
+        self.buffer.extend_from_slice(&bytes);
+    }
+
+    // Blocks are length prefixed so a mismatched size is caught on load
+    pub fn write_bytes(&mut self, val: &[u8]) {
+        self.write_u32(val.len() as u32);
+        self.buffer.extend_from_slice(val);
+    }
+}
+
+pub struct StateReader<'a> {
+    buffer: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(buffer: &'a [u8]) -> Self {
+        StateReader {
+            buffer: buffer,
+            offset: 0,
+        }
+    }
+
+    pub fn read_u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> io::Result<bool> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> io::Result<u16> {
+        Ok(LittleEndian::read_u16(self.take(2)?))
+    }
+
+    pub fn read_u32(&mut self) -> io::Result<u32> {
+        Ok(LittleEndian::read_u32(self.take(4)?))
+    }
+
+    pub fn read_u64(&mut self) -> io::Result<u64> {
+        Ok(LittleEndian::read_u64(self.take(8)?))
+    }
+
+    pub fn read_f32(&mut self) -> io::Result<f32> {
+        Ok(LittleEndian::read_f32(self.take(4)?))
+    }
+
+    // Fills a block written by write_bytes, which must have the same length
+    pub fn read_bytes(&mut self, val: &mut [u8]) -> io::Result<()> {
+        let length = self.read_u32()? as usize;
+        if length != val.len() {
+            return Err(invalid_state("Block length doesn't match"));
+        }
+        val.copy_from_slice(self.take(length)?);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offset == self.buffer.len()
+    }
+
+    fn take(&mut self, length: usize) -> io::Result<&'a [u8]> {
+        if self.offset + length > self.buffer.len() {
+            return Err(invalid_state("Unexpected end of state"));
+        }
+
+        let ret = &self.buffer[self.offset..self.offset + length];
+        self.offset += length;
+        Ok(ret)
+    }
+}
+
+pub fn invalid_state(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
@@ -1,5 +1,7 @@
+use std::io;
 use device::Device;
 use interrupt::{Irq, Interrupt};
+use state::{StateReader, StateWriter};
 
 pub struct Timer {
     pub divider: u16,
@@ -77,6 +79,27 @@ impl Timer {
         self.timer_clock_select | (enabled << 2)
     }
 
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.divider);
+        state.write_u8(self.timer_counter);
+        state.write_u8(self.timer_modulo);
+        state.write_u8(self.timer_clock_select);
+        state.write_bool(self.timer_enable);
+        state.write_u8(self.tac_edge_delay);
+        state.write_u8(self.tac_reload_delay);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.divider = state.read_u16()?;
+        self.timer_counter = state.read_u8()?;
+        self.timer_modulo = state.read_u8()?;
+        self.timer_clock_select = state.read_u8()?;
+        self.timer_enable = state.read_bool()?;
+        self.tac_edge_delay = state.read_u8()?;
+        self.tac_reload_delay = state.read_u8()?;
+        Ok(())
+    }
+
     fn divider_change(&mut self) -> bool {
         let new_delay = if !self.timer_enable {
             0
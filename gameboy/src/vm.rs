@@ -1,10 +1,15 @@
 use std::thread;
 use std::sync::mpsc::{channel, Receiver};
-use std::io::{stdin, stdout, Write};
+use std::io::{self, stdin, stdout, Write};
 use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
 use interconnect::Interconnect;
+use model::Model;
 use cpu::Cpu;
-use device::Device;
+use device::{Device, Key};
+use rewind::Rewind;
+use state::{self, StateReader, StateWriter};
 use time::{self, SteadyTime};
 use command::*;
 use opcodes::*;
@@ -12,6 +17,21 @@ use opcodes::*;
 // The Game Boy runs at 4194304 Hz which is 8192 clocks every 1953125 nanoseconds
 const SYNC_PERIOD_NS: i64 = 1953125;
 const SYNC_PERIOD_CLOCKS: i64 = 8192;
+// Battery backed RAM is flushed to disk at most this often while running
+const SAVE_PERIOD_CLOCKS: i64 = 4194304 * 5;
+// A frame is 154 lines of 456 clocks, which takes 16742706 nanoseconds
+const FRAME_CLOCKS: u32 = 70224;
+const FRAME_NS: i64 = 16742706;
+
+// Save states start with a magic number and a version, which is bumped
+// whenever the layout of the state changes
+const STATE_MAGIC: &[u8; 4] = b"GBST";
+const STATE_VERSION: u32 = 9;
+
+// Pressing one of the save keys stores a state in the matching slot, the load
+// keys restore it
+const SAVE_STATE_KEYS: [Key; 4] = [Key::F1, Key::F2, Key::F3, Key::F4];
+const LOAD_STATE_KEYS: [Key; 4] = [Key::F5, Key::F6, Key::F7, Key::F8];
 
 #[derive(PartialEq, Eq, Debug)]
 enum Mode {
@@ -30,6 +50,14 @@ pub struct VM {
     cursor: u16,
     last_command: Option<Command>,
     stdin_receiver: Receiver<String>,
+
+    state_path: Option<PathBuf>,
+    state_keys_down: [bool; 8],
+
+    rewind: Option<Rewind>,
+    rewind_period_clocks: u32,
+    rewind_period_ns: i64,
+    rewind_cycles: u32,
 }
 
 impl VM {
@@ -37,9 +65,12 @@ impl VM {
         let (stdin_sender, stdin_receiver) = channel();
 
         // Blocking stdin means it's impossible to join this thread, so we let
-        // the OS clean it up when we quit.
-        thread::spawn(move || loop {
-                          stdin_sender.send(read_stdin()).unwrap();
+        // the OS clean it up when we quit. It stops at the end of stdin, which
+        // is straight away when running headless without a terminal.
+        thread::spawn(move || while let Some(line) = read_stdin() {
+                          if stdin_sender.send(line).is_err() {
+                              break;
+                          }
                       });
 
         let mut cpu = Cpu::new();
@@ -50,7 +81,11 @@ impl VM {
             // Set the registers up as if we'd run the boot rom
             // TODO -the values don't match the reference, check once the cpu is
             // working
-            cpu.a = 0x00;
+            // Games check A to tell whether they're running on a CGB
+            cpu.a = match interconnect.model() {
+                Model::Dmg => 0x00,
+                Model::Cgb => 0x11,
+            };
             cpu.f = 0x00.into();
             cpu.set_bc(0x0000);
             cpu.set_de(0x0000);
@@ -59,6 +94,8 @@ impl VM {
             interconnect.write_byte(0xff05, 0x00);
             interconnect.write_byte(0xff06, 0x00);
             interconnect.write_byte(0xff07, 0x00);
+            // The APU must be powered on before its registers can be written
+            interconnect.write_byte(0xff26, 0xf1);
             interconnect.write_byte(0xff10, 0x80);
             interconnect.write_byte(0xff11, 0xbf);
             interconnect.write_byte(0xff12, 0xf3);
@@ -76,7 +113,6 @@ impl VM {
             interconnect.write_byte(0xff23, 0xbf);
             interconnect.write_byte(0xff24, 0x77);
             interconnect.write_byte(0xff25, 0xf3);
-            interconnect.write_byte(0xff26, 0xf1);
             interconnect.write_byte(0xff40, 0x91);
             interconnect.write_byte(0xff42, 0x00);
             interconnect.write_byte(0xff43, 0x00);
@@ -102,6 +138,14 @@ impl VM {
             cursor: cursor,
             last_command: None,
             stdin_receiver: stdin_receiver,
+
+            state_path: None,
+            state_keys_down: [false; 8],
+
+            rewind: None,
+            rewind_period_clocks: 0,
+            rewind_period_ns: 0,
+            rewind_cycles: 0,
         };
         if vm.mode == Mode::Debugging {
             vm.disassemble_instruction();
@@ -111,18 +155,123 @@ impl VM {
     }
 
     pub fn step(&mut self, device: &mut Device) -> (u16, bool) {
-        let cycles = self.cpu.step(&mut self.inter);
+        let mut cycles = self.cpu.step(&mut self.inter, device);
+
+        let stall_cycles = self.inter.take_stall_cycles();
+        if stall_cycles > 0 {
+            self.inter.step(stall_cycles, device);
+            cycles += stall_cycles;
+        }
+        let start_debugger = self.inter.take_watchpoint_trigger();
 
-        let start_debugger = self.inter.step(cycles, device);
+        // The rest of the machine runs at the normal speed, so in double
+        // speed mode the CPU's clocks only take half as long
+        if self.inter.double_speed() {
+            cycles /= 2;
+        }
         let breakpoint = self.breakpoints.contains(&self.cpu.pc);
 
+        self.record_rewind(cycles);
+
 
         (cycles, start_debugger || breakpoint)
     }
 
+    pub fn read_byte(&self, addr: u16) -> u8 {
+        self.inter.read_byte(addr)
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn save_state(&mut self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        for b in STATE_MAGIC {
+            state.write_u8(*b);
+        }
+        state.write_u32(STATE_VERSION);
+
+        self.save_machine(&mut state);
+        state.into_bytes()
+    }
+
+    // A state that fails to load leaves the machine as it was
+    pub fn load_state(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut state = StateReader::new(data);
+        for b in STATE_MAGIC {
+            if state.read_u8()? != *b {
+                return Err(state::invalid_state("Not a save state"));
+            }
+        }
+        if state.read_u32()? != STATE_VERSION {
+            return Err(state::invalid_state("Unsupported save state version"));
+        }
+
+        // The cartridge header and the length of the state are only checked
+        // part way through, so keep a copy of the current state to go back to
+        let mut backup = StateWriter::new();
+        self.save_machine(&mut backup);
+        if let Err(e) = self.load_machine(&mut state) {
+            let backup = backup.into_bytes();
+            self.load_machine(&mut StateReader::new(&backup))
+                .expect("Failed to restore the previous state");
+            return Err(e);
+        }
+
+        self.cursor = self.cpu.pc;
+        Ok(())
+    }
+
+    fn save_machine(&mut self, state: &mut StateWriter) {
+        self.cpu.save_state(state);
+        self.inter.save_state(state);
+    }
+
+    fn load_machine(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.cpu.load_state(state)?;
+        self.inter.load_state(state)?;
+        if !state.is_empty() {
+            return Err(state::invalid_state("Unexpected data at end of state"));
+        }
+        Ok(())
+    }
+
+    // Quick save slots are stored next to the ROM as <rom>.state1 etc.
+    pub fn set_state_path<P: AsRef<Path>>(&mut self, rom_file: P) {
+        self.state_path = Some(rom_file.as_ref().to_path_buf());
+    }
+
+    // Keeps a snapshot every frame_interval frames for rewinding, the oldest
+    // are dropped to stay within memory_limit bytes
+    pub fn enable_rewind(&mut self, memory_limit: usize, frame_interval: u32) {
+        self.rewind = Some(Rewind::new(memory_limit));
+        self.rewind_period_clocks = frame_interval * FRAME_CLOCKS;
+        self.rewind_period_ns = frame_interval as i64 * FRAME_NS;
+        self.rewind_cycles = self.rewind_period_clocks;
+    }
+
+    // Restores the most recent rewind snapshot, which is then dropped from the
+    // history. Returns false if there's nothing left to rewind to.
+    pub fn step_back(&mut self, device: &mut Device) -> bool {
+        let state = match self.rewind.as_mut().and_then(|r| r.pop()) {
+            Some(state) => state,
+            None => return false,
+        };
+
+        if let Err(e) = self.load_state(&state) {
+            eprintln!("Failed to rewind: {}", e);
+            return false;
+        }
+        self.rewind_cycles = 0;
+        self.inter.present_frame(device);
+        true
+    }
+
     pub fn run(&mut self, device: &mut Device) {
         let mut nsecs_elapsed = 0;
         let mut cycles_to_run = 0;
+        let mut cycles_since_save = 0;
 
         while device.running() {
             match self.mode {
@@ -132,22 +281,40 @@ impl VM {
                     nsecs_elapsed += elapsed.num_nanoseconds().expect("Loop took too long");
                     self.start_time = now;
 
-                    while device.running() && nsecs_elapsed > SYNC_PERIOD_NS {
-                        cycles_to_run += SYNC_PERIOD_CLOCKS;
-                        while device.running() && cycles_to_run > 0 {
-                            let (cycles_run, start_debugger) = self.step(device);
-                            if start_debugger {
-                                self.mode = Mode::Debugging;
-                                cycles_to_run = 0;
-                                self.cursor = self.cpu.pc;
-                                self.print_cursor();
-                                nsecs_elapsed = 0;
-                                break;
+                    // Play backwards while rewind is held, at the speed the
+                    // snapshots were taken
+                    if device.key_down(Key::R) && self.can_rewind() {
+                        while nsecs_elapsed > self.rewind_period_ns {
+                            self.step_back(device);
+                            nsecs_elapsed -= self.rewind_period_ns;
+                        }
+                        device.update();
+                    } else {
+                        while device.running() && nsecs_elapsed > SYNC_PERIOD_NS {
+                            cycles_to_run += SYNC_PERIOD_CLOCKS;
+                            while device.running() && cycles_to_run > 0 {
+                                let (cycles_run, start_debugger) = self.step(device);
+                                if start_debugger {
+                                    self.mode = Mode::Debugging;
+                                    cycles_to_run = 0;
+                                    self.cursor = self.cpu.pc;
+                                    self.print_cursor();
+                                    nsecs_elapsed = 0;
+                                    break;
+                                }
+                                cycles_to_run -= cycles_run as i64;
+                                cycles_since_save += cycles_run as i64;
+                                device.update();
                             }
-                            cycles_to_run -= cycles_run as i64;
-                            device.update();
+                            nsecs_elapsed -= SYNC_PERIOD_NS;
                         }
-                        nsecs_elapsed -= SYNC_PERIOD_NS;
+                    }
+
+                    self.check_state_keys(device);
+
+                    if cycles_since_save > SAVE_PERIOD_CLOCKS && self.inter.ram_dirty() {
+                        self.save_ram();
+                        cycles_since_save = 0;
                     }
                 }
                 Mode::Debugging => {
@@ -161,6 +328,95 @@ impl VM {
 
             thread::sleep(time::Duration::milliseconds(3).to_std().unwrap());
         }
+
+        self.save_ram();
+    }
+
+    // Runs the given number of frames as fast as possible, without keeping to
+    // real time or taking debugger commands
+    pub fn run_frames(&mut self, device: &mut Device, frames: u32) {
+        let mut cycles_to_run = frames as i64 * FRAME_CLOCKS as i64;
+        while device.running() && cycles_to_run > 0 {
+            let (cycles_run, _) = self.step(device);
+            cycles_to_run -= cycles_run as i64;
+            device.update();
+        }
+
+        self.save_ram();
+    }
+
+    pub fn save_ram(&mut self) {
+        if let Err(e) = self.inter.save_ram() {
+            eprintln!("Failed to save cartridge RAM: {}", e);
+        }
+    }
+
+    fn can_rewind(&self) -> bool {
+        self.rewind.as_ref().map_or(false, |r| !r.is_empty())
+    }
+
+    fn record_rewind(&mut self, cycles: u16) {
+        if self.rewind.is_none() {
+            return;
+        }
+
+        self.rewind_cycles += cycles as u32;
+        if self.rewind_cycles >= self.rewind_period_clocks {
+            self.rewind_cycles = 0;
+            let state = self.save_state();
+            if let Some(ref mut rewind) = self.rewind {
+                rewind.push(state);
+            }
+        }
+    }
+
+    fn check_state_keys(&mut self, device: &mut Device) {
+        for slot in 0..SAVE_STATE_KEYS.len() {
+            if self.state_key_pressed(device, SAVE_STATE_KEYS[slot], slot) {
+                self.save_state_slot(slot + 1);
+            }
+            let index = slot + SAVE_STATE_KEYS.len();
+            if self.state_key_pressed(device, LOAD_STATE_KEYS[slot], index) {
+                self.load_state_slot(slot + 1);
+            }
+        }
+    }
+
+    // Only act on the key going down, not while it's held
+    fn state_key_pressed(&mut self, device: &Device, key: Key, index: usize) -> bool {
+        let down = device.key_down(key);
+        let pressed = down && !self.state_keys_down[index];
+        self.state_keys_down[index] = down;
+        pressed
+    }
+
+    fn state_slot_path(&self, slot: usize) -> Option<PathBuf> {
+        self.state_path.as_ref().map(|p| p.with_extension(format!("state{}", slot)))
+    }
+
+    fn save_state_slot(&mut self, slot: usize) {
+        let path = match self.state_slot_path(slot) {
+            Some(path) => path,
+            None => return,
+        };
+
+        let state = self.save_state();
+        match fs::write(&path, &state) {
+            Ok(()) => eprintln!("Saved state {}", slot),
+            Err(e) => eprintln!("Failed to save state {}: {}", slot, e),
+        }
+    }
+
+    fn load_state_slot(&mut self, slot: usize) {
+        let path = match self.state_slot_path(slot) {
+            Some(path) => path,
+            None => return,
+        };
+
+        match fs::read(&path).and_then(|data| self.load_state(&data)) {
+            Ok(()) => eprintln!("Loaded state {}", slot),
+            Err(e) => eprintln!("Failed to load state {}: {}", slot, e),
+        }
     }
 
     #[cfg_attr(feature = "cargo-clippy", allow(match_same_arms))]
@@ -181,6 +437,9 @@ impl VM {
                     println!("DE: {:04x}", self.cpu.de());
                     println!("HL: {:04x}", self.cpu.hl());
                     println!("SP: {:04x}", self.cpu.sp);
+                    if self.cpu.locked_up {
+                        println!("The CPU has locked up on an illegal instruction");
+                    }
                 }
                 Ok(Command::ShowIORegs) => {
                     println!("Total Cycles: {}", self.cpu.total_cycles);
@@ -191,6 +450,9 @@ impl VM {
                              timer.divider, timer.timer_counter, timer.timer_modulo, timer.timer_control());
                     println!("Interrupts:");
                     println!("IE: {:02x}, IF: {:02x}", self.inter.ie_register, self.inter.if_register);
+                    let (vram_writes, oam_writes) = self.inter.dropped_writes();
+                    println!("Dropped writes:");
+                    println!("VRAM: {}, OAM: {}", vram_writes, oam_writes);
                 }
                 Ok(Command::Step(count)) => {
                     for _ in 0..count {
@@ -199,6 +461,20 @@ impl VM {
                         self.disassemble_instruction();
                     }
                 }
+                Ok(Command::StepBack(count)) => {
+                    let mut stepped = 0;
+                    while stepped < count && self.step_back(device) {
+                        stepped += 1;
+                    }
+                    if stepped < count {
+                        println!("Rewind history exhausted after {} snapshots", stepped);
+                    }
+                    if let Some(ref rewind) = self.rewind {
+                        println!("{} snapshots left, using {} KiB",
+                                 rewind.len(), rewind.memory_used() / 1024);
+                    }
+                    self.disassemble_instruction();
+                }
                 Ok(Command::Continue) => {
                     self.mode = Mode::Running;
                     self.start_time = SteadyTime::now();
@@ -300,8 +576,11 @@ impl VM {
 }
 
 
-fn read_stdin() -> String {
+// Returns None at the end of stdin
+fn read_stdin() -> Option<String> {
     let mut input = String::new();
-    stdin().read_line(&mut input).unwrap();
-    input.trim().into()
+    match stdin().read_line(&mut input) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(input.trim().into()),
+    }
 }
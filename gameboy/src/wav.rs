@@ -0,0 +1,73 @@
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::fs::File;
+use std::path::Path;
+use byteorder::{LittleEndian, WriteBytesExt};
+
+const CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: u16 = 16;
+const HEADER_LENGTH: u32 = 44;
+
+// Writes interleaved stereo samples to a 16-bit PCM WAV file
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    data_length: u32,
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(file_name: P, sample_rate: u32) -> io::Result<Self> {
+        let file = File::create(file_name)?;
+        WavWriter::new(BufWriter::new(file), sample_rate)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(writer: W, sample_rate: u32) -> io::Result<Self> {
+        let mut wav = WavWriter {
+            writer: writer,
+            data_length: 0,
+        };
+        wav.write_header(sample_rate)?;
+        Ok(wav)
+    }
+
+    // Samples are interleaved left and right pairs in the range -1.0 to 1.0
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for sample in samples {
+            let clamped = sample.max(-1.0).min(1.0);
+            self.writer.write_i16::<LittleEndian>((clamped * i16::max_value() as f32) as i16)?;
+        }
+        self.data_length += samples.len() as u32 * (BITS_PER_SAMPLE / 8) as u32;
+        Ok(())
+    }
+
+    // Patches the chunk lengths in the header now the amount of data is known
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer.write_u32::<LittleEndian>(HEADER_LENGTH - 8 + self.data_length)?;
+        self.writer.seek(SeekFrom::Start(40))?;
+        self.writer.write_u32::<LittleEndian>(self.data_length)?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn write_header(&mut self, sample_rate: u32) -> io::Result<()> {
+        let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+
+        self.writer.write_all(b"RIFF")?;
+        self.writer.write_u32::<LittleEndian>(HEADER_LENGTH - 8)?;
+        self.writer.write_all(b"WAVE")?;
+
+        self.writer.write_all(b"fmt ")?;
+        self.writer.write_u32::<LittleEndian>(16)?;
+        self.writer.write_u16::<LittleEndian>(1)?; // PCM
+        self.writer.write_u16::<LittleEndian>(CHANNELS)?;
+        self.writer.write_u32::<LittleEndian>(sample_rate)?;
+        self.writer.write_u32::<LittleEndian>(sample_rate * block_align as u32)?;
+        self.writer.write_u16::<LittleEndian>(block_align)?;
+        self.writer.write_u16::<LittleEndian>(BITS_PER_SAMPLE)?;
+
+        self.writer.write_all(b"data")?;
+        self.writer.write_u32::<LittleEndian>(0)
+    }
+}
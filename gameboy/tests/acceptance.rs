@@ -0,0 +1,1428 @@
+// Small acceptance tests built in the mooneye-gb style, they cover the same
+// features as tests/mooneye.rs without needing the ROMs to be checked in
+mod common;
+
+extern crate gameboy;
+
+use std::ops::Range;
+use gameboy::cartridge::Cartridge;
+use gameboy::interconnect::Interconnect;
+use gameboy::model::Model;
+use gameboy::vm::VM;
+use gameboy::device::Key;
+use common::{FrameDevice, KeyDevice, RumbleDevice, TestDevice, run_to_breakpoint, assert_passed};
+use common::rom::{Rom, PASS, FAIL};
+
+fn run_rom(rom: Rom) {
+    common::run_test_with_breakpoint(Cartridge::from_bytes(&rom.into_bytes()));
+}
+
+fn run_cgb_rom(mut rom: Rom) {
+    rom.set_cgb();
+    let cartridge = Cartridge::from_bytes(&rom.into_bytes());
+    let interconnect = Interconnect::with_model(cartridge, Model::Cgb);
+    let mut vm = VM::new(interconnect, false, false);
+
+    run_to_breakpoint(&mut vm, &mut TestDevice);
+    assert_passed(&vm);
+}
+
+// For tests that step the VM themselves
+fn start_dmg(rom: Rom) -> VM {
+    let cartridge = Cartridge::from_bytes(&rom.into_bytes());
+    let interconnect = Interconnect::with_model(cartridge, Model::Dmg);
+    VM::new(interconnect, false, false)
+}
+
+#[test]
+fn timer_div_write() {
+    let mut rom = Rom::new();
+    rom.code(&[
+        0xf3,       // DI
+        0xe0, 0x04, // LDH ($04),A - reset DIV
+        0xf0, 0x04, // LDH A,($04)
+    ]).expect_a(0x00);
+    // DIV counts every 256 clocks, this is around 600 clocks after the reset
+    rom.code(&[0x06, 0x24]); // LD B,$24
+    let wait = rom.here();
+    rom.code(&[0x05]).jr_nz(wait); // DEC B
+    rom.code(&[0xf0, 0x04]).expect_a(0x02); // LDH A,($04)
+    rom.jp(PASS);
+    run_rom(rom);
+}
+
+#[test]
+fn timer_tima_overflow() {
+    let mut rom = Rom::new();
+    rom.code(&[
+        0xf3,       // DI
+        0xaf,       // XOR A
+        0xe0, 0x0f, // LDH ($0f),A - clear IF
+        0x3e, 0xf0, // LD A,$f0
+        0xe0, 0x06, // LDH ($06),A - TMA
+        0x3e, 0xfe, // LD A,$fe
+        0xe0, 0x05, // LDH ($05),A - TIMA
+        0x3e, 0x05, // LD A,$05
+        0xe0, 0x07, // LDH ($07),A - start counting every 16 clocks
+        0x06, 0x08, // LD B,$08
+    ]);
+    let wait = rom.here();
+    rom.code(&[0x05]).jr_nz(wait); // DEC B
+    // TIMA has overflowed, requesting the interrupt, and been reloaded
+    rom.code(&[
+        0xf0, 0x0f, // LDH A,($0f)
+        0xe6, 0x04, // AND $04
+    ]).jp_z(FAIL);
+    rom.code(&[
+        0xf0, 0x05, // LDH A,($05)
+        0xfe, 0xf0, // CP $f0
+    ]).jp_c(FAIL);
+    rom.jp(PASS);
+    run_rom(rom);
+}
+
+#[test]
+fn interrupts_vblank() {
+    let mut rom = Rom::new();
+    rom.code(&[
+        0xf3,       // DI
+        0x3e, 0x01, // LD A,$01
+        0xe0, 0xff, // LDH ($ff),A - IE
+        0xaf,       // XOR A
+        0xe0, 0x0f, // LDH ($0f),A - IF
+        0xfb,       // EI
+        0x76,       // HALT
+    ]).jp(FAIL);
+    rom.org(0x0040).jp(0x0200);
+    // The interrupt is serviced at the start of vblank, and its IF bit is
+    // cleared
+    rom.org(0x0200).code(&[0xf0, 0x44]).expect_a(0x90); // LDH A,($44)
+    rom.code(&[
+        0xf0, 0x0f, // LDH A,($0f)
+        0xe6, 0x01, // AND $01
+    ]).jp_nz(FAIL);
+    rom.jp(PASS);
+    run_rom(rom);
+}
+
+#[test]
+fn interrupts_priority() {
+    let mut rom = Rom::new();
+    rom.code(&[
+        0xf3,       // DI
+        0x3e, 0x1f, // LD A,$1f
+        0xe0, 0xff, // LDH ($ff),A - IE
+        0x3e, 0x05, // LD A,$05
+        0xe0, 0x0f, // LDH ($0f),A - request vblank and timer
+        0xfb,       // EI
+        0x00,       // NOP
+    ]).jp(FAIL);
+    // VBlank has the highest priority, the timer interrupt is left pending
+    rom.org(0x0040).jp(0x0200);
+    rom.org(0x0200).code(&[
+        0xf0, 0x0f, // LDH A,($0f)
+        0xe6, 0x1f, // AND $1f
+    ]).expect_a(0x04);
+    rom.jp(PASS);
+    run_rom(rom);
+}
+
+// Sets IE to enable and IF to request then runs EI; NOP at addr with SP set
+// to sp, so an interrupt is dispatched straight after the NOP
+fn request_interrupt(rom: &mut Rom, addr: u16, sp: u16, enable: u8, request: u8) {
+    rom.jp(addr);
+    rom.org(addr as usize).code(&[
+        0xf3,                            // DI
+        0x31, sp as u8, (sp >> 8) as u8, // LD SP,sp
+        0x3e, enable,                    // LD A,enable
+        0xe0, 0xff,                      // LDH ($ff),A - IE
+        0x3e, request,                   // LD A,request
+        0xe0, 0x0f,                      // LDH ($0f),A - IF
+        0xfb,                            // EI
+        0x00,                            // NOP
+    ]).jp(FAIL);
+}
+
+// Pushing the high byte of PC to 0xffff sets IE to 0x20, which cancels any
+// interrupt and the CPU jumps to 0x0000 instead
+#[test]
+fn interrupts_ie_push_cancel() {
+    for interrupt in 0..5 {
+        let mut rom = Rom::new();
+        request_interrupt(&mut rom, 0x2000, 0x0000, 1 << interrupt, 1 << interrupt);
+        rom.org(0x0000).jp(0x0300);
+        rom.org(0x0300).code(&[0xf0, 0xff]).expect_a(0x20); // LDH A,($ff)
+        // The interrupt is still pending
+        rom.code(&[
+            0xf0, 0x0f, // LDH A,($0f)
+            0xe6, 0x1f, // AND $1f
+        ]).expect_a(1 << interrupt);
+        rom.jp(PASS);
+        run_rom(rom);
+    }
+}
+
+// If the new IE enables another requested interrupt, that one is dispatched
+#[test]
+fn interrupts_ie_push_redirect() {
+    let mut rom = Rom::new();
+    request_interrupt(&mut rom, 0x0200, 0x0000, 0x04, 0x06);
+    rom.org(0x0048).jp(0x0300);
+    rom.org(0x0300).code(&[
+        0xf0, 0x0f, // LDH A,($0f)
+        0xe6, 0x1f, // AND $1f
+    ]).expect_a(0x04);
+    rom.jp(PASS);
+    run_rom(rom);
+}
+
+// The interrupt has already been picked when the low byte of PC is pushed to
+// IE, so clearing it there doesn't cancel the dispatch
+#[test]
+fn interrupts_ie_push_low_byte() {
+    let mut rom = Rom::new();
+    // The NOP ends at 0x0300 so 0x00 is written to IE
+    request_interrupt(&mut rom, 0x02f2, 0x0001, 0x04, 0x04);
+    rom.org(0x0050).jp(0x0400);
+    rom.org(0x0400).code(&[0xf0, 0xff]).expect_a(0x00); // LDH A,($ff)
+    rom.jp(PASS);
+    run_rom(rom);
+}
+
+// Interrupts are enabled once the instruction after EI has run
+#[test]
+fn ei_timing() {
+    let mut rom = Rom::new();
+    rom.code(&[
+        0xf3,       // DI
+        0x06, 0x00, // LD B,$00
+        0x3e, 0x04, // LD A,$04
+        0xe0, 0xff, // LDH ($ff),A - IE
+        0xe0, 0x0f, // LDH ($0f),A - IF
+        0xfb,       // EI
+        0x04,       // INC B
+        0x04,       // INC B
+    ]).jp(FAIL);
+    rom.org(0x0050).code(&[0x78]).expect_a(0x01); // LD A,B
+    rom.jp(PASS);
+    run_rom(rom);
+}
+
+// EI straight after EI doesn't delay interrupts by another instruction
+#[test]
+fn ei_sequence() {
+    let mut rom = Rom::new();
+    rom.code(&[
+        0xf3,       // DI
+        0x3e, 0x04, // LD A,$04
+        0xe0, 0xff, // LDH ($ff),A - IE
+        0xe0, 0x0f, // LDH ($0f),A - IF
+        0xfb,       // EI
+        0xfb,       // EI
+    ]);
+    let ret = rom.here();
+    rom.code(&[0x00]).jp(FAIL); // NOP
+    rom.org(0x0050).code(&[0xe1]).jp(0x0200); // POP HL
+    rom.org(0x0200).code(&[0x7c]).expect_a((ret >> 8) as u8); // LD A,H
+    rom.code(&[0x7d]).expect_a(ret as u8); // LD A,L
+    rom.jp(PASS);
+    run_rom(rom);
+}
+
+// DI straight after EI stops the interrupt from being dispatched, it's only
+// dispatched once EI is followed by another instruction
+#[test]
+fn rapid_di_ei() {
+    let mut rom = Rom::new();
+    rom.code(&[
+        0xf3,       // DI
+        0x3e, 0x04, // LD A,$04
+        0xe0, 0xff, // LDH ($ff),A - IE
+        0xe0, 0x0f, // LDH ($0f),A - IF
+        0xfb,       // EI
+        0xf3,       // DI
+        0xfb,       // EI
+        0xf3,       // DI
+        0xfb,       // EI
+        0xf3,       // DI
+        0xfb,       // EI
+        0x00,       // NOP
+    ]);
+    let ret = rom.here();
+    rom.jp(FAIL);
+    rom.org(0x0050).code(&[0xe1]).jp(0x0200); // POP HL
+    rom.org(0x0200).code(&[0x7c]).expect_a((ret >> 8) as u8); // LD A,H
+    rom.code(&[0x7d]).expect_a(ret as u8); // LD A,L
+    rom.jp(PASS);
+    run_rom(rom);
+}
+
+// Dispatching an interrupt takes 5 M-cycles
+#[test]
+fn interrupts_dispatch_timing() {
+    let mut rom = Rom::new();
+    request_interrupt(&mut rom, 0x0200, 0xfffe, 0x04, 0x04);
+    rom.org(0x0050).jp(PASS);
+
+    let mut vm = start_dmg(rom);
+    for _ in 0..100 {
+        let (cycles, _) = vm.step(&mut TestDevice);
+        if vm.cpu().pc == 0x0050 {
+            assert_eq!(20, cycles);
+            return;
+        }
+    }
+    panic!("The interrupt wasn't dispatched");
+}
+
+// HALT with interrupts disabled and one already pending doesn't halt, and the
+// next opcode is read twice
+#[test]
+fn halt_bug() {
+    let mut rom = Rom::new();
+    rom.code(&[
+        0xf3,       // DI
+        0x3e, 0x04, // LD A,$04
+        0xe0, 0xff, // LDH ($ff),A - IE
+        0xe0, 0x0f, // LDH ($0f),A - IF
+        0x06, 0x00, // LD B,$00
+        0x76,       // HALT
+        0x04,       // INC B
+        0x78,       // LD A,B
+    ]).expect_a(0x02);
+    rom.jp(PASS);
+    run_rom(rom);
+}
+
+// EI then HALT with an interrupt pending dispatches it straight away, and the
+// interrupt returns to the HALT rather than the instruction after it
+#[test]
+fn halt_after_ei_with_interrupt_pending() {
+    let mut rom = Rom::new();
+    rom.code(&[
+        0xf3,       // DI
+        0x06, 0x00, // LD B,$00
+        0x3e, 0x04, // LD A,$04
+        0xe0, 0xff, // LDH ($ff),A - IE
+        0xe0, 0x0f, // LDH ($0f),A - IF
+        0xfb,       // EI
+    ]);
+    let halt = rom.here();
+    rom.code(&[0x76]).jp(FAIL); // HALT
+    // The handler's first opcode is only run once
+    rom.org(0x0050).code(&[0x04]).jp(0x0200); // INC B
+    rom.org(0x0200).code(&[0x78]).expect_a(0x01); // LD A,B
+    rom.code(&[0xe1, 0x7c]).expect_a((halt >> 8) as u8); // POP HL; LD A,H
+    rom.code(&[0x7d]).expect_a(halt as u8); // LD A,L
+    rom.jp(PASS);
+    run_rom(rom);
+}
+
+// With interrupts disabled HALT waits for an interrupt to be requested, but
+// it isn't dispatched
+#[test]
+fn halt_ime0_wakes() {
+    let mut rom = Rom::new();
+    rom.code(&[
+        0xf3,       // DI
+        0x3e, 0x04, // LD A,$04
+        0xe0, 0xff, // LDH ($ff),A - IE
+        0xaf,       // XOR A
+        0xe0, 0x0f, // LDH ($0f),A - IF
+        0x3e, 0xf0, // LD A,$f0
+        0xe0, 0x05, // LDH ($05),A - TIMA
+        0x3e, 0x05, // LD A,$05
+        0xe0, 0x07, // LDH ($07),A - start counting every 16 clocks
+        0x76,       // HALT
+        0xf0, 0x0f, // LDH A,($0f)
+        0xe6, 0x04, // AND $04
+    ]).jp_z(FAIL);
+    rom.jp(PASS);
+    run_rom(rom);
+}
+
+// STOP resets DIV, then nothing runs until a button is pressed
+#[test]
+fn stop_waits_for_button() {
+    let mut rom = Rom::new();
+    rom.code(&[
+        0xf3,       // DI
+        0x3e, 0x10, // LD A,$10
+        0xe0, 0x00, // LDH ($00),A - select the buttons
+        0x10, 0x00, // STOP
+        0xf0, 0x04, // LDH A,($04)
+    ]).expect_a(0x00);
+    rom.jp(PASS);
+
+    let mut vm = start_dmg(rom);
+    for _ in 0..10 {
+        vm.step(&mut TestDevice);
+    }
+    let pc = vm.cpu().pc;
+    let div = vm.read_byte(0xff04);
+    for _ in 0..100000 {
+        vm.step(&mut TestDevice);
+    }
+    assert_eq!(pc, vm.cpu().pc);
+    assert_eq!(div, vm.read_byte(0xff04));
+
+    run_to_breakpoint(&mut vm, &mut KeyDevice(Key::Z));
+    assert_passed(&vm);
+}
+
+// An illegal opcode hangs the CPU, even interrupts don't get it going again
+#[test]
+fn illegal_opcode_locks_up() {
+    let mut rom = Rom::new();
+    rom.code(&[
+        0xf3,       // DI
+        0x3e, 0x04, // LD A,$04
+        0xe0, 0xff, // LDH ($ff),A - IE
+        0xaf,       // XOR A
+        0xe0, 0x0f, // LDH ($0f),A - IF
+        0x3e, 0xfc, // LD A,$fc
+        0xe0, 0x05, // LDH ($05),A - TIMA
+        0x3e, 0x05, // LD A,$05
+        0xe0, 0x07, // LDH ($07),A - start counting every 16 clocks
+        0xfb,       // EI
+        0x00,       // NOP
+        0xd3,       // Illegal
+    ]);
+    let pc = rom.here();
+    rom.jp(FAIL);
+
+    let mut vm = start_dmg(rom);
+    for _ in 0..100000 {
+        vm.step(&mut TestDevice);
+    }
+    assert!(vm.cpu().locked_up);
+    assert_eq!(pc, vm.cpu().pc);
+    // The timer interrupt was requested but never dispatched
+    assert!(vm.read_byte(0xff0f) & 0x04 != 0);
+}
+
+// Copies a ramp into OAM from WRAM, running from HRAM while the DMA has the
+// bus
+#[test]
+fn oam_dma_basic() {
+    let mut rom = Rom::new();
+    let routine = 0x0300;
+    rom.code(&[
+        0xf3,             // DI
+        0xaf,             // XOR A
+        0xe0, 0x40,       // LDH ($40),A - LCD off so OAM can be read back
+        0x21, 0x00, 0xc0, // LD HL,$c000
+        0x06, 0xa0,       // LD B,$a0
+    ]);
+    let fill = rom.here();
+    rom.code(&[
+        0x22, // LD (HL+),A
+        0x3c, // INC A
+        0x05, // DEC B
+    ]).jr_nz(fill);
+
+    rom.code(&[
+        0x21, routine as u8, (routine >> 8) as u8, // LD HL,routine
+        0x0e, 0x80,                                // LD C,$80
+        0x06, 0x09,                                // LD B,$09
+    ]);
+    let copy = rom.here();
+    rom.code(&[
+        0x2a, // LD A,(HL+)
+        0xe2, // LDH (C),A
+        0x0c, // INC C
+        0x05, // DEC B
+    ]).jr_nz(copy);
+    rom.code(&[0x3e, 0xc0]).call(0xff80); // LD A,$c0
+
+    rom.code(&[
+        0x21, 0x00, 0xfe, // LD HL,$fe00
+        0x06, 0xa0,       // LD B,$a0
+        0x0e, 0x00,       // LD C,$00
+    ]);
+    let check = rom.here();
+    rom.code(&[
+        0x2a, // LD A,(HL+)
+        0xb9, // CP C
+    ]).jp_nz(FAIL);
+    rom.code(&[
+        0x0c, // INC C
+        0x05, // DEC B
+    ]).jr_nz(check);
+    rom.jp(PASS);
+
+    // Starts the DMA and waits for it to finish
+    rom.org(routine).code(&[
+        0xe0, 0x46, // LDH ($46),A
+        0x3e, 0x2a, // LD A,$2a
+        0x3d,       // DEC A
+        0x20, 0xfd, // JR NZ,-3
+        0xc9,       // RET
+    ]);
+    run_rom(rom);
+}
+
+#[test]
+fn ppu_lyc_interrupt() {
+    let mut rom = Rom::new();
+    rom.code(&[
+        0xf3,       // DI
+        0x3e, 0x10, // LD A,$10
+        0xe0, 0x45, // LDH ($45),A - LYC
+        0x3e, 0x40, // LD A,$40
+        0xe0, 0x41, // LDH ($41),A - interrupt on LY == LYC
+        0x3e, 0x02, // LD A,$02
+        0xe0, 0xff, // LDH ($ff),A - IE
+        0xaf,       // XOR A
+        0xe0, 0x0f, // LDH ($0f),A - IF
+        0xfb,       // EI
+        0x76,       // HALT
+    ]).jp(FAIL);
+    rom.org(0x0048).jp(0x0200);
+    rom.org(0x0200).code(&[0xf0, 0x44]).expect_a(0x10); // LDH A,($44)
+    rom.code(&[
+        0xf0, 0x41, // LDH A,($41)
+        0xe6, 0x04, // AND $04 - the coincidence flag
+    ]).jp_z(FAIL);
+    rom.jp(PASS);
+    run_rom(rom);
+}
+
+#[test]
+fn ppu_vblank_mode() {
+    let mut rom = Rom::new();
+    rom.code(&[0xf3]); // DI
+    let wait = rom.here();
+    rom.code(&[
+        0xf0, 0x44, // LDH A,($44)
+        0xfe, 0x90, // CP $90
+    ]).jr_nz(wait);
+    rom.code(&[
+        0xf0, 0x41, // LDH A,($41)
+        0xe6, 0x03, // AND $03
+    ]).expect_a(0x01);
+    rom.jp(PASS);
+    run_rom(rom);
+}
+
+#[test]
+fn ppu_lcd_off() {
+    let mut rom = Rom::new();
+    rom.code(&[
+        0xf3,       // DI
+        0xaf,       // XOR A
+        0xe0, 0x40, // LDH ($40),A - LCD off
+        0xf0, 0x44, // LDH A,($44)
+    ]).expect_a(0x00);
+    rom.code(&[
+        0xf0, 0x41, // LDH A,($41)
+        0xe6, 0x03, // AND $03
+    ]).expect_a(0x00);
+    rom.jp(PASS);
+    run_rom(rom);
+}
+
+// The STAT interrupt is only requested when the OR of its enabled sources
+// rises. With the HBlank source enabled as well the line is already high
+// when the OAM search starts, so there's no OAM interrupt.
+#[test]
+fn ppu_stat_blocking() {
+    for &(sources, requested) in &[(0x20, 0x02), (0x28, 0x00)] {
+        let mut rom = Rom::new();
+        rom.code(&[
+            0xf3,          // DI
+            0x3e, sources, // LD A,sources
+            0xe0, 0x41,    // LDH ($41),A - STAT
+        ]);
+        wait_ly(&mut rom, 0x10);
+        wait_mode(&mut rom, 0);
+        rom.code(&[
+            0xaf,       // XOR A
+            0xe0, 0x0f, // LDH ($0f),A - IF
+        ]);
+        wait_mode(&mut rom, 3);
+        rom.code(&[
+            0xf0, 0x0f, // LDH A,($0f)
+            0xe6, 0x02, // AND $02
+        ]).expect_a(requested);
+        rom.jp(PASS);
+        run_rom(rom);
+    }
+}
+
+// On the DMG writing STAT enables every source for a cycle, which requests
+// the interrupt in vblank even with no sources enabled
+#[test]
+fn ppu_stat_write_dmg() {
+    let mut rom = Rom::new();
+    rom.code(&[0xf3]); // DI
+    wait_ly(&mut rom, 0x90);
+    rom.code(&[
+        0xaf,       // XOR A
+        0xe0, 0x0f, // LDH ($0f),A - IF
+        0xe0, 0x41, // LDH ($41),A - STAT
+        0xf0, 0x0f, // LDH A,($0f)
+        0xe6, 0x02, // AND $02
+    ]).expect_a(0x02);
+    rom.jp(PASS);
+    run_rom(rom);
+}
+
+// VRAM can't be accessed while the PPU reads it in mode 3, writes are
+// dropped and reads return 0xff
+#[test]
+fn ppu_vram_locked_in_mode_3() {
+    let mut rom = Rom::new();
+    rom.code(&[
+        0xf3,             // DI
+        0xaf,             // XOR A
+        0xe0, 0x40,       // LDH ($40),A - LCD off
+        0x3e, 0x12,       // LD A,$12
+        0xea, 0x00, 0x80, // LD ($8000),A
+        0x3e, 0x91,       // LD A,$91
+        0xe0, 0x40,       // LDH ($40),A - LCD on
+    ]);
+    wait_ly(&mut rom, 0x10);
+    wait_mode(&mut rom, 3);
+    rom.code(&[
+        0x3e, 0x34,       // LD A,$34
+        0xea, 0x00, 0x80, // LD ($8000),A
+        0xfa, 0x00, 0x80, // LD A,($8000)
+    ]).expect_a(0xff);
+    wait_mode(&mut rom, 0);
+    rom.code(&[0xfa, 0x00, 0x80]).expect_a(0x12); // LD A,($8000)
+    rom.jp(PASS);
+    run_rom(rom);
+}
+
+// OAM is locked during the OAM search as well as mode 3
+#[test]
+fn ppu_oam_locked_in_modes_2_and_3() {
+    for &mode in &[2, 3] {
+        let mut rom = Rom::new();
+        rom.code(&[
+            0xf3,             // DI
+            0xaf,             // XOR A
+            0xe0, 0x40,       // LDH ($40),A - LCD off
+            0x3e, 0x12,       // LD A,$12
+            0xea, 0x00, 0xfe, // LD ($fe00),A
+            0x3e, 0x91,       // LD A,$91
+            0xe0, 0x40,       // LDH ($40),A - LCD on
+        ]);
+        wait_ly(&mut rom, 0x10);
+        wait_mode(&mut rom, mode);
+        rom.code(&[
+            0x3e, 0x34,       // LD A,$34
+            0xea, 0x00, 0xfe, // LD ($fe00),A
+            0xfa, 0x00, 0xfe, // LD A,($fe00)
+        ]).expect_a(0xff);
+        wait_mode(&mut rom, 0);
+        rom.code(&[0xfa, 0x00, 0xfe]).expect_a(0x12); // LD A,($fe00)
+        rom.jp(PASS);
+        run_rom(rom);
+    }
+}
+
+// Steps the machine to mode 3 of the line after the next one
+fn step_to_mode_3(interconnect: &mut Interconnect) {
+    let ly = interconnect.read_byte(0xff44);
+    while interconnect.read_byte(0xff44) != ly.wrapping_add(2) ||
+          interconnect.read_byte(0xff41) & 0x03 != 3 {
+        interconnect.step(4, &mut TestDevice);
+    }
+}
+
+// Dropped writes are counted for the debugger, and locking can be turned off
+#[test]
+fn ppu_dropped_writes() {
+    let cartridge = Cartridge::from_bytes(&Rom::new().into_bytes());
+    let mut interconnect = Interconnect::with_model(cartridge, Model::Dmg);
+    interconnect.write_byte(0xff40, 0x91);
+
+    step_to_mode_3(&mut interconnect);
+    interconnect.write_byte(0x8000, 0x12);
+    interconnect.write_byte(0x9800, 0x12);
+    interconnect.write_byte(0xfe00, 0x12);
+    assert_eq!((2, 1), interconnect.dropped_writes());
+
+    interconnect.set_access_locking(false);
+    step_to_mode_3(&mut interconnect);
+    interconnect.write_byte(0x8000, 0x34);
+    interconnect.write_byte(0xfe00, 0x56);
+    assert_eq!(0x34, interconnect.read_byte(0x8000));
+    assert_eq!(0x56, interconnect.read_byte(0xfe00));
+    assert_eq!((2, 1), interconnect.dropped_writes());
+}
+
+// The DMG's shades, lightest first
+const SHADES: [u32; 4] = [0xff7e8429, 0xff527a4b, 0xff315d4b, 0xff29473e];
+// Data copied into VRAM or OAM is kept here in the ROM
+const DATA: u16 = 0x1000;
+
+// Fills len bytes from addr with val
+fn fill(rom: &mut Rom, addr: u16, len: u16, val: u8) {
+    rom.code(&[
+        0x21, addr as u8, (addr >> 8) as u8, // LD HL,addr
+        0x01, len as u8, (len >> 8) as u8,   // LD BC,len
+    ]);
+    let fill = rom.here();
+    rom.code(&[
+        0x3e, val, // LD A,val
+        0x22,      // LD (HL+),A
+        0x0b,      // DEC BC
+        0x78,      // LD A,B
+        0xb1,      // OR C
+    ]).jr_nz(fill);
+}
+
+// Stores data in the ROM at src and copies it to addr
+fn copy(rom: &mut Rom, src: u16, addr: u16, data: &[u8]) {
+    let pos = rom.here();
+    rom.org(src as usize).code(data);
+    let len = data.len() as u16;
+    rom.org(pos as usize).code(&[
+        0x21, src as u8, (src >> 8) as u8,   // LD HL,src
+        0x11, addr as u8, (addr >> 8) as u8, // LD DE,addr
+        0x01, len as u8, (len >> 8) as u8,   // LD BC,len
+    ]);
+    let copy = rom.here();
+    rom.code(&[
+        0x2a, // LD A,(HL+)
+        0x12, // LD (DE),A
+        0x13, // INC DE
+        0x0b, // DEC BC
+        0x78, // LD A,B
+        0xb1, // OR C
+    ]).jr_nz(copy);
+}
+
+fn wait_ly(rom: &mut Rom, line: u8) {
+    let wait = rom.here();
+    rom.code(&[
+        0xf0, 0x44, // LDH A,($44)
+        0xfe, line, // CP line
+    ]).jr_nz(wait);
+}
+
+fn wait_mode(rom: &mut Rom, mode: u8) {
+    let wait = rom.here();
+    rom.code(&[
+        0xf0, 0x41, // LDH A,($41)
+        0xe6, 0x03, // AND $03
+        0xfe, mode, // CP mode
+    ]).jr_nz(wait);
+}
+
+// Switches the LCD off and loads tiles 0 - 3, each filled with the colour of
+// its number, and the BG and OBJ0 palettes mapping each colour to itself
+fn load_tiles(rom: &mut Rom) {
+    let mut tiles = Vec::new();
+    for colour in 0..4 {
+        for _ in 0..8 {
+            tiles.push(if colour & 1 != 0 { 0xff } else { 0x00 });
+            tiles.push(if colour & 2 != 0 { 0xff } else { 0x00 });
+        }
+    }
+
+    rom.code(&[
+        0xf3,       // DI
+        0xaf,       // XOR A
+        0xe0, 0x40, // LDH ($40),A - LCD off
+        0x3e, 0xe4, // LD A,$e4
+        0xe0, 0x47, // LDH ($47),A - BGP
+        0xe0, 0x48, // LDH ($48),A - OBP0
+    ]);
+    copy(rom, DATA, 0x8000, &tiles);
+}
+
+// Runs the ROM to its breakpoint and returns the last frame shown
+fn run_frame(rom: Rom) -> Vec<u32> {
+    let mut vm = start_dmg(rom);
+    let mut device = FrameDevice(Vec::new());
+    run_to_breakpoint(&mut vm, &mut device);
+    assert_passed(&vm);
+    device.0
+}
+
+fn assert_line_colour(frame: &[u32], line: usize, xs: Range<usize>, colour: usize) {
+    for x in xs {
+        assert_eq!(SHADES[colour], frame[line * 160 + x], "Wrong colour at {}, {}", x, line);
+    }
+}
+
+// The window's line counter only advances on lines where the window is drawn,
+// so switching it off for a few lines doesn't skip any of it
+#[test]
+fn ppu_window_line_counter() {
+    let mut rom = Rom::new();
+    load_tiles(&mut rom);
+    // The background is colour 1, the window's first row of tiles colour 0,
+    // the next colour 3 and the rest colour 2
+    fill(&mut rom, 0x9800, 0x400, 1);
+    fill(&mut rom, 0x9c00, 0x20, 0);
+    fill(&mut rom, 0x9c20, 0x20, 3);
+    fill(&mut rom, 0x9c40, 0x3c0, 2);
+    rom.code(&[
+        0xaf,       // XOR A
+        0xe0, 0x4a, // LDH ($4a),A - WY
+        0x3e, 0x07, // LD A,$07
+        0xe0, 0x4b, // LDH ($4b),A - WX
+        0x3e, 0xf1, // LD A,$f1
+        0xe0, 0x40, // LDH ($40),A - LCD and window on
+    ]);
+    wait_ly(&mut rom, 4);
+    rom.code(&[
+        0x3e, 0xd1, // LD A,$d1
+        0xe0, 0x40, // LDH ($40),A - window off
+    ]);
+    wait_ly(&mut rom, 10);
+    rom.code(&[
+        0x3e, 0xf1, // LD A,$f1
+        0xe0, 0x40, // LDH ($40),A - window on
+    ]);
+    wait_ly(&mut rom, 144);
+    rom.jp(PASS);
+
+    let frame = run_frame(rom);
+    for line in 0..144 {
+        let colour = match line {
+            0...3 => 0,
+            4...9 => 1,
+            // Window lines 4 - 7
+            10...13 => 0,
+            // Window lines 8 - 15
+            14...21 => 3,
+            _ => 2,
+        };
+        assert_line_colour(&frame, line, 0..160, colour);
+    }
+}
+
+// Loads OAM with the sprites, given as Y, X and tile, with the rest hidden
+// above the screen, then draws a frame with sprites over a colour 0
+// background
+fn draw_sprites(sprites: &[(u8, u8, u8)]) -> Vec<u32> {
+    let mut oam = vec![0; 0xa0];
+    for (i, &(y, x, tile)) in sprites.iter().enumerate() {
+        oam[i * 4] = y;
+        oam[(i * 4) + 1] = x;
+        oam[(i * 4) + 2] = tile;
+    }
+
+    let mut rom = Rom::new();
+    load_tiles(&mut rom);
+    fill(&mut rom, 0x9800, 0x400, 0);
+    copy(&mut rom, DATA + 0x100, 0xfe00, &oam);
+    rom.code(&[
+        0x3e, 0x93, // LD A,$93
+        0xe0, 0x40, // LDH ($40),A - LCD and sprites on
+    ]);
+    wait_ly(&mut rom, 144);
+    rom.jp(PASS);
+    run_frame(rom)
+}
+
+// Only the first ten sprites in OAM on a line are drawn, including ones off
+// the left edge of the screen
+#[test]
+fn ppu_sprite_limit() {
+    let mut sprites = vec![(16, 0, 3)];
+    for i in 0..10 {
+        sprites.push((16, 8 + (i * 16), 3));
+    }
+    // The limit applies to each line separately
+    sprites.push((24, 8, 3));
+
+    let frame = draw_sprites(&sprites);
+    for line in 0..8 {
+        for i in 0..9 {
+            assert_line_colour(&frame, line, i * 16..(i * 16) + 8, 3);
+            assert_line_colour(&frame, line, (i * 16) + 8..(i * 16) + 16, 0);
+        }
+        assert_line_colour(&frame, line, 144..160, 0);
+    }
+    assert_line_colour(&frame, 8, 0..8, 3);
+}
+
+// On the DMG the sprite with the lower X is drawn on top, OAM order only
+// decides between sprites at the same X
+#[test]
+fn ppu_sprite_x_priority() {
+    let frame = draw_sprites(&[
+        (16, 20, 1), // Covers 12 - 19
+        (16, 16, 3), // Covers 8 - 15
+        (24, 40, 1),
+        (24, 40, 3),
+    ]);
+    for line in 0..8 {
+        assert_line_colour(&frame, line, 8..16, 3);
+        assert_line_colour(&frame, line, 16..20, 1);
+    }
+    for line in 8..16 {
+        assert_line_colour(&frame, line, 32..40, 1);
+    }
+}
+
+// Measures how long mode 3 lasts on line 10 with SCX set to scx and sprites
+// at the given X positions. The CPU runs NOPs so STAT is sampled every 4
+// dots, the length is rounded to that.
+fn mode_3_dots(scx: u8, sprite_xs: &[u8]) -> u32 {
+    let mut oam = vec![0; 0xa0];
+    for (i, x) in sprite_xs.iter().enumerate() {
+        oam[i * 4] = 16 + 10;
+        oam[(i * 4) + 1] = *x;
+    }
+
+    let mut rom = Rom::new();
+    load_tiles(&mut rom);
+    copy(&mut rom, DATA + 0x100, 0xfe00, &oam);
+    rom.code(&[
+        0x3e, scx,  // LD A,scx
+        0xe0, 0x43, // LDH ($43),A - SCX
+        0x3e, 0x93, // LD A,$93
+        0xe0, 0x40, // LDH ($40),A - LCD and sprites on
+    ]);
+    // The rest of the ROM up to PASS is NOPs
+
+    let mut vm = start_dmg(rom);
+    while vm.read_byte(0xff44) != 10 {
+        vm.step(&mut TestDevice);
+    }
+    let mut samples = 0;
+    while vm.read_byte(0xff44) == 10 {
+        let (cycles, _) = vm.step(&mut TestDevice);
+        assert_eq!(4, cycles);
+        if vm.read_byte(0xff41) & 0x03 == 3 {
+            samples += 1;
+        }
+    }
+    samples * 4
+}
+
+// Mode 3 starts at the same dot on every line, so lengths that differ by
+// less than 4 dots can only be rounded 4 dots apart
+#[test]
+fn ppu_mode_3_length() {
+    let base = mode_3_dots(0, &[]);
+    assert!(base >= 172 && base <= 176, "Mode 3 took {} dots", base);
+
+    // Fine scrolling throws away SCX % 8 pixels at the start of the line
+    let scrolled = mode_3_dots(5, &[]) - base;
+    assert!(scrolled >= 4 && scrolled <= 8, "SCX added {} dots", scrolled);
+    assert_eq!(base, mode_3_dots(8, &[]));
+
+    // Each sprite fetch stalls the pixel pipeline for 6 - 11 dots, sprites
+    // past the right edge aren't fetched
+    let sprite = mode_3_dots(0, &[8]) - base;
+    assert!(sprite >= 4 && sprite <= 12, "A sprite added {} dots", sprite);
+    assert_eq!(base, mode_3_dots(0, &[168]));
+    let xs: Vec<u8> = (0..10).map(|i| 8 + (i * 16)).collect();
+    let sprites = mode_3_dots(0, &xs) - base;
+    assert!(sprites >= 60 && sprites <= 120, "Ten sprites added {} dots", sprites);
+}
+
+#[test]
+fn mbc1_rom_banks() {
+    let mut rom = Rom::with_banks(8);
+    rom.set_cartridge_type(0x01, 0x00);
+    // Each switchable bank starts with its own number
+    for bank in 1..8 {
+        rom.org(bank * 0x4000).code(&[bank as u8]);
+    }
+
+    rom.org(0x0150).code(&[
+        0x3e, 0x03,       // LD A,$03
+        0xea, 0x00, 0x20, // LD ($2000),A
+        0xfa, 0x00, 0x40, // LD A,($4000)
+    ]).expect_a(0x03);
+    // Bank 0 maps to bank 1
+    rom.code(&[
+        0xaf,             // XOR A
+        0xea, 0x00, 0x20, // LD ($2000),A
+        0xfa, 0x00, 0x40, // LD A,($4000)
+    ]).expect_a(0x01);
+    // Bank numbers past the end of the ROM wrap around
+    rom.code(&[
+        0x3e, 0x0d,       // LD A,$0d
+        0xea, 0x00, 0x20, // LD ($2000),A
+        0xfa, 0x00, 0x40, // LD A,($4000)
+    ]).expect_a(0x05);
+    rom.jp(PASS);
+    run_rom(rom);
+}
+
+#[test]
+fn mbc1_ram_enable() {
+    let mut rom = Rom::new();
+    rom.set_cartridge_type(0x02, 0x02);
+    rom.code(&[
+        0x3e, 0x0a,       // LD A,$0a
+        0xea, 0x00, 0x00, // LD ($0000),A - enable RAM
+        0x3e, 0x5a,       // LD A,$5a
+        0xea, 0x00, 0xa0, // LD ($a000),A
+        0xfa, 0x00, 0xa0, // LD A,($a000)
+    ]).expect_a(0x5a);
+    // Disabled RAM reads as 0xff, but keeps its contents
+    rom.code(&[
+        0xaf,             // XOR A
+        0xea, 0x00, 0x00, // LD ($0000),A
+        0xfa, 0x00, 0xa0, // LD A,($a000)
+    ]).expect_a(0xff);
+    rom.code(&[
+        0x3e, 0x0a,       // LD A,$0a
+        0xea, 0x00, 0x00, // LD ($0000),A
+        0xfa, 0x00, 0xa0, // LD A,($a000)
+    ]).expect_a(0x5a);
+    rom.jp(PASS);
+    run_rom(rom);
+}
+
+// Each bank starts this far in with its number, low byte first
+const BANK_NUMBER_OFFSET: usize = 0x3ff0;
+
+fn mark_bank_numbers(rom: &mut Rom, banks: &[usize]) {
+    for bank in banks {
+        rom.org(bank * 0x4000 + BANK_NUMBER_OFFSET).code(&[*bank as u8, (*bank >> 8) as u8]);
+    }
+}
+
+// Selects a ROM bank with the lower and upper bank registers and checks the
+// number in the 0x4000 - 0x7fff window
+fn expect_mbc5_bank(rom: &mut Rom, lower: u8, upper: u8, bank: usize) {
+    let number_addr = 0x4000 + BANK_NUMBER_OFFSET as u16;
+    rom.code(&[
+        0x3e, lower,      // LD A,lower
+        0xea, 0x00, 0x20, // LD ($2000),A
+        0x3e, upper,      // LD A,upper
+        0xea, 0x00, 0x30, // LD ($3000),A
+        0xfa, number_addr as u8, (number_addr >> 8) as u8, // LD A,(number)
+    ]).expect_a(bank as u8);
+    rom.code(&[
+        0xfa, number_addr as u8 + 1, (number_addr >> 8) as u8, // LD A,(number + 1)
+    ]).expect_a((bank >> 8) as u8);
+}
+
+#[test]
+fn mbc5_rom_banks() {
+    let mut rom = Rom::with_banks(512);
+    rom.set_cartridge_type(0x19, 0x00);
+    mark_bank_numbers(&mut rom, &[0x000, 0x001, 0x0ff, 0x100, 0x1ff]);
+
+    rom.org(0x0150);
+    expect_mbc5_bank(&mut rom, 0xff, 0x00, 0x0ff);
+    // The ninth bit comes from 0x3000 - 0x3fff
+    expect_mbc5_bank(&mut rom, 0x00, 0x01, 0x100);
+    expect_mbc5_bank(&mut rom, 0xff, 0x01, 0x1ff);
+    // Unlike the other MBCs bank 0 can be mapped at 0x4000
+    expect_mbc5_bank(&mut rom, 0x00, 0x00, 0x000);
+    expect_mbc5_bank(&mut rom, 0x01, 0x00, 0x001);
+    rom.jp(PASS);
+    run_rom(rom);
+}
+
+// On rumble carts bit 3 of the RAM bank register drives the motor, so only 8
+// RAM banks can be selected
+#[test]
+fn mbc5_rumble() {
+    let mut rom = Rom::new();
+    rom.set_cartridge_type(0x1d, 0x03);
+    rom.code(&[
+        0x3e, 0x0a,       // LD A,$0a
+        0xea, 0x00, 0x00, // LD ($0000),A - enable RAM
+        0x3e, 0x01,       // LD A,$01
+        0xea, 0x00, 0x40, // LD ($4000),A - RAM bank 1
+        0x3e, 0x5a,       // LD A,$5a
+        0xea, 0x00, 0xa0, // LD ($a000),A
+        0x3e, 0x09,       // LD A,$09
+        0xea, 0x00, 0x40, // LD ($4000),A - RAM bank 1, motor on
+        0xfa, 0x00, 0xa0, // LD A,($a000)
+    ]).expect_a(0x5a);
+    rom.code(&[
+        0x3e, 0x01,       // LD A,$01
+        0xea, 0x00, 0x40, // LD ($4000),A - motor off
+    ]);
+    rom.jp(PASS);
+
+    let mut vm = start_dmg(rom);
+    let mut device = RumbleDevice(Vec::new());
+    run_to_breakpoint(&mut vm, &mut device);
+    assert_passed(&vm);
+    // The motor state is passed on as the cartridge is stepped
+    vm.step(&mut device);
+    assert_eq!(vec![true, false], device.0);
+}
+
+#[test]
+fn mbc2_registers() {
+    let mut rom = Rom::with_banks(4);
+    rom.set_cartridge_type(0x05, 0x00);
+    mark_bank_numbers(&mut rom, &[1, 2, 3]);
+    let number_addr = 0x4000 + BANK_NUMBER_OFFSET as u16;
+
+    // With bit 8 of the address clear the register enables RAM, anywhere in
+    // 0x0000 - 0x3fff
+    rom.org(0x0150).code(&[
+        0x3e, 0x0a,       // LD A,$0a
+        0xea, 0x00, 0x20, // LD ($2000),A
+        0xfa, number_addr as u8, (number_addr >> 8) as u8, // LD A,(number)
+    ]).expect_a(0x01);
+    rom.code(&[
+        0x3e, 0x05,       // LD A,$05
+        0xea, 0x00, 0xa0, // LD ($a000),A
+        0xfa, 0x00, 0xa0, // LD A,($a000)
+    ]).expect_a(0xf5);
+    // With bit 8 set it selects the ROM bank
+    rom.code(&[
+        0x3e, 0x03,       // LD A,$03
+        0xea, 0x00, 0x01, // LD ($0100),A
+        0xfa, number_addr as u8, (number_addr >> 8) as u8, // LD A,(number)
+    ]).expect_a(0x03);
+    rom.code(&[
+        0x3e, 0x12,       // LD A,$12 - only the low nibble is used
+        0xea, 0xff, 0x3f, // LD ($3fff),A
+        0xfa, number_addr as u8, (number_addr >> 8) as u8, // LD A,(number)
+    ]).expect_a(0x02);
+    rom.code(&[
+        0xaf,             // XOR A - bank 0 maps to bank 1
+        0xea, 0x00, 0x21, // LD ($2100),A
+        0xfa, number_addr as u8, (number_addr >> 8) as u8, // LD A,(number)
+    ]).expect_a(0x01);
+    // Disabled RAM reads as 0xff
+    rom.code(&[
+        0xaf,             // XOR A
+        0xea, 0x00, 0x00, // LD ($0000),A
+        0xfa, 0x00, 0xa0, // LD A,($a000)
+    ]).expect_a(0xff);
+    rom.jp(PASS);
+    run_rom(rom);
+}
+
+// The 512 4-bit cells are repeated through 0xa000 - 0xbfff
+#[test]
+fn mbc2_ram() {
+    let mut rom = Rom::new();
+    rom.set_cartridge_type(0x06, 0x00);
+    rom.code(&[
+        0x3e, 0x0a,       // LD A,$0a
+        0xea, 0x00, 0x00, // LD ($0000),A - enable RAM
+        0x3e, 0xa7,       // LD A,$a7
+        0xea, 0xff, 0xa1, // LD ($a1ff),A
+        0xfa, 0xff, 0xa1, // LD A,($a1ff)
+    ]).expect_a(0xf7);
+    rom.code(&[0xfa, 0xff, 0xa3]).expect_a(0xf7); // LD A,($a3ff)
+    rom.code(&[0xfa, 0xff, 0xbf]).expect_a(0xf7); // LD A,($bfff)
+    // Writing through an echo changes the same cell
+    rom.code(&[
+        0x3e, 0x03,       // LD A,$03
+        0xea, 0x00, 0xb2, // LD ($b200),A
+        0xfa, 0x00, 0xa0, // LD A,($a000)
+    ]).expect_a(0xf3);
+    rom.code(&[0xfa, 0xff, 0xa1]).expect_a(0xf7); // LD A,($a1ff)
+    rom.jp(PASS);
+    run_rom(rom);
+}
+
+// Waits about 8.5 lines at the normal speed from the start of line 0x10, then
+// loads A with the number of lines that went by
+fn count_lines(rom: &mut Rom) {
+    wait_ly(rom, 0x0f);
+    wait_ly(rom, 0x10);
+    rom.code(&[0x06, 242]); // LD B,242
+    let wait = rom.here();
+    rom.code(&[0x05]).jr_nz(wait); // DEC B
+    rom.code(&[
+        0xf0, 0x44, // LDH A,($44)
+        0xd6, 0x10, // SUB $10
+    ]);
+}
+
+#[test]
+fn cgb_speed_switch() {
+    let mut rom = Rom::new();
+    rom.code(&[0xf3]); // DI
+    count_lines(&mut rom);
+    rom.expect_a(8);
+    rom.code(&[0xf0, 0x4d]).expect_a(0x7e); // LDH A,($4d)
+    rom.code(&[
+        0x3e, 0x01, // LD A,$01
+        0xe0, 0x4d, // LDH ($4d),A - arm the switch
+        0xf0, 0x4d, // LDH A,($4d)
+    ]).expect_a(0x7f);
+    rom.code(&[
+        0x10, 0x00, // STOP
+        0xf0, 0x4d, // LDH A,($4d)
+    ]).expect_a(0xfe);
+
+    // The CPU now gets through the loop in half the time
+    count_lines(&mut rom);
+    rom.expect_a(4);
+
+    rom.code(&[
+        0x3e, 0x01, // LD A,$01
+        0xe0, 0x4d, // LDH ($4d),A
+        0x10, 0x00, // STOP
+        0xf0, 0x4d, // LDH A,($4d)
+    ]).expect_a(0x7e);
+    count_lines(&mut rom);
+    rom.expect_a(8);
+    rom.jp(PASS);
+    run_cgb_rom(rom);
+}
+
+#[test]
+fn cgb_vram_banks() {
+    let mut rom = Rom::new();
+    rom.code(&[
+        0xf3,             // DI
+        0xaf,             // XOR A
+        0xe0, 0x40,       // LDH ($40),A - LCD off
+        0x3e, 0x01,       // LD A,$01
+        0xe0, 0x4f,       // LDH ($4f),A - VBK
+        0x3e, 0x22,       // LD A,$22
+        0xea, 0x00, 0x80, // LD ($8000),A
+        0xf0, 0x4f,       // LDH A,($4f)
+    ]).expect_a(0xff);
+    rom.code(&[
+        0xaf,             // XOR A
+        0xe0, 0x4f,       // LDH ($4f),A
+        0x3e, 0x11,       // LD A,$11
+        0xea, 0x00, 0x80, // LD ($8000),A
+        0xf0, 0x4f,       // LDH A,($4f)
+    ]).expect_a(0xfe);
+    rom.code(&[0xfa, 0x00, 0x80]).expect_a(0x11); // LD A,($8000)
+
+    // Only bit 0 selects the bank
+    rom.code(&[
+        0x3e, 0xff,       // LD A,$ff
+        0xe0, 0x4f,       // LDH ($4f),A
+        0xfa, 0x00, 0x80, // LD A,($8000)
+    ]).expect_a(0x22);
+    rom.jp(PASS);
+    run_cgb_rom(rom);
+}
+
+#[test]
+fn cgb_wram_banks() {
+    let mut rom = Rom::new();
+    rom.code(&[0xf3]); // DI
+    for bank in 1..8 {
+        rom.code(&[
+            0x3e, bank,             // LD A,bank
+            0xe0, 0x70,             // LDH ($70),A - SVBK
+            0x3e, bank * 0x11,      // LD A,bank * $11
+            0xea, 0x00, 0xd0,       // LD ($d000),A
+        ]);
+    }
+    rom.code(&[
+        0x3e, 0x44,       // LD A,$44
+        0xea, 0x00, 0xc0, // LD ($c000),A
+    ]);
+    for bank in 1..8 {
+        rom.code(&[
+            0x3e, bank,       // LD A,bank
+            0xe0, 0x70,       // LDH ($70),A
+            0xfa, 0x00, 0xd0, // LD A,($d000)
+        ]).expect_a(bank * 0x11);
+        rom.code(&[0xf0, 0x70]).expect_a(0xf8 | bank); // LDH A,($70)
+        rom.code(&[0xfa, 0x00, 0xc0]).expect_a(0x44); // LD A,($c000)
+    }
+
+    // Bank 0 selects bank 1, and the echo follows the selected bank
+    rom.code(&[
+        0xaf,             // XOR A
+        0xe0, 0x70,       // LDH ($70),A
+        0xf0, 0x70,       // LDH A,($70)
+    ]).expect_a(0xf9);
+    rom.code(&[0xfa, 0x00, 0xd0]).expect_a(0x11); // LD A,($d000)
+    rom.code(&[
+        0x3e, 0x05,       // LD A,$05
+        0xe0, 0x70,       // LDH ($70),A
+        0xfa, 0x00, 0xf0, // LD A,($f000)
+    ]).expect_a(0x55);
+    rom.jp(PASS);
+    run_cgb_rom(rom);
+}
+
+#[test]
+fn cgb_palette_ram() {
+    let mut rom = Rom::new();
+    rom.code(&[
+        0xf3,       // DI
+        0xaf,       // XOR A
+        0xe0, 0x40, // LDH ($40),A - LCD off
+        0x3e, 0x80, // LD A,$80
+        0xe0, 0x68, // LDH ($68),A - BCPS, index 0 with auto-increment
+        0x3e, 0x11, // LD A,$11
+        0xe0, 0x69, // LDH ($69),A
+        0x3e, 0x22, // LD A,$22
+        0xe0, 0x69, // LDH ($69),A
+        0xf0, 0x68, // LDH A,($68)
+    ]).expect_a(0xc2);
+
+    // Reads don't move the index on
+    rom.code(&[
+        0x3e, 0x80, // LD A,$80
+        0xe0, 0x68, // LDH ($68),A
+        0xf0, 0x69, // LDH A,($69)
+        0xf0, 0x69, // LDH A,($69)
+    ]).expect_a(0x11);
+    rom.code(&[0xf0, 0x68]).expect_a(0xc0); // LDH A,($68)
+
+    // Without auto-increment writes keep going to the same byte
+    rom.code(&[
+        0x3e, 0x01, // LD A,$01
+        0xe0, 0x68, // LDH ($68),A
+        0x3e, 0x33, // LD A,$33
+        0xe0, 0x69, // LDH ($69),A
+        0xf0, 0x68, // LDH A,($68)
+    ]).expect_a(0x41);
+    rom.code(&[0xf0, 0x69]).expect_a(0x33); // LDH A,($69)
+
+    // The OBJ palettes are separate, and the index wraps at 64 bytes
+    rom.code(&[
+        0x3e, 0xbf, // LD A,$bf
+        0xe0, 0x6a, // LDH ($6a),A - OCPS
+        0x3e, 0x44, // LD A,$44
+        0xe0, 0x6b, // LDH ($6b),A
+        0xf0, 0x6a, // LDH A,($6a)
+    ]).expect_a(0xc0);
+    rom.code(&[
+        0x3e, 0x55, // LD A,$55
+        0xe0, 0x6b, // LDH ($6b),A
+        0x3e, 0x3f, // LD A,$3f
+        0xe0, 0x6a, // LDH ($6a),A
+        0xf0, 0x6b, // LDH A,($6b)
+    ]).expect_a(0x44);
+    rom.code(&[
+        0xaf,       // XOR A
+        0xe0, 0x6a, // LDH ($6a),A
+        0xf0, 0x6b, // LDH A,($6b)
+    ]).expect_a(0x55);
+    rom.code(&[
+        0x3e, 0x3f, // LD A,$3f
+        0xe0, 0x68, // LDH ($68),A
+        0xf0, 0x69, // LDH A,($69)
+    ]).expect_a(0x00);
+    rom.jp(PASS);
+    run_cgb_rom(rom);
+}
+
+// Points the VRAM DMA from DATA in the ROM to addr in VRAM
+fn set_hdma(rom: &mut Rom, addr: u16) {
+    rom.code(&[
+        0x3e, (DATA >> 8) as u8, // LD A,DATA >> 8
+        0xe0, 0x51,              // LDH ($51),A - HDMA1
+        0x3e, DATA as u8,        // LD A,DATA & $ff
+        0xe0, 0x52,              // LDH ($52),A - HDMA2
+        0x3e, (addr >> 8) as u8, // LD A,addr >> 8
+        0xe0, 0x53,              // LDH ($53),A - HDMA3
+        0x3e, addr as u8,        // LD A,addr & $ff
+        0xe0, 0x54,              // LDH ($54),A - HDMA4
+    ]);
+}
+
+// Loads 0x30 bytes at DATA, each holding its offset plus one
+fn hdma_data(rom: &mut Rom) {
+    let data: Vec<u8> = (1..0x31).collect();
+    let pos = rom.here();
+    rom.org(DATA as usize).code(&data).org(pos as usize);
+}
+
+// Checks the bytes at addr against hdma_data, up to len
+fn expect_hdma_data(rom: &mut Rom, addr: u16, len: u8) {
+    rom.code(&[0x21, addr as u8, (addr >> 8) as u8]); // LD HL,addr
+    for i in 0..len {
+        rom.code(&[0x2a]).expect_a(i + 1); // LD A,(HL+)
+    }
+    rom.code(&[0x7e]).expect_a(0x00); // LD A,(HL)
+}
+
+#[test]
+fn cgb_general_hdma() {
+    let mut rom = Rom::new();
+    hdma_data(&mut rom);
+    rom.code(&[
+        0xf3,       // DI
+        0xaf,       // XOR A
+        0xe0, 0x40, // LDH ($40),A - LCD off
+    ]);
+    set_hdma(&mut rom, 0x8800);
+    rom.code(&[
+        0x3e, 0x01, // LD A,$01
+        0xe0, 0x55, // LDH ($55),A - two blocks at once
+        0xf0, 0x55, // LDH A,($55)
+    ]).expect_a(0xff);
+    expect_hdma_data(&mut rom, 0x8800, 0x20);
+
+    // The VRAM bank selected by VBK is written
+    set_hdma(&mut rom, 0x9000);
+    rom.code(&[
+        0x3e, 0x01, // LD A,$01
+        0xe0, 0x4f, // LDH ($4f),A
+        0xaf,       // XOR A
+        0xe0, 0x55, // LDH ($55),A
+    ]);
+    expect_hdma_data(&mut rom, 0x9000, 0x10);
+    rom.code(&[
+        0xaf,             // XOR A
+        0xe0, 0x4f,       // LDH ($4f),A
+        0xfa, 0x00, 0x90, // LD A,($9000)
+    ]).expect_a(0x00);
+    rom.jp(PASS);
+    run_cgb_rom(rom);
+}
+
+// One block is copied at the start of each hblank, HDMA5 counts down with
+// bit 7 clear while the transfer runs
+#[test]
+fn cgb_hblank_hdma() {
+    let mut rom = Rom::new();
+    hdma_data(&mut rom);
+    rom.code(&[
+        0xf3,       // DI
+        0xaf,       // XOR A
+        0xe0, 0x40, // LDH ($40),A - LCD off
+    ]);
+    set_hdma(&mut rom, 0x8800);
+    rom.code(&[
+        0x3e, 0x91, // LD A,$91
+        0xe0, 0x40, // LDH ($40),A - LCD on
+    ]);
+    wait_ly(&mut rom, 0x10);
+    wait_mode(&mut rom, 3);
+    rom.code(&[
+        0x3e, 0x82, // LD A,$82
+        0xe0, 0x55, // LDH ($55),A - three blocks in hblank
+        0xf0, 0x55, // LDH A,($55)
+    ]).expect_a(0x02);
+    for &remaining in &[0x01, 0x00, 0xff] {
+        wait_mode(&mut rom, 0);
+        rom.code(&[0xf0, 0x55]).expect_a(remaining); // LDH A,($55)
+        wait_mode(&mut rom, 3);
+    }
+
+    // Writing HDMA5 with bit 7 clear stops a transfer part way
+    set_hdma(&mut rom, 0x9000);
+    rom.code(&[
+        0x3e, 0x82, // LD A,$82
+        0xe0, 0x55, // LDH ($55),A
+    ]);
+    wait_mode(&mut rom, 0);
+    rom.code(&[
+        0xaf,       // XOR A
+        0xe0, 0x55, // LDH ($55),A
+        0xf0, 0x55, // LDH A,($55)
+    ]).expect_a(0x81);
+    wait_mode(&mut rom, 3);
+    wait_mode(&mut rom, 0);
+    rom.code(&[0xf0, 0x55]).expect_a(0x81); // LDH A,($55)
+
+    rom.code(&[
+        0xaf,       // XOR A
+        0xe0, 0x40, // LDH ($40),A - LCD off
+    ]);
+    expect_hdma_data(&mut rom, 0x8800, 0x30);
+    expect_hdma_data(&mut rom, 0x9000, 0x10);
+    rom.jp(PASS);
+    run_cgb_rom(rom);
+}
@@ -2,10 +2,25 @@ mod common;
 
 #[test]
 fn cpu_instrs() {
-    common::run_test_with_hash("tests/blargg/cpu_instrs.gb", 0xd9fc572a);
+    common::run_test_with_serial_output("tests/blargg/cpu_instrs.gb");
 }
 
 #[test]
 fn instr_timing() {
-    common::run_test_with_hash("tests/blargg/instr_timing.gb", 0xb376297f);
+    common::run_test_with_serial_output("tests/blargg/instr_timing.gb");
+}
+
+#[test]
+fn dmg_sound() {
+    common::run_test_with_memory_output("tests/blargg/dmg_sound_2.gb");
+}
+
+#[test]
+fn mem_timing() {
+    common::run_test_with_memory_output("tests/blargg/mem_timing_2.gb");
+}
+
+#[test]
+fn oam_bug() {
+    common::run_test_with_memory_output("tests/blargg/oam_bug_2.gb");
 }
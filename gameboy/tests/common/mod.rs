@@ -1,36 +1,85 @@
-extern crate byteorder;
-extern crate crc;
+// Each test crate only uses some of the helpers
+#![allow(dead_code)]
+
 extern crate gameboy;
 
+pub mod rom;
+
 use std::path::Path;
-use self::byteorder::{ByteOrder, LittleEndian};
-use self::crc::crc32::checksum_ieee;
 use self::gameboy::cartridge::Cartridge;
 use self::gameboy::interconnect::Interconnect;
+use self::gameboy::model::Model;
+use self::gameboy::serial::SerialSink;
 use self::gameboy::vm::VM;
 use self::gameboy::device::{self, Device};
 
-struct TestDevice {
-    buffer: Box<[u32]>,
+pub struct TestDevice;
+
+impl Device for TestDevice {
+    fn update(&mut self) {}
+
+    fn set_frame_buffer(&mut self, _: &[u32]) {}
+
+    fn audio_sample_rate(&self) -> u32 {
+        0
+    }
+
+    fn queue_audio_samples(&mut self, _: &[f32]) {}
+
+    fn set_rumble(&mut self, _: bool) {}
+
+    fn key_down(&self, _: device::Key) -> bool {
+        false
+    }
+
+    fn running(&self) -> bool {
+        true
+    }
 }
 
-impl TestDevice {
-    fn new(width: usize, height: usize) -> Self {
-        TestDevice {
-            buffer: vec![0; width * height].into_boxed_slice(),
-        }
+// Holds a single key down
+pub struct KeyDevice(pub device::Key);
+
+impl Device for KeyDevice {
+    fn update(&mut self) {}
+
+    fn set_frame_buffer(&mut self, _: &[u32]) {}
+
+    fn audio_sample_rate(&self) -> u32 {
+        0
+    }
+
+    fn queue_audio_samples(&mut self, _: &[f32]) {}
+
+    fn set_rumble(&mut self, _: bool) {}
+
+    fn key_down(&self, key: device::Key) -> bool {
+        key as u8 == self.0 as u8
+    }
+
+    fn running(&self) -> bool {
+        true
     }
 }
 
-impl Device for TestDevice {
+// Keeps the last frame shown
+pub struct FrameDevice(pub Vec<u32>);
+
+impl Device for FrameDevice {
     fn update(&mut self) {}
 
     fn set_frame_buffer(&mut self, buffer: &[u32]) {
-        for i in 0..self.buffer.len() {
-            self.buffer[i] = buffer[i];
-        }
+        self.0 = buffer.to_vec();
+    }
+
+    fn audio_sample_rate(&self) -> u32 {
+        0
     }
 
+    fn queue_audio_samples(&mut self, _: &[f32]) {}
+
+    fn set_rumble(&mut self, _: bool) {}
+
     fn key_down(&self, _: device::Key) -> bool {
         false
     }
@@ -40,30 +89,145 @@ impl Device for TestDevice {
     }
 }
 
-pub fn run_test_with_hash<P: AsRef<Path>>(file_name: P, hash: u32) {
-    let cartridge = Cartridge::load(file_name).unwrap();
-    let interconnect = Interconnect::new(cartridge);
+// Records each time the rumble motor is switched on or off
+pub struct RumbleDevice(pub Vec<bool>);
+
+impl Device for RumbleDevice {
+    fn update(&mut self) {}
 
-    let mut device = TestDevice::new(interconnect.get_width(), interconnect.get_height());
+    fn set_frame_buffer(&mut self, _: &[u32]) {}
 
+    fn audio_sample_rate(&self) -> u32 {
+        0
+    }
+
+    fn queue_audio_samples(&mut self, _: &[f32]) {}
+
+    fn set_rumble(&mut self, active: bool) {
+        self.0.push(active);
+    }
+
+    fn key_down(&self, _: device::Key) -> bool {
+        false
+    }
+
+    fn running(&self) -> bool {
+        true
+    }
+}
+
+// Mooneye's test ROMs finish by executing LD B,B as a software breakpoint,
+// with the registers loaded with the start of the Fibonacci sequence if the
+// test passed
+const BREAKPOINT_OPCODE: u8 = 0x40;
+const PASS_SIGNATURE: [u8; 6] = [3, 5, 8, 13, 21, 34];
+const MAX_STEPS: u32 = 50000000;
+
+pub fn run_test_with_breakpoint(cartridge: Cartridge) {
+    // The acceptance tests are meant for the DMG
+    let interconnect = Interconnect::with_model(cartridge, Model::Dmg);
     let mut vm = VM::new(interconnect, false, false);
 
+    run_to_breakpoint(&mut vm, &mut TestDevice);
+    assert_passed(&vm);
+}
+
+pub fn run_to_breakpoint(vm: &mut VM, device: &mut Device) {
+    let mut finished = false;
+    for _ in 0..MAX_STEPS {
+        if vm.read_byte(vm.cpu().pc) == BREAKPOINT_OPCODE {
+            finished = true;
+            break;
+        }
+        vm.step(device);
+    }
+    assert!(finished, "Test didn't reach the LD B,B breakpoint");
+}
 
-    for _ in 0..25000000 {
+pub fn assert_passed(vm: &VM) {
+    let cpu = vm.cpu();
+    let registers = [cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l];
+    assert_eq!(PASS_SIGNATURE,
+               registers,
+               "Failed with B: {:02x} C: {:02x} D: {:02x} E: {:02x} H: {:02x} L: {:02x}",
+               cpu.b,
+               cpu.c,
+               cpu.d,
+               cpu.e,
+               cpu.h,
+               cpu.l);
+}
+
+// Later blargg test ROMs report their result in cartridge RAM, with a
+// signature at 0xa001 - 0xa003, the result code at 0xa000 and the text output
+// from 0xa004
+const RESULT_ADDR: u16 = 0xa000;
+const RESULT_SIGNATURE: [u8; 3] = [0xde, 0xb0, 0x61];
+const RESULT_RUNNING: u8 = 0x80;
+
+pub fn run_test_with_memory_output<P: AsRef<Path>>(file_name: P) {
+    let cartridge = Cartridge::load(file_name).unwrap();
+    // The expected results come from a DMG
+    let interconnect = Interconnect::with_model(cartridge, Model::Dmg);
+
+    let mut device = TestDevice;
+
+    let mut vm = VM::new(interconnect, false, false);
+
+    let mut result = None;
+    for i in 0..200000000 {
         vm.step(&mut device);
+
+        if i % 10000 == 0 && has_result_signature(&vm) {
+            let status = vm.read_byte(RESULT_ADDR);
+            if status != RESULT_RUNNING {
+                result = Some(status);
+                break;
+            }
+        }
+    }
+
+    let mut text = String::new();
+    let mut addr = RESULT_ADDR + 4;
+    while vm.read_byte(addr) != 0 && addr < 0xc000 {
+        text.push(vm.read_byte(addr) as char);
+        addr += 1;
     }
 
-    let mut bytes = Vec::new();
+    assert_eq!(Some(0), result, "{}", text);
+}
+
+fn has_result_signature(vm: &VM) -> bool {
+    RESULT_SIGNATURE.iter()
+        .enumerate()
+        .all(|(i, b)| vm.read_byte(RESULT_ADDR + 1 + i as u16) == *b)
+}
+
+// Blargg's test ROMs also print their results over the serial port, ending
+// with "Passed" or "Failed"
+pub fn run_test_with_serial_output<P: AsRef<Path>>(file_name: P) {
+    let cartridge = Cartridge::load(file_name).unwrap();
+    // The expected results come from a DMG
+    let mut interconnect = Interconnect::with_model(cartridge, Model::Dmg);
+    let sink = SerialSink::new();
+    let output = sink.output();
+    interconnect.set_serial_peer(Box::new(sink));
+
+    let mut device = TestDevice;
 
-    for b in device.buffer.iter() {
-        let mut parts = vec![0;4];
-        // BigEndian::write_u32(&mut parts, *b);
-        LittleEndian::write_u32(&mut parts, *b);
+    let mut vm = VM::new(interconnect, false, false);
+
+    for i in 0..200000000 {
+        vm.step(&mut device);
 
-        for p in parts {
-            bytes.push(p);
+        if i % 10000 == 0 {
+            let text = String::from_utf8_lossy(&output.borrow()).into_owned();
+            if text.contains("Passed") || text.contains("Failed") {
+                break;
+            }
         }
     }
 
-    assert_eq!(hash, checksum_ieee(&bytes));
+    let text = String::from_utf8_lossy(&output.borrow()).into_owned();
+    assert!(text.contains("Passed") && !text.contains("Failed"), "{}", text);
 }
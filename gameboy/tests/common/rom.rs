@@ -0,0 +1,136 @@
+// Builds small test ROMs that follow the mooneye-gb convention, finishing with
+// LD B,B and B/C/D/E/H/L holding 3/5/8/13/21/34 if they passed
+
+// Jump to these to finish a test, they stay mapped when ROM banks are switched
+pub const PASS: u16 = 0x3f00;
+pub const FAIL: u16 = 0x3f80;
+// The test code starts after the cartridge header
+pub const START: u16 = 0x0150;
+
+const BANK_LENGTH: usize = 0x4000;
+const CGB_FLAG_OFFSET: usize = 0x0143;
+const ROM_TYPE_OFFSET: usize = 0x0147;
+const ROM_SIZE_OFFSET: usize = 0x0148;
+const RAM_SIZE_OFFSET: usize = 0x0149;
+
+pub struct Rom {
+    bytes: Vec<u8>,
+    pos: usize,
+}
+
+impl Rom {
+    // A 32KiB ROM without a memory bank controller
+    pub fn new() -> Rom {
+        Rom::with_banks(2)
+    }
+
+    // The number of banks must be a power of two
+    pub fn with_banks(banks: usize) -> Rom {
+        let mut rom = Rom {
+            bytes: vec![0; banks * BANK_LENGTH],
+            pos: 0,
+        };
+
+        // Unexpected RSTs and interrupts fail the test
+        for vector in 0..13 {
+            rom.org(vector * 8).jp(FAIL);
+        }
+        rom.org(0x0100).code(&[0x00]).jp(START);
+        rom.bytes[ROM_SIZE_OFFSET] = (banks.trailing_zeros() - 1) as u8;
+
+        rom.org(PASS as usize).code(&[
+            0x06, 3,    // LD B,3
+            0x0e, 5,    // LD C,5
+            0x16, 8,    // LD D,8
+            0x1e, 13,   // LD E,13
+            0x26, 21,   // LD H,21
+            0x2e, 34,   // LD L,34
+            0x40,       // LD B,B
+            0x18, 0xfe, // JR -2
+        ]);
+        rom.org(FAIL as usize).code(&[
+            0x06, 0x42, // LD B,$42
+            0x0e, 0x42, // LD C,$42
+            0x16, 0x42, // LD D,$42
+            0x1e, 0x42, // LD E,$42
+            0x26, 0x42, // LD H,$42
+            0x2e, 0x42, // LD L,$42
+            0x40,       // LD B,B
+            0x18, 0xfe, // JR -2
+        ]);
+
+        rom.org(START as usize);
+        rom
+    }
+
+    pub fn set_cartridge_type(&mut self, rom_type: u8, ram_size: u8) -> &mut Rom {
+        self.bytes[ROM_TYPE_OFFSET] = rom_type;
+        self.bytes[RAM_SIZE_OFFSET] = ram_size;
+        self
+    }
+
+    // Marks the cartridge as supporting the CGB's features
+    pub fn set_cgb(&mut self) -> &mut Rom {
+        self.bytes[CGB_FLAG_OFFSET] = 0x80;
+        self
+    }
+
+    // Moves to an offset in the ROM file, which can be past the first two
+    // banks
+    pub fn org(&mut self, offset: usize) -> &mut Rom {
+        self.pos = offset;
+        self
+    }
+
+    pub fn code(&mut self, bytes: &[u8]) -> &mut Rom {
+        self.bytes[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+        self
+    }
+
+    // JP addr
+    pub fn jp(&mut self, addr: u16) -> &mut Rom {
+        self.code(&[0xc3, addr as u8, (addr >> 8) as u8])
+    }
+
+    // JP NZ,addr
+    pub fn jp_nz(&mut self, addr: u16) -> &mut Rom {
+        self.code(&[0xc2, addr as u8, (addr >> 8) as u8])
+    }
+
+    // JP Z,addr
+    pub fn jp_z(&mut self, addr: u16) -> &mut Rom {
+        self.code(&[0xca, addr as u8, (addr >> 8) as u8])
+    }
+
+    // JP C,addr
+    pub fn jp_c(&mut self, addr: u16) -> &mut Rom {
+        self.code(&[0xda, addr as u8, (addr >> 8) as u8])
+    }
+
+    // JR NZ,addr
+    pub fn jr_nz(&mut self, addr: u16) -> &mut Rom {
+        let offset = addr.wrapping_sub(self.here() + 2) as i16;
+        assert!((-128..128).contains(&offset), "JR target out of range");
+        self.code(&[0x20, offset as u8])
+    }
+
+    // CALL addr
+    pub fn call(&mut self, addr: u16) -> &mut Rom {
+        self.code(&[0xcd, addr as u8, (addr >> 8) as u8])
+    }
+
+    // Fails the test unless A holds val
+    pub fn expect_a(&mut self, val: u8) -> &mut Rom {
+        self.code(&[0xfe, val]).jp_nz(FAIL)
+    }
+
+    // The address the next byte is written to
+    pub fn here(&self) -> u16 {
+        self.pos as u16
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
@@ -0,0 +1,178 @@
+mod common;
+
+extern crate gameboy;
+
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::net::TcpListener;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, Instant};
+use gameboy::cartridge::Cartridge;
+use gameboy::interconnect::Interconnect;
+use gameboy::link::TcpLink;
+use common::TestDevice;
+use common::rom::Rom;
+
+// Starts a transfer of data and runs until both sides should be finished,
+// returns SB and whether the serial interrupt was raised
+fn run_transfer(link: TcpLink, data: u8, control: u8) -> (u8, bool) {
+    let cartridge = Cartridge::from_bytes(&vec![0; 0x8000]);
+    let mut interconnect = Interconnect::new(cartridge);
+    interconnect.set_serial_peer(Box::new(link));
+
+    interconnect.write_byte(0xff01, data);
+    interconnect.write_byte(0xff02, control);
+    for _ in 0..4096 {
+        interconnect.step(4, &mut TestDevice);
+    }
+
+    (interconnect.read_byte(0xff01), interconnect.if_register & 0x08 != 0)
+}
+
+#[test]
+fn tcp_link_transfer() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    // The listening side waits with the external clock, the other side
+    // drives the transfer
+    let slave = thread::spawn(move || {
+        let link = TcpLink::accept(&listener).unwrap();
+        run_transfer(link, 0x34, 0x80)
+    });
+    let link = TcpLink::connect(("127.0.0.1", port)).unwrap();
+    let master = run_transfer(link, 0x12, 0x81);
+
+    assert_eq!((0x34, true), master);
+    assert_eq!((0x12, true), slave.join().unwrap());
+}
+
+// A side that stops running, like one paused in the debugger, holds the
+// transfer up but doesn't stop the other side from running
+#[test]
+fn tcp_link_stalled_peer() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let (done_sender, done_receiver) = channel();
+    let stalled = thread::spawn(move || {
+        let _link = TcpLink::accept(&listener).unwrap();
+        done_receiver.recv().unwrap();
+    });
+    let link = TcpLink::connect(("127.0.0.1", port)).unwrap();
+    let error = link.error();
+
+    let start = Instant::now();
+    let master = run_transfer(link, 0x12, 0x81);
+    assert!(start.elapsed() < Duration::from_secs(2));
+    // Nothing was waiting on the other end, so the line stayed high
+    assert_eq!((0xff, true), master);
+    assert!(error.borrow().is_none());
+
+    done_sender.send(()).unwrap();
+    stalled.join().unwrap();
+}
+
+// Closing one end disconnects the other, which carries on as if the cable
+// had been pulled out and reports the error
+#[test]
+fn tcp_link_disconnect() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let closed = thread::spawn(move || {
+        TcpLink::accept(&listener).unwrap();
+    });
+    let link = TcpLink::connect(("127.0.0.1", port)).unwrap();
+    let error = link.error();
+    closed.join().unwrap();
+
+    assert_eq!((0xff, true), run_transfer(link, 0x12, 0x81));
+    assert!(error.borrow().is_some());
+}
+
+// A battery backed cartridge that swaps data over the link cable and keeps
+// the byte it gets back at the start of its RAM, which is saved on exit
+fn write_link_rom(file_name: &Path, data: u8, control: u8) {
+    let mut rom = Rom::new();
+    rom.set_cartridge_type(0x03, 0x02);
+    rom.code(&[
+        0xf3,             // DI
+        0x3e, 0x0a,       // LD A,$0a
+        0xea, 0x00, 0x00, // LD ($0000),A - enable RAM
+        0x01, 0x00, 0x10, // LD BC,$1000
+    ]);
+    // Give the other side time to start waiting
+    let delay = rom.here();
+    rom.code(&[
+        0x0b, // DEC BC
+        0x78, // LD A,B
+        0xb1, // OR C
+    ]).jr_nz(delay);
+    rom.code(&[
+        0x3e, data,    // LD A,data
+        0xe0, 0x01,    // LDH ($01),A
+        0x3e, control, // LD A,control
+        0xe0, 0x02,    // LDH ($02),A
+    ]);
+    let wait = rom.here();
+    rom.code(&[
+        0xf0, 0x02, // LDH A,($02)
+        0xcb, 0x7f, // BIT 7,A
+    ]).jr_nz(wait);
+    rom.code(&[
+        0xf0, 0x01,       // LDH A,($01)
+        0xea, 0x00, 0xa0, // LD ($a000),A
+        0x18, 0xfe,       // JR -2
+    ]);
+    fs::write(file_name, rom.into_bytes()).unwrap();
+}
+
+fn run_emulator(rom_file: &Path, link: &[&str]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_gameboy"));
+    command.arg(rom_file).args(["--headless", "30"]).args(link).stdout(Stdio::null());
+    command
+}
+
+// Two copies of the emulator connected through --link-listen and
+// --link-connect swap a byte each way
+#[test]
+fn tcp_link_between_processes() {
+    let dir = env::temp_dir().join("gameboy-test-link-processes");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let slave_rom = dir.join("slave.gb");
+    let master_rom = dir.join("master.gb");
+    write_link_rom(&slave_rom, 0x34, 0x80);
+    write_link_rom(&master_rom, 0x12, 0x81);
+
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let port = port.to_string();
+    let mut slave = run_emulator(&slave_rom, &["--link-listen", &port])
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stderr = BufReader::new(slave.stderr.take().unwrap());
+    let mut line = String::new();
+    while !line.starts_with("Waiting for a link cable") {
+        line.clear();
+        assert!(stderr.read_line(&mut line).unwrap() > 0, "The listening side exited");
+    }
+
+    // The message comes just before the port is opened
+    let address = format!("127.0.0.1:{}", port);
+    let mut attempts = 0;
+    while !run_emulator(&master_rom, &["--link-connect", &address]).status().unwrap().success() {
+        attempts += 1;
+        assert!(attempts < 50, "Couldn't connect to the listening side");
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(slave.wait().unwrap().success());
+
+    assert_eq!(fs::read(dir.join("master.sav")).unwrap()[0], 0x34);
+    assert_eq!(fs::read(dir.join("slave.sav")).unwrap()[0], 0x12);
+}
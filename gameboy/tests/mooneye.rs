@@ -0,0 +1,88 @@
+mod common;
+
+extern crate gameboy;
+
+use std::path::Path;
+use gameboy::cartridge::Cartridge;
+
+fn run_mooneye_test<P: AsRef<Path>>(file_name: P) {
+    common::run_test_with_breakpoint(Cartridge::load(file_name).unwrap());
+}
+
+// The ROMs aren't in the repository yet, see tests/mooneye/README.md. Run
+// them with cargo test -- --ignored once they've been added. Until then
+// tests/acceptance.rs covers the same features with ROMs built in the tests.
+macro_rules! mooneye_tests {
+    ($($name:ident: $file:expr,)*) => {
+        $(
+            #[test]
+            #[ignore = "mooneye-gb test ROMs aren't vendored yet"]
+            fn $name() {
+                run_mooneye_test(concat!("tests/mooneye/acceptance/", $file));
+            }
+        )*
+    }
+}
+
+mooneye_tests! {
+    timer_div_write: "timer/div_write.gb",
+    timer_rapid_toggle: "timer/rapid_toggle.gb",
+    timer_tim00: "timer/tim00.gb",
+    timer_tim00_div_trigger: "timer/tim00_div_trigger.gb",
+    timer_tim01: "timer/tim01.gb",
+    timer_tim01_div_trigger: "timer/tim01_div_trigger.gb",
+    timer_tim10: "timer/tim10.gb",
+    timer_tim10_div_trigger: "timer/tim10_div_trigger.gb",
+    timer_tim11: "timer/tim11.gb",
+    timer_tim11_div_trigger: "timer/tim11_div_trigger.gb",
+    timer_tima_reload: "timer/tima_reload.gb",
+    timer_tima_write_reloading: "timer/tima_write_reloading.gb",
+    timer_tma_write_reloading: "timer/tma_write_reloading.gb",
+
+    interrupts_ie_push: "interrupts/ie_push.gb",
+    di_timing: "di_timing-GS.gb",
+    ei_sequence: "ei_sequence.gb",
+    ei_timing: "ei_timing.gb",
+    intr_timing: "intr_timing.gb",
+    rapid_di_ei: "rapid_di_ei.gb",
+    reti_intr_timing: "reti_intr_timing.gb",
+
+    halt_ime0_ei: "halt_ime0_ei.gb",
+    halt_ime0_nointr_timing: "halt_ime0_nointr_timing.gb",
+    halt_ime1_timing: "halt_ime1_timing.gb",
+    halt_ime1_timing2: "halt_ime1_timing2-GS.gb",
+
+    oam_dma_basic: "oam_dma/basic.gb",
+    oam_dma_reg_read: "oam_dma/reg_read.gb",
+    oam_dma_sources: "oam_dma/sources-GS.gb",
+    oam_dma_restart: "oam_dma_restart.gb",
+    oam_dma_start: "oam_dma_start.gb",
+    oam_dma_timing: "oam_dma_timing.gb",
+
+    ppu_hblank_ly_scx_timing: "ppu/hblank_ly_scx_timing-GS.gb",
+    ppu_intr_1_2_timing: "ppu/intr_1_2_timing-GS.gb",
+    ppu_intr_2_0_timing: "ppu/intr_2_0_timing.gb",
+    ppu_intr_2_mode0_timing: "ppu/intr_2_mode0_timing.gb",
+    ppu_intr_2_mode0_timing_sprites: "ppu/intr_2_mode0_timing_sprites.gb",
+    ppu_intr_2_mode3_timing: "ppu/intr_2_mode3_timing.gb",
+    ppu_intr_2_oam_ok_timing: "ppu/intr_2_oam_ok_timing.gb",
+    ppu_lcdon_timing: "ppu/lcdon_timing-GS.gb",
+    ppu_lcdon_write_timing: "ppu/lcdon_write_timing-GS.gb",
+    ppu_stat_irq_blocking: "ppu/stat_irq_blocking.gb",
+    ppu_stat_lyc_onoff: "ppu/stat_lyc_onoff.gb",
+    ppu_vblank_stat_intr: "ppu/vblank_stat_intr-GS.gb",
+
+    mbc1_bits_bank1: "mbc1/bits_bank1.gb",
+    mbc1_bits_bank2: "mbc1/bits_bank2.gb",
+    mbc1_bits_mode: "mbc1/bits_mode.gb",
+    mbc1_bits_ramg: "mbc1/bits_ramg.gb",
+    mbc1_multicart_rom_8mb: "mbc1/multicart_rom_8Mb.gb",
+    mbc1_ram_64kb: "mbc1/ram_64kb.gb",
+    mbc1_ram_256kb: "mbc1/ram_256kb.gb",
+    mbc1_rom_512kb: "mbc1/rom_512kb.gb",
+    mbc1_rom_1mb: "mbc1/rom_1Mb.gb",
+    mbc1_rom_2mb: "mbc1/rom_2Mb.gb",
+    mbc1_rom_4mb: "mbc1/rom_4Mb.gb",
+    mbc1_rom_8mb: "mbc1/rom_8Mb.gb",
+    mbc1_rom_16mb: "mbc1/rom_16Mb.gb",
+}
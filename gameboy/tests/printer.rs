@@ -0,0 +1,184 @@
+extern crate gameboy;
+extern crate crc;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::PathBuf;
+use crc::crc32::checksum_ieee;
+use gameboy::png;
+use gameboy::printer::Printer;
+use gameboy::serial::SerialPeer;
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_STATUS: u8 = 0x0f;
+
+const STATUS_CHECKSUM_ERROR: u8 = 1 << 0;
+
+// Sends a whole packet, the checksum is worked out unless one is given.
+// Returns the status the printer answers with.
+fn send_packet(printer: &mut Printer,
+               command: u8,
+               compressed: bool,
+               data: &[u8],
+               checksum: Option<u16>)
+               -> u8 {
+    let mut packet = vec![command, compressed as u8, data.len() as u8, (data.len() >> 8) as u8];
+    packet.extend_from_slice(data);
+    let checksum = checksum.unwrap_or_else(|| {
+        packet.iter().fold(0u16, |sum, val| sum.wrapping_add(*val as u16))
+    });
+
+    printer.exchange(0x88);
+    printer.exchange(0x33);
+    for val in packet {
+        printer.exchange(val);
+    }
+    printer.exchange(checksum as u8);
+    printer.exchange((checksum >> 8) as u8);
+    assert_eq!(printer.exchange(0x00), 0x81);
+    printer.exchange(0x00)
+}
+
+fn output_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("gameboy-test-{}", name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    ((bytes[0] as u32) << 24) | ((bytes[1] as u32) << 16) | ((bytes[2] as u32) << 8) |
+    bytes[3] as u32
+}
+
+// Decodes the 8-bit greyscale PNGs written by the png module, which only
+// hold stored deflate blocks. Returns the width, height and pixels.
+fn decode_png(bytes: &[u8]) -> (usize, usize, Vec<u8>) {
+    assert_eq!(&bytes[0..8], &[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n']);
+
+    let mut pos = 8;
+    let mut width = 0;
+    let mut height = 0;
+    let mut idat = Vec::new();
+    loop {
+        let length = read_u32(&bytes[pos..]) as usize;
+        let chunk = &bytes[pos + 4..pos + 8 + length];
+        let crc = read_u32(&bytes[pos + 8 + length..]);
+        assert_eq!(checksum_ieee(chunk), crc, "Bad chunk CRC");
+        pos += length + 12;
+
+        let (chunk_type, data) = chunk.split_at(4);
+        match chunk_type {
+            b"IHDR" => {
+                width = read_u32(&data[0..]) as usize;
+                height = read_u32(&data[4..]) as usize;
+                assert_eq!(&data[8..], &[8, 0, 0, 0, 0]);
+            }
+            b"IDAT" => idat.extend_from_slice(data),
+            b"IEND" => break,
+            _ => panic!("Unexpected chunk"),
+        }
+    }
+    assert_eq!(pos, bytes.len());
+
+    // zlib header, stored blocks then the Adler-32 of the data
+    assert_eq!((((idat[0] as u16) << 8) | idat[1] as u16) % 31, 0);
+    let mut scanlines = Vec::new();
+    let mut pos = 2;
+    loop {
+        let last = idat[pos] & 1 != 0;
+        assert_eq!(idat[pos] & 0x6, 0, "Block isn't stored");
+        let length = idat[pos + 1] as usize | ((idat[pos + 2] as usize) << 8);
+        let inverse = idat[pos + 3] as usize | ((idat[pos + 4] as usize) << 8);
+        assert_eq!(length ^ 0xffff, inverse);
+        scanlines.extend_from_slice(&idat[pos + 5..pos + 5 + length]);
+        pos += length + 5;
+        if last {
+            break;
+        }
+    }
+    let (mut a, mut b) = (1u32, 0u32);
+    for val in &scanlines {
+        a = (a + *val as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    assert_eq!(read_u32(&idat[pos..]), (b << 16) | a, "Bad Adler-32");
+    assert_eq!(pos + 4, idat.len());
+
+    // Each row starts with its filter type
+    assert_eq!(scanlines.len(), (width + 1) * height);
+    let mut pixels = Vec::with_capacity(width * height);
+    for row in scanlines.chunks(width + 1) {
+        assert_eq!(row[0], 0, "Row is filtered");
+        pixels.extend_from_slice(&row[1..]);
+    }
+    (width, height, pixels)
+}
+
+#[test]
+fn png_round_trip() {
+    // Big enough to need more than one stored block
+    let (width, height) = (300, 300);
+    let pixels: Vec<u8> = (0..width * height).map(|i| (i * 7 + i / width) as u8).collect();
+
+    let mut bytes = Vec::new();
+    png::write_greyscale(&mut bytes, width, height, &pixels).unwrap();
+
+    assert_eq!(decode_png(&bytes), (width, height, pixels));
+}
+
+#[test]
+fn checksum_error_status() {
+    let mut printer = Printer::new(output_dir("printer-checksum"));
+
+    let status = send_packet(&mut printer, COMMAND_INIT, false, &[], Some(0x1234));
+    assert_eq!(status & STATUS_CHECKSUM_ERROR, STATUS_CHECKSUM_ERROR);
+
+    // The next good packet clears the error
+    let status = send_packet(&mut printer, COMMAND_STATUS, false, &[], None);
+    assert_eq!(status & STATUS_CHECKSUM_ERROR, 0);
+}
+
+#[test]
+fn print_compressed_data() {
+    let dir = output_dir("printer-compressed");
+    let mut printer = Printer::new(&dir);
+    let saved_pages = printer.saved_pages();
+
+    assert_eq!(send_packet(&mut printer, COMMAND_INIT, false, &[], None), 0);
+
+    // A row of black tiles followed by a row of white tiles, 320 bytes each
+    let data = [
+        0x01, 0xff, 0xff, // Two bytes as they are
+        0xff, 0xff, // 129 of 0xff
+        0xff, 0xff,
+        0xba, 0xff, // 60 of 0xff
+        0xff, 0x00,
+        0xff, 0x00,
+        0xbc, 0x00, // 62 of 0x00
+    ];
+    let status = send_packet(&mut printer, COMMAND_DATA, true, &data, None);
+    assert_eq!(status & STATUS_CHECKSUM_ERROR, 0);
+
+    // One sheet, no top margin and a bottom margin of one, which saves the
+    // page
+    send_packet(&mut printer, COMMAND_PRINT, false, &[0x01, 0x01, 0xe4, 0x40], None);
+
+    let file_name = dir.join("print_0001.png");
+    match saved_pages.borrow_mut().pop() {
+        Some(Ok(ref saved)) => assert_eq!(saved, &file_name),
+        _ => panic!("Page wasn't saved"),
+    }
+
+    let mut bytes = Vec::new();
+    File::open(&file_name).unwrap().read_to_end(&mut bytes).unwrap();
+    let (width, height, pixels) = decode_png(&bytes);
+    assert_eq!((width, height), (160, 24));
+    for (y, row) in pixels.chunks(width).enumerate() {
+        let shade = if y < 8 { 0x00 } else { 0xff };
+        assert!(row.iter().all(|val| *val == shade), "Wrong shade in row {}", y);
+    }
+}
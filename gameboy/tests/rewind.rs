@@ -0,0 +1,68 @@
+extern crate gameboy;
+
+use gameboy::rewind::Rewind;
+
+// A state that's mostly the same as the ones around it, with a few bytes
+// changed in a pattern that depends on the frame
+fn make_state(frame: u32, length: usize) -> Vec<u8> {
+    let mut state = vec![0; length];
+    let mut seed = frame.wrapping_mul(2654435761) | 1;
+    for _ in 0..length / 64 {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        let index = seed as usize % length;
+        state[index] = seed as u8;
+    }
+    // A long changed run to exercise multi-byte run lengths
+    for b in state.iter_mut().skip(1000).take(300) {
+        *b = frame as u8;
+    }
+    state
+}
+
+#[test]
+fn rewind_round_trip() {
+    let mut rewind = Rewind::new(usize::max_value());
+    for frame in 0..20 {
+        rewind.push(make_state(frame, 4096));
+    }
+    assert_eq!(20, rewind.len());
+
+    for frame in (0..20).rev() {
+        assert_eq!(Some(make_state(frame, 4096)), rewind.pop());
+    }
+    assert_eq!(None, rewind.pop());
+    assert!(rewind.is_empty());
+    assert_eq!(0, rewind.memory_used());
+}
+
+#[test]
+fn rewind_evicts_oldest_over_memory_limit() {
+    let memory_limit = 16 * 1024;
+    let mut rewind = Rewind::new(memory_limit);
+    for frame in 0..200 {
+        rewind.push(make_state(frame, 4096));
+        assert!(rewind.memory_used() <= memory_limit);
+    }
+    let kept = rewind.len();
+    assert!(kept > 1 && kept < 200);
+
+    // The newest states are the ones kept
+    for frame in (200 - kept as u32..200).rev() {
+        assert_eq!(Some(make_state(frame, 4096)), rewind.pop());
+    }
+    assert_eq!(None, rewind.pop());
+}
+
+#[test]
+fn rewind_clears_history_when_the_state_length_changes() {
+    let mut rewind = Rewind::new(usize::max_value());
+    rewind.push(make_state(0, 4096));
+    rewind.push(make_state(1, 4096));
+    rewind.push(make_state(2, 2048));
+
+    assert_eq!(1, rewind.len());
+    assert_eq!(Some(make_state(2, 2048)), rewind.pop());
+    assert_eq!(None, rewind.pop());
+}
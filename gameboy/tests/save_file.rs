@@ -0,0 +1,221 @@
+mod common;
+
+extern crate gameboy;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use gameboy::cartridge::{Cartridge, RtcClock};
+use common::TestDevice;
+use common::rom::Rom;
+
+// An MBC1 cartridge with 8KiB of battery backed RAM
+fn battery_cartridge() -> Cartridge {
+    let mut rom = Rom::new();
+    rom.set_cartridge_type(0x03, 0x02);
+    Cartridge::from_bytes(&rom.into_bytes())
+}
+
+// An MBC3 cartridge with the clock and 8KiB of battery backed RAM, the clock
+// is enabled along with the RAM
+fn rtc_cartridge() -> Cartridge {
+    let mut rom = Rom::new();
+    rom.set_cartridge_type(0x10, 0x02);
+    let mut cartridge = Cartridge::from_bytes(&rom.into_bytes());
+    cartridge.set_rtc_clock(RtcClock::Emulated);
+    cartridge.write(0x0000, 0x0a);
+    cartridge
+}
+
+fn write_rtc(cartridge: &mut Cartridge, reg: u8, val: u8) {
+    cartridge.write(0x4000, reg);
+    cartridge.write(0xa000, val);
+}
+
+fn latch_rtc(cartridge: &mut Cartridge) {
+    cartridge.write(0x6000, 0x00);
+    cartridge.write(0x6000, 0x01);
+}
+
+// Seconds, minutes, hours, low byte of the day and the control register
+fn read_rtc(cartridge: &mut Cartridge) -> [u8; 5] {
+    let mut registers = [0; 5];
+    for (i, reg) in registers.iter_mut().enumerate() {
+        cartridge.write(0x4000, 0x08 + i as u8);
+        *reg = cartridge.read_byte(0xa000);
+    }
+    registers
+}
+
+fn run_seconds(cartridge: &mut Cartridge, seconds: u32) {
+    // The clock is driven by the 4MHz CPU clock
+    for _ in 0..seconds * 4194304 / 4 {
+        cartridge.step(4, &mut TestDevice);
+    }
+}
+
+fn save_file_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("gameboy-test-{}.sav", name))
+}
+
+#[test]
+fn save_file_round_trip() {
+    let path = save_file_path("round-trip");
+    let _ = fs::remove_file(&path);
+
+    let mut cartridge = battery_cartridge();
+    cartridge.attach_save_file(&path).unwrap();
+    cartridge.write(0x0000, 0x0a);
+    cartridge.write(0xa123, 0x5a);
+    cartridge.save_ram().unwrap();
+
+    let mut cartridge = battery_cartridge();
+    cartridge.attach_save_file(&path).unwrap();
+    cartridge.write(0x0000, 0x0a);
+    assert_eq!(0x5a, cartridge.read_byte(0xa123));
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn short_save_file_is_kept_until_ram_changes() {
+    let path = save_file_path("short");
+    fs::write(&path, [0x12; 16]).unwrap();
+
+    let mut cartridge = battery_cartridge();
+    assert!(cartridge.attach_save_file(&path).is_err());
+    cartridge.write(0x0000, 0x0a);
+    assert_eq!(0x00, cartridge.read_byte(0xa000));
+
+    // Nothing has been written, so the file is left for the player to recover
+    cartridge.save_ram().unwrap();
+    assert_eq!(16, fs::metadata(&path).unwrap().len());
+
+    cartridge.write(0xa000, 0x34);
+    cartridge.save_ram().unwrap();
+    assert_eq!(8 * 1024, fs::metadata(&path).unwrap().len());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn rtc_latch() {
+    let mut cartridge = rtc_cartridge();
+    write_rtc(&mut cartridge, 0x08, 30);
+    write_rtc(&mut cartridge, 0x09, 20);
+
+    // The registers read back what was last latched
+    assert_eq!([0, 0, 0, 0, 0], read_rtc(&mut cartridge));
+
+    // Only writing 0x00 then 0x01 latches the clock
+    cartridge.write(0x6000, 0x01);
+    assert_eq!([0, 0, 0, 0, 0], read_rtc(&mut cartridge));
+    cartridge.write(0x6000, 0x00);
+    cartridge.write(0x6000, 0x02);
+    cartridge.write(0x6000, 0x01);
+    assert_eq!([0, 0, 0, 0, 0], read_rtc(&mut cartridge));
+
+    latch_rtc(&mut cartridge);
+    assert_eq!([30, 20, 0, 0, 0], read_rtc(&mut cartridge));
+}
+
+#[test]
+fn rtc_ticks_with_the_emulated_clock() {
+    let mut cartridge = rtc_cartridge();
+    write_rtc(&mut cartridge, 0x08, 10);
+
+    run_seconds(&mut cartridge, 2);
+    latch_rtc(&mut cartridge);
+    assert_eq!([12, 0, 0, 0, 0], read_rtc(&mut cartridge));
+
+    // Writing the seconds restarts the current second
+    cartridge.step(4000, &mut TestDevice);
+    write_rtc(&mut cartridge, 0x08, 0);
+    for _ in 0..(4194304 - 4000) / 4 {
+        cartridge.step(4, &mut TestDevice);
+    }
+    latch_rtc(&mut cartridge);
+    assert_eq!([0, 0, 0, 0, 0], read_rtc(&mut cartridge));
+}
+
+#[test]
+fn rtc_day_counter_carry() {
+    let mut cartridge = rtc_cartridge();
+    write_rtc(&mut cartridge, 0x08, 59);
+    write_rtc(&mut cartridge, 0x09, 59);
+    write_rtc(&mut cartridge, 0x0a, 23);
+    write_rtc(&mut cartridge, 0x0b, 0xff);
+    write_rtc(&mut cartridge, 0x0c, 0x01); // Bit 8 of the day
+
+    // Day 511 wraps to 0 and sets the carry, which stays set
+    run_seconds(&mut cartridge, 1);
+    latch_rtc(&mut cartridge);
+    assert_eq!([0, 0, 0, 0, 0x80], read_rtc(&mut cartridge));
+    run_seconds(&mut cartridge, 1);
+    latch_rtc(&mut cartridge);
+    assert_eq!([1, 0, 0, 0, 0x80], read_rtc(&mut cartridge));
+
+    // Only the game clears it
+    write_rtc(&mut cartridge, 0x0c, 0x00);
+    latch_rtc(&mut cartridge);
+    assert_eq!([1, 0, 0, 0, 0x00], read_rtc(&mut cartridge));
+}
+
+#[test]
+fn rtc_halt() {
+    let mut cartridge = rtc_cartridge();
+    write_rtc(&mut cartridge, 0x08, 5);
+    write_rtc(&mut cartridge, 0x0c, 0x40);
+
+    run_seconds(&mut cartridge, 2);
+    latch_rtc(&mut cartridge);
+    assert_eq!([5, 0, 0, 0, 0x40], read_rtc(&mut cartridge));
+
+    write_rtc(&mut cartridge, 0x0c, 0x00);
+    run_seconds(&mut cartridge, 1);
+    latch_rtc(&mut cartridge);
+    assert_eq!([6, 0, 0, 0, 0x00], read_rtc(&mut cartridge));
+}
+
+#[test]
+fn rtc_save_file_round_trip() {
+    let path = save_file_path("rtc-round-trip");
+    let _ = fs::remove_file(&path);
+
+    let mut cartridge = rtc_cartridge();
+    cartridge.attach_save_file(&path).unwrap();
+    write_rtc(&mut cartridge, 0x08, 1);
+    write_rtc(&mut cartridge, 0x09, 2);
+    write_rtc(&mut cartridge, 0x0a, 3);
+    write_rtc(&mut cartridge, 0x0b, 4);
+    write_rtc(&mut cartridge, 0x0c, 0xc1);
+    latch_rtc(&mut cartridge);
+    // The latched registers are saved separately from the clock
+    write_rtc(&mut cartridge, 0x08, 9);
+    cartridge.save_ram().unwrap();
+    assert_eq!(8 * 1024 + 48, fs::metadata(&path).unwrap().len());
+
+    let mut cartridge = rtc_cartridge();
+    cartridge.attach_save_file(&path).unwrap();
+    assert_eq!([1, 2, 3, 4, 0xc1], read_rtc(&mut cartridge));
+    latch_rtc(&mut cartridge);
+    assert_eq!([9, 2, 3, 4, 0xc1], read_rtc(&mut cartridge));
+    fs::remove_file(&path).unwrap();
+}
+
+// Older emulators store a 32-bit timestamp, making the footer 44 bytes
+#[test]
+fn rtc_save_file_with_32_bit_timestamp() {
+    let path = save_file_path("rtc-32-bit");
+    let mut contents = vec![0; 8 * 1024];
+    for reg in &[10u32, 20, 5, 0x80, 0x41, 11, 21, 6, 0x81, 0x40] {
+        contents.extend_from_slice(&[*reg as u8, 0, 0, 0]);
+    }
+    contents.extend_from_slice(&[0x00, 0x00, 0x00, 0x60]);
+    fs::write(&path, &contents).unwrap();
+
+    let mut cartridge = rtc_cartridge();
+    cartridge.attach_save_file(&path).unwrap();
+    assert_eq!([11, 21, 6, 0x81, 0x40], read_rtc(&mut cartridge));
+    latch_rtc(&mut cartridge);
+    assert_eq!([10, 20, 5, 0x80, 0x41], read_rtc(&mut cartridge));
+    fs::remove_file(&path).unwrap();
+}
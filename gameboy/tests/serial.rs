@@ -0,0 +1,125 @@
+mod common;
+
+extern crate gameboy;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use gameboy::cartridge::Cartridge;
+use gameboy::interconnect::Interconnect;
+use gameboy::model::Model;
+use gameboy::serial::SerialPeer;
+use common::TestDevice;
+
+const CGB_FLAG_OFFSET: usize = 0x0143;
+const SERIAL_IO: u8 = 0x08;
+
+// Answers every byte with reply and records the bytes it was sent. With
+// clock_after set it drives the clock itself, sending its reply that many
+// clocks after it's asked to.
+struct StubPeer {
+    reply: u8,
+    sent: Rc<RefCell<Vec<u8>>>,
+    clock_after: Option<u32>,
+    waited: u32,
+}
+
+impl SerialPeer for StubPeer {
+    fn exchange(&mut self, data: u8) -> u8 {
+        self.sent.borrow_mut().push(data);
+        self.reply
+    }
+
+    fn external_clock(&mut self, cycles: u16, data: Option<u8>) -> Option<u8> {
+        let clock_after = self.clock_after?;
+        self.waited += cycles as u32;
+        if self.waited < clock_after {
+            return None;
+        }
+        self.waited = 0;
+        if let Some(data) = data {
+            self.sent.borrow_mut().push(data);
+        }
+        Some(self.reply)
+    }
+}
+
+fn start(model: Model, clock_after: Option<u32>) -> (Interconnect, Rc<RefCell<Vec<u8>>>) {
+    let mut rom = vec![0; 0x8000];
+    rom[CGB_FLAG_OFFSET] = 0x80;
+    let mut interconnect = Interconnect::with_model(Cartridge::from_bytes(&rom), model);
+
+    let sent = Rc::new(RefCell::new(Vec::new()));
+    interconnect.set_serial_peer(Box::new(StubPeer {
+        reply: 0x34,
+        sent: sent.clone(),
+        clock_after: clock_after,
+        waited: 0,
+    }));
+    interconnect.if_register = 0;
+    (interconnect, sent)
+}
+
+// Starts a transfer of 0x12 and returns the clocks until SC shows it's
+// finished, checking the interrupt is only raised at the end
+fn transfer(interconnect: &mut Interconnect, control: u8) -> u32 {
+    interconnect.write_byte(0xff01, 0x12);
+    interconnect.write_byte(0xff02, control);
+
+    let mut cycles = 0;
+    while interconnect.read_byte(0xff02) & 0x80 != 0 {
+        assert_eq!(interconnect.if_register & SERIAL_IO, 0);
+        assert!(cycles < 0x10000, "The transfer didn't finish");
+        interconnect.step(4, &mut TestDevice);
+        cycles += 4;
+    }
+    assert_eq!(interconnect.if_register & SERIAL_IO, SERIAL_IO);
+    cycles
+}
+
+// Eight bits at 8192Hz, the first bit waits for the next edge of the clock
+#[test]
+fn serial_internal_clock() {
+    let (mut interconnect, sent) = start(Model::Dmg, None);
+
+    let cycles = transfer(&mut interconnect, 0x81);
+    assert!(cycles > 7 * 512 && cycles <= 8 * 512, "Took {} clocks", cycles);
+    assert_eq!(interconnect.read_byte(0xff01), 0x34);
+    assert_eq!(*sent.borrow(), vec![0x12]);
+
+    // Back to back transfers take the whole eight bits
+    interconnect.if_register = 0;
+    assert_eq!(transfer(&mut interconnect, 0x81), 8 * 512);
+}
+
+// The CGB's fast clock runs at 262144Hz, the DMG doesn't have it
+#[test]
+fn serial_fast_clock() {
+    let (mut interconnect, sent) = start(Model::Cgb, None);
+    transfer(&mut interconnect, 0x83);
+    interconnect.if_register = 0;
+    assert_eq!(transfer(&mut interconnect, 0x83), 8 * 16);
+    assert_eq!(interconnect.read_byte(0xff02), 0x7f);
+    assert_eq!(*sent.borrow(), vec![0x12, 0x12]);
+
+    let (mut interconnect, _) = start(Model::Dmg, None);
+    transfer(&mut interconnect, 0x83);
+    interconnect.if_register = 0;
+    assert_eq!(transfer(&mut interconnect, 0x83), 8 * 512);
+}
+
+// With the external clock the transfer waits for the peer, which only
+// swaps bytes when one is waiting
+#[test]
+fn serial_external_clock() {
+    let (mut interconnect, sent) = start(Model::Dmg, Some(1000));
+    for _ in 0..1000 {
+        interconnect.step(4, &mut TestDevice);
+    }
+    assert_eq!(interconnect.if_register & SERIAL_IO, 0);
+    assert!(sent.borrow().is_empty());
+
+    let cycles = transfer(&mut interconnect, 0x80);
+    assert!(cycles <= 1000, "Took {} clocks", cycles);
+    assert_eq!(interconnect.read_byte(0xff01), 0x34);
+    assert_eq!(*sent.borrow(), vec![0x12]);
+}
@@ -0,0 +1,63 @@
+mod common;
+
+extern crate gameboy;
+
+use gameboy::cartridge::Cartridge;
+use gameboy::interconnect::Interconnect;
+use gameboy::vm::VM;
+use common::TestDevice;
+use common::rom::Rom;
+
+// Runs a ROM that counts up in A forever for the given number of steps
+fn run_counter(title: &[u8], steps: u32) -> VM {
+    let mut rom = Rom::new();
+    rom.code(&[
+        0x3c,       // INC A
+        0x18, 0xfd, // JR -3
+    ]);
+    rom.org(0x0134).code(title);
+
+    let cartridge = Cartridge::from_bytes(&rom.into_bytes());
+    let mut vm = VM::new(Interconnect::new(cartridge), false, false);
+    for _ in 0..steps {
+        vm.step(&mut TestDevice);
+    }
+    vm
+}
+
+#[test]
+fn state_round_trip() {
+    let mut vm = run_counter(b"COUNTER", 1000);
+    let state = vm.save_state();
+    let a = vm.cpu().a;
+    for _ in 0..1000 {
+        vm.step(&mut TestDevice);
+    }
+
+    vm.load_state(&state).unwrap();
+    assert_eq!(a, vm.cpu().a);
+    assert_eq!(state, vm.save_state());
+}
+
+#[test]
+fn state_from_another_rom_is_rejected() {
+    let state = run_counter(b"ONE", 1000).save_state();
+    let mut vm = run_counter(b"TWO", 500);
+    let before = vm.save_state();
+
+    assert!(vm.load_state(&state).is_err());
+    assert_eq!(before, vm.save_state());
+}
+
+#[test]
+fn truncated_state_is_rejected() {
+    let mut vm = run_counter(b"COUNTER", 1000);
+    let state = vm.save_state();
+    for _ in 0..1000 {
+        vm.step(&mut TestDevice);
+    }
+    let before = vm.save_state();
+
+    assert!(vm.load_state(&state[..state.len() - 1]).is_err());
+    assert_eq!(before, vm.save_state());
+}
@@ -0,0 +1,53 @@
+extern crate gameboy;
+extern crate byteorder;
+
+use std::io::{Cursor, Read};
+use byteorder::{LittleEndian, ReadBytesExt};
+use gameboy::wav::WavWriter;
+
+fn read_tag(reader: &mut Cursor<Vec<u8>>) -> [u8; 4] {
+    let mut tag = [0; 4];
+    reader.read_exact(&mut tag).unwrap();
+    tag
+}
+
+#[test]
+fn wav_round_trip() {
+    let mut wav = WavWriter::new(Cursor::new(Vec::new()), 22050).unwrap();
+    wav.write_samples(&[0.0, 1.0, -1.0, 0.5]).unwrap();
+    // Out of range samples are clamped
+    wav.write_samples(&[2.0, -2.0]).unwrap();
+    let bytes = wav.finish().unwrap().into_inner();
+    assert_eq!(bytes.len(), 44 + 6 * 2);
+
+    let mut reader = Cursor::new(bytes);
+    assert_eq!(&read_tag(&mut reader), b"RIFF");
+    assert_eq!(reader.read_u32::<LittleEndian>().unwrap(), 36 + 6 * 2);
+    assert_eq!(&read_tag(&mut reader), b"WAVE");
+
+    assert_eq!(&read_tag(&mut reader), b"fmt ");
+    assert_eq!(reader.read_u32::<LittleEndian>().unwrap(), 16);
+    assert_eq!(reader.read_u16::<LittleEndian>().unwrap(), 1); // PCM
+    assert_eq!(reader.read_u16::<LittleEndian>().unwrap(), 2); // Channels
+    assert_eq!(reader.read_u32::<LittleEndian>().unwrap(), 22050);
+    assert_eq!(reader.read_u32::<LittleEndian>().unwrap(), 22050 * 4); // Bytes per second
+    assert_eq!(reader.read_u16::<LittleEndian>().unwrap(), 4); // Block align
+    assert_eq!(reader.read_u16::<LittleEndian>().unwrap(), 16); // Bits per sample
+
+    assert_eq!(&read_tag(&mut reader), b"data");
+    assert_eq!(reader.read_u32::<LittleEndian>().unwrap(), 6 * 2);
+    let mut samples = Vec::new();
+    for _ in 0..6 {
+        samples.push(reader.read_i16::<LittleEndian>().unwrap());
+    }
+    assert_eq!(samples, vec![0, 32767, -32767, 16383, 32767, -32767]);
+}
+
+#[test]
+fn wav_without_samples() {
+    let wav = WavWriter::new(Cursor::new(Vec::new()), 44100).unwrap();
+    let bytes = wav.finish().unwrap().into_inner();
+    assert_eq!(bytes.len(), 44);
+    assert_eq!(&bytes[4..8], &[36, 0, 0, 0]);
+    assert_eq!(&bytes[40..44], &[0, 0, 0, 0]);
+}